@@ -712,7 +712,7 @@ mod tests {
     use super::*;
     use crate::actions::{copy_a_file_sftp, ssh_util};
     use crate::data_shape::{
-        string_path
+        string_path, AuthMethod, HostKeyCheck
     };
     use crate::develope::tutil;
     use crate::log_util;
@@ -784,11 +784,26 @@ mod tests {
         dotenv().ok();
         let username = env::var("username")?;
         let password = env::var("password")?;
-        let sess = ssh_util::create_ssh_session_password(
-            "localhost:22",
-            username.as_str(),
-            password.as_str(),
-        )?;
+        let pin_file = tu.tmp_dir_path().join("known_hosts");
+        let endpoint = ssh_util::SshEndpoint {
+            host: "localhost",
+            port: 22,
+            connect_timeout: None,
+            host_key_policy: ssh_util::HostKeyPolicy {
+                check: &HostKeyCheck::TrustOnFirstUse,
+                known_hosts: None,
+                pin_file: pin_file.as_path(),
+            },
+            credentials: ssh_util::SshCredentials {
+                username: username.as_str(),
+                password: password.as_str(),
+                id_rsa: "",
+                id_rsa_pub: None,
+                id_rsa_passphrase: None,
+            },
+            auth_methods: &[AuthMethod::Password],
+        };
+        let sess = ssh_util::create_ssh_session(&endpoint, &[])?;
         let sftp = sess.sftp()?;
         eprintln!("copy {:?} to {:?}", f_path_buf, to_dir_str);
         if let Err(err) = copy_a_file_sftp(
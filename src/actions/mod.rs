@@ -1,5 +1,6 @@
 pub mod copy_file;
 // mod reporter;
+pub mod s3_client;
 pub mod ssh_util;
 
 pub use copy_file::{write_str_to_file, hash_file_sha1, copy_a_file_sftp};
@@ -1,87 +1,516 @@
+use crate::data_shape::{AuthMethod, HostKeyCheck, PassphraseSource};
 use log::*;
-use ssh2;
-use std::io::Read;
-use std::net::TcpStream;
-use std::path::Path;
-
-fn get_sess_pre_authentication(url: &str) -> Result<ssh2::Session, failure::Error> {
-    trace!("connecting to: {}", url);
-    let tcp = TcpStream::connect(&url)?;
+use ssh2::{self, CheckResult, HashType, KeyboardInteractivePrompt, KnownHostFileKind, Prompt};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Fail)]
+pub enum HostKeyError {
+    #[fail(display = "server {}:{} didn't present a host key.", _0, _1)]
+    NoHostKey(String, u16),
+    #[fail(
+        display = "HOST KEY MISMATCH for {}:{}! the server presented {} which differs from the key recorded in {:?}. Someone could be eavesdropping on you (man-in-the-middle attack), or the host key has just been changed. Refusing to connect.",
+        host, port, fingerprint, known_hosts
+    )]
+    Mismatch {
+        host: String,
+        port: u16,
+        fingerprint: String,
+        known_hosts: Vec<PathBuf>,
+    },
+    #[fail(
+        display = "host key of {}:{} ({}) isn't in {:?}. add it to known_hosts (e.g. ssh-keyscan) or set host_key_check: TrustOnFirstUse.",
+        host, port, fingerprint, known_hosts
+    )]
+    NotFound {
+        host: String,
+        port: u16,
+        fingerprint: String,
+        known_hosts: Vec<PathBuf>,
+    },
+    #[fail(display = "checking host key of {}:{} failed.", _0, _1)]
+    CheckFailed(String, u16),
+}
+
+#[derive(Debug, Fail)]
+pub enum SshAuthError {
+    #[fail(
+        display = "authenticate {}@{}:{} failed, tried: {}",
+        username, host, port, failures
+    )]
+    AllMethodsFailed {
+        username: String,
+        host: String,
+        port: u16,
+        failures: String,
+    },
+}
+
+/// How the host key of a server is verified.
+pub struct HostKeyPolicy<'a> {
+    pub check: &'a HostKeyCheck,
+    /// if None, the user's ~/.ssh/known_hosts.
+    pub known_hosts: Option<&'a Path>,
+    /// the file trust-on-first-use mode pins keys into.
+    pub pin_file: &'a Path,
+}
+
+impl<'a> HostKeyPolicy<'a> {
+    fn known_hosts_files(&self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        match self.known_hosts {
+            Some(kh) => files.push(kh.to_path_buf()),
+            None => {
+                if let Some(home) = dirs::home_dir() {
+                    files.push(home.join(".ssh").join("known_hosts"));
+                }
+            }
+        }
+        if let HostKeyCheck::TrustOnFirstUse = self.check {
+            files.push(self.pin_file.to_path_buf());
+        }
+        files
+    }
+}
+
+/// Like OpenSSH, SHA256:base64_without_padding.
+pub fn host_key_fingerprint(sess: &ssh2::Session) -> String {
+    match sess.host_key_hash(HashType::Sha256) {
+        Some(hash) => format!(
+            "SHA256:{}",
+            base64::encode_config(hash, base64::STANDARD_NO_PAD)
+        ),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Non standard port is recorded as [host]:port in known_hosts.
+fn known_hosts_name(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+/// libssh2 copies comment_len + 1 bytes from the comment, so there must be a NUL right behind it.
+fn pin_comment() -> &'static str {
+    let comment = "pinned by bk-over-ssh\0";
+    &comment[..comment.len() - 1]
+}
+
+pub fn verify_host_key(
+    sess: &ssh2::Session,
+    host: &str,
+    port: u16,
+    policy: &HostKeyPolicy,
+) -> Result<(), failure::Error> {
+    let (key, key_type) = match sess.host_key() {
+        Some(kt) => kt,
+        None => bail!(HostKeyError::NoHostKey(host.to_string(), port)),
+    };
+    let fingerprint = host_key_fingerprint(sess);
+    let files = policy.known_hosts_files();
+
+    let mut known_hosts = sess.known_hosts()?;
+    for f in files.iter().filter(|f| f.exists()) {
+        trace!("reading known_hosts: {:?}", f);
+        if let Err(err) = known_hosts.read_file(f, KnownHostFileKind::OpenSSH) {
+            bail!("read known_hosts file {:?} failed, {:?}", f, err);
+        }
+    }
+
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => {
+            trace!("host key of {}:{} matched, {}", host, port, fingerprint);
+            Ok(())
+        }
+        CheckResult::Mismatch => bail!(HostKeyError::Mismatch {
+            host: host.to_string(),
+            port,
+            fingerprint,
+            known_hosts: files,
+        }),
+        CheckResult::NotFound => match policy.check {
+            HostKeyCheck::Strict => bail!(HostKeyError::NotFound {
+                host: host.to_string(),
+                port,
+                fingerprint,
+                known_hosts: files,
+            }),
+            HostKeyCheck::TrustOnFirstUse => {
+                // only the pinned keys go back to the pin file, never the user's known_hosts.
+                let mut pinned = sess.known_hosts()?;
+                if policy.pin_file.exists() {
+                    pinned.read_file(policy.pin_file, KnownHostFileKind::OpenSSH)?;
+                }
+                pinned.add(
+                    known_hosts_name(host, port).as_str(),
+                    key,
+                    pin_comment(),
+                    key_type.into(),
+                )?;
+                pinned.write_file(policy.pin_file, KnownHostFileKind::OpenSSH)?;
+                warn!(
+                    "first connect to {}:{}, pinned host key {} into {:?}",
+                    host, port, fingerprint, policy.pin_file
+                );
+                Ok(())
+            }
+        },
+        CheckResult::Failure => bail!(HostKeyError::CheckFailed(host.to_string(), port)),
+    }
+}
+
+/// Try every resolved address, return the last error if none connects in time.
+fn connect_timeout(url: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in url.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(tcp) => return Ok(tcp),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} resolves to nothing.", url),
+        )
+    }))
+}
+
+fn get_sess_pre_authentication(
+    endpoint: &SshEndpoint,
+    via: Option<SocketAddr>,
+) -> Result<ssh2::Session, failure::Error> {
+    let tcp = match via {
+        Some(addr) => {
+            trace!(
+                "connecting to: {}:{} via tunnel {}",
+                endpoint.host,
+                endpoint.port,
+                addr
+            );
+            TcpStream::connect(addr)?
+        }
+        None => {
+            let url = format!("{}:{}", endpoint.host, endpoint.port);
+            trace!("connecting to: {}", url);
+            match endpoint.connect_timeout {
+                Some(timeout) => connect_timeout(&url, timeout)?,
+                None => TcpStream::connect(&url)?,
+            }
+        }
+    };
     let mut sess = ssh2::Session::new()?;
     sess.set_tcp_stream(tcp);
     sess.handshake()?;
+    verify_host_key(
+        &sess,
+        endpoint.host,
+        endpoint.port,
+        &endpoint.host_key_policy,
+    )?;
     Ok(sess)
 }
 
-pub fn create_ssh_session_agent(
-    url: &str,
-    username: &str,
-) -> Result<ssh2::Session, failure::Error> {
-    let sess = get_sess_pre_authentication(url)?;
+/// What a server yml offers to authenticate with.
+pub struct SshCredentials<'a> {
+    pub username: &'a str,
+    pub password: &'a str,
+    pub id_rsa: &'a str,
+    pub id_rsa_pub: Option<&'a str>,
+    pub id_rsa_passphrase: Option<&'a PassphraseSource>,
+}
+
+/// The method name of the ssh protocol, as listed by the server.
+fn ssh_method_name(method: &AuthMethod) -> &'static str {
+    match method {
+        AuthMethod::Password => "password",
+        AuthMethod::Agent | AuthMethod::IdentityFile => "publickey",
+        AuthMethod::KeyboardInteractive => "keyboard-interactive",
+    }
+}
+
+fn userauth_agent(sess: &ssh2::Session, username: &str) -> Result<(), failure::Error> {
     let mut agent = sess.agent()?;
     agent.connect()?;
     agent.list_identities()?;
 
-    match agent.identities() {
-        Ok(identities) => {
-            for identity in identities {
-                trace!("start authenticate with public key.");
-                if let Err(err) = agent.userauth(username, &identity) {
-                    warn!("ssh agent authentication failed. {:?}", err);
+    for identity in agent.identities()? {
+        trace!("start authenticate with public key {}.", identity.comment());
+        match agent.userauth(username, &identity) {
+            Ok(_) => return Ok(()),
+            Err(err) => warn!("ssh agent authentication failed. {:?}", err),
+        }
+    }
+    bail!("none of the identities in ssh agent was accepted.");
+}
+
+fn userauth_identity_file(
+    sess: &ssh2::Session,
+    credentials: &SshCredentials,
+) -> Result<(), failure::Error> {
+    let id_rsa = Path::new(credentials.id_rsa);
+    if !id_rsa.exists() {
+        bail!("identity file {:?} doesn't exist.", id_rsa);
+    }
+    let passphrase = match credentials.id_rsa_passphrase {
+        Some(source) => Some(source.resolve(credentials.id_rsa)?),
+        None => None,
+    };
+    trace!(
+        "about authenticate with IdentityFile: {:?}, passphrase: {}",
+        id_rsa,
+        passphrase.is_some()
+    );
+    sess.userauth_pubkey_file(
+        credentials.username,
+        credentials.id_rsa_pub.map(Path::new),
+        id_rsa,
+        passphrase.as_deref(),
+    )?;
+    Ok(())
+}
+
+/// Answers the hidden prompts with the configured password, asks on the terminal otherwise.
+struct PasswordPrompter<'a> {
+    password: &'a str,
+}
+
+impl<'a> KeyboardInteractivePrompt for PasswordPrompter<'a> {
+    fn prompt<'b>(
+        &mut self,
+        _username: &str,
+        instructions: &str,
+        prompts: &[Prompt<'b>],
+    ) -> Vec<String> {
+        if !instructions.is_empty() {
+            eprintln!("{}", instructions);
+        }
+        prompts
+            .iter()
+            .map(|p| {
+                let answer = if !p.echo && !self.password.is_empty() {
+                    Ok(self.password.to_string())
+                } else if p.echo {
+                    eprint!("{}", p.text);
+                    let mut line = String::new();
+                    io::stdin()
+                        .read_line(&mut line)
+                        .map(|_| line.trim_end().to_string())
                 } else {
-                    break;
-                }
-            }
+                    rpassword::read_password_from_tty(Some(&p.text))
+                };
+                answer.unwrap_or_else(|err| {
+                    warn!("answer prompt {:?} failed, {:?}", p.text, err);
+                    String::new()
+                })
+            })
+            .collect()
+    }
+}
+
+fn userauth(
+    sess: &ssh2::Session,
+    method: &AuthMethod,
+    credentials: &SshCredentials,
+) -> Result<(), failure::Error> {
+    match method {
+        AuthMethod::Agent => userauth_agent(sess, credentials.username),
+        AuthMethod::IdentityFile => userauth_identity_file(sess, credentials),
+        AuthMethod::Password => {
+            Ok(sess.userauth_password(credentials.username, credentials.password)?)
         }
-        Err(err) => warn!("can't get key from ssh agent {:?}.", err),
-    }
-
-    // for id in agent.identities() {
-    //     match id {
-    //         Ok(identity) => {
-    //             trace!("start authenticate with public key.");
-    //             if let Err(err) = agent.userauth(username, &identity) {
-    //                 warn!("ssh agent authentication failed. {:?}", err);
-    //             } else {
-    //                 break;
-    //             }
-    //         }
-    //         Err(err) => warn!("can't get key from ssh agent {:?}.", err),
-    //     }
-    // }
-    Ok(sess)
+        AuthMethod::KeyboardInteractive => {
+            let mut prompter = PasswordPrompter {
+                password: credentials.password,
+            };
+            Ok(sess.userauth_keyboard_interactive(credentials.username, &mut prompter)?)
+        }
+    }
 }
 
-pub fn create_ssh_session_identity_file(
-    url: &str,
-    username: &str,
-    id_rsa: &str,
-    id_rsa_pub: Option<&str>,
+/// Everything needed to reach and log into one ssh server.
+pub struct SshEndpoint<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub connect_timeout: Option<Duration>,
+    pub host_key_policy: HostKeyPolicy<'a>,
+    pub credentials: SshCredentials<'a>,
+    /// tried in order, the first succeeded one wins.
+    pub auth_methods: &'a [AuthMethod],
+}
+
+fn authenticate(
+    endpoint: &SshEndpoint,
+    via: Option<SocketAddr>,
 ) -> Result<ssh2::Session, failure::Error> {
-    let sess = get_sess_pre_authentication(url)?;
+    let sess = get_sess_pre_authentication(endpoint, via)?;
+    let credentials = &endpoint.credentials;
+    let offered = sess
+        .auth_methods(credentials.username)
+        .map(str::to_string)
+        .unwrap_or_default();
+    if sess.authenticated() {
+        return Ok(sess);
+    }
     trace!(
-        "about authenticate to {:?} with IdentityFile: {:?}",
-        url,
-        id_rsa_pub,
+        "server {}:{} offers: {}",
+        endpoint.host,
+        endpoint.port,
+        offered
     );
-    sess.userauth_pubkey_file(
-        username,
-        id_rsa_pub.as_ref().map(Path::new),
-        Path::new(id_rsa),
-        None,
-    )
-    .expect("userauth_pubkey_file should succeeded.");
-    Ok(sess)
+
+    let mut failures = Vec::new();
+    for method in endpoint.auth_methods {
+        let name = ssh_method_name(method);
+        if !offered.is_empty() && !offered.split(',').any(|m| m == name) {
+            failures.push(format!("{:?}: server doesn't allow {}", method, name));
+            continue;
+        }
+        match userauth(&sess, method, credentials) {
+            Ok(_) if sess.authenticated() => {
+                trace!("authenticated by {:?}", method);
+                return Ok(sess);
+            }
+            Ok(_) => failures.push(format!("{:?}: not authenticated", method)),
+            Err(err) => {
+                warn!("authenticate by {:?} failed, {}", method, err);
+                failures.push(format!("{:?}: {}", method, err));
+            }
+        }
+    }
+    bail!(SshAuthError::AllMethodsFailed {
+        username: credentials.username.to_string(),
+        host: endpoint.host.to_string(),
+        port: endpoint.port,
+        failures: failures.join("; "),
+    });
 }
-pub fn create_ssh_session_password(
-    url: &str,
-    username: &str,
-    password: &str,
+
+/// Connect to the target, hopping through the jump hosts in order if any.
+pub fn create_ssh_session(
+    target: &SshEndpoint,
+    jump_hosts: &[SshEndpoint],
 ) -> Result<ssh2::Session, failure::Error> {
-    let sess = get_sess_pre_authentication(url)?;
-    sess.userauth_password(username, password)
-        .expect("userauth_password should succeeded.");
-    Ok(sess)
+    let mut via = None;
+    for (i, jump_host) in jump_hosts.iter().enumerate() {
+        let bastion = authenticate(jump_host, via)?;
+        let next = jump_hosts.get(i + 1).unwrap_or(target);
+        via = Some(open_tunnel(bastion, next.host, next.port)?);
+    }
+    authenticate(target, via)
+}
+
+/// Forward a local port to host:port through a direct-tcpip channel of the bastion.
+/// The tunnel accepts exactly one connection and lives as long as that connection.
+/// Anyone else grabbing the port first just fails the host key check of the next hop.
+fn open_tunnel(
+    bastion: ssh2::Session,
+    host: &str,
+    port: u16,
+) -> Result<SocketAddr, failure::Error> {
+    let channel = match bastion.channel_direct_tcpip(host, port, None) {
+        Ok(channel) => channel,
+        Err(err) => bail!(
+            "open direct-tcpip channel to {}:{} failed, {:?}",
+            host,
+            port,
+            err
+        ),
+    };
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let local_addr = listener.local_addr()?;
+    let target = format!("{}:{}", host, port);
+    thread::spawn(move || {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                warn!("accept tunnel connection to {} failed, {:?}", target, err);
+                return;
+            }
+        };
+        drop(listener);
+        if let Err(err) = pump_tunnel(&bastion, channel, stream) {
+            warn!("tunnel to {} broken, {:?}", target, err);
+        }
+        trace!("tunnel to {} closed.", target);
+    });
+    Ok(local_addr)
+}
+
+fn write_all_nonblocking(w: &mut impl Write, mut buf: &[u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        match w.write(buf) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => buf = &buf[n..],
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(1))
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// How long an idle tunnel waits on the local socket, the channel is read in between.
+const TUNNEL_MIN_WAIT: Duration = Duration::from_millis(1);
+const TUNNEL_MAX_WAIT: Duration = Duration::from_millis(50);
+
+/// The bastion session is only used by this channel, so it's safe to switch it to non-blocking.
+/// The local socket is read with a timeout, which doubles while the tunnel stays idle.
+fn pump_tunnel(
+    bastion: &ssh2::Session,
+    mut channel: ssh2::Channel,
+    mut stream: TcpStream,
+) -> io::Result<()> {
+    bastion.set_blocking(false);
+    let mut buf = vec![0; 32 * 1024];
+    let mut wait = TUNNEL_MIN_WAIT;
+    stream.set_read_timeout(Some(wait))?;
+    loop {
+        let mut idle = true;
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                write_all_nonblocking(&mut channel, &buf[..n])?;
+                idle = false;
+            }
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            }
+            Err(e) => return Err(e),
+        }
+        match channel.read(&mut buf) {
+            Ok(0) => {
+                if channel.eof() {
+                    break;
+                }
+            }
+            Ok(n) => {
+                stream.write_all(&buf[..n])?;
+                idle = false;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        let next_wait = if idle {
+            (wait * 2).min(TUNNEL_MAX_WAIT)
+        } else {
+            TUNNEL_MIN_WAIT
+        };
+        if next_wait != wait {
+            wait = next_wait;
+            stream.set_read_timeout(Some(wait))?;
+        }
+    }
+    bastion.set_blocking(true);
+    channel.close().ok();
+    Ok(())
 }
 
 #[allow(dead_code)]
@@ -133,3 +562,67 @@ pub fn get_stdout_eprintln_stderr(channel: &mut ssh2::Channel, verbose: bool) ->
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::develope::tutil;
+
+    #[test]
+    fn t_known_hosts_pin_file() -> Result<(), failure::Error> {
+        let tu = tutil::TestDir::new();
+        let pin_file = tu.tmp_dir_path().join("known_hosts");
+        let policy = HostKeyPolicy {
+            check: &HostKeyCheck::TrustOnFirstUse,
+            known_hosts: Some(Path::new("/not/exists/known_hosts")),
+            pin_file: pin_file.as_path(),
+        };
+        assert_eq!(
+            policy.known_hosts_files(),
+            vec![PathBuf::from("/not/exists/known_hosts"), pin_file.clone()]
+        );
+
+        let sess = ssh2::Session::new()?;
+        let key = b"a-fake-host-key";
+        let mut kh = sess.known_hosts()?;
+        kh.add(
+            known_hosts_name("example.com", 2222).as_str(),
+            key,
+            pin_comment(),
+            ssh2::KnownHostKeyFormat::SshRsa,
+        )?;
+        kh.write_file(&pin_file, KnownHostFileKind::OpenSSH)?;
+
+        let mut kh = sess.known_hosts()?;
+        kh.read_file(&pin_file, KnownHostFileKind::OpenSSH)?;
+        assert!(matches!(
+            kh.check_port("example.com", 2222, key),
+            CheckResult::Match
+        ));
+        assert!(matches!(
+            kh.check_port("example.com", 2222, b"another-host-key"),
+            CheckResult::Mismatch
+        ));
+        assert!(matches!(
+            kh.check_port("example.com", 22, key),
+            CheckResult::NotFound
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn t_passphrase_source() -> Result<(), failure::Error> {
+        let tu = tutil::TestDir::new();
+        let f = tu.make_a_file_with_content("passphrase.txt", "secret words\n")?;
+        let source = PassphraseSource::File(f.to_str().unwrap().to_string());
+        assert_eq!(source.resolve("id_rsa")?, "secret words");
+
+        std::env::set_var("BK_T_PASSPHRASE", "from env");
+        let source = PassphraseSource::Env("BK_T_PASSPHRASE".to_string());
+        assert_eq!(source.resolve("id_rsa")?, "from env");
+
+        let source = PassphraseSource::Env("BK_T_PASSPHRASE_NOT_SET".to_string());
+        assert!(source.resolve("id_rsa").is_err());
+        Ok(())
+    }
+}
@@ -1,22 +1,37 @@
 
 use log::*;
 use rayon::prelude::*;
+use std::path::{Path, PathBuf};
 
-use crate::data_shape::{AppConf};
+use serde::Serialize;
+
+use crate::data_shape::archive_crypt::{self, ENCRYPTED_POSTFIX};
+use crate::data_shape::rolling_files::ArchiveRetention;
+use crate::data_shape::{AppConf, Secret, Server};
+
+fn load_servers(app_conf: &AppConf, server_yml: Option<&str>) -> Result<Vec<Server>, failure::Error> {
+    Ok(if let Some(server_yml) = server_yml {
+        let server = app_conf.load_server_from_yml(server_yml, false)?;
+        vec![server]
+    } else {
+        app_conf.load_all_server_yml(false)
+    })
+}
 
 pub fn archive_local(
     app_conf: &AppConf,
     server_yml: Option<&str>,
     prune: bool,
     prune_only: bool,
+    dry_run: bool,
+    json: bool,
 ) -> Result<(), failure::Error>
 {
-    let servers = if let Some(server_yml) = server_yml {
-        let server = app_conf.load_server_from_yml(server_yml, false)?;
-        vec![server]
-    } else {
-        app_conf.load_all_server_yml(false)
-    };
+    let servers = load_servers(app_conf, server_yml)?;
+
+    if dry_run {
+        return print_prune_plan(&servers, json);
+    }
 
     if servers.is_empty() {
         println!("found no server yml!");
@@ -51,4 +66,161 @@ pub fn archive_local(
         })
         .count();
     Ok(())
-}
\ No newline at end of file
+}
+/// The passphrase is a literal or a reference like `${env:NAME}`, or the one in the server yml.
+/// Without out the archive is decrypted next to itself with .enc removed.
+pub fn decrypt_archive(
+    app_conf: &AppConf,
+    archive: &str,
+    out: Option<&str>,
+    server_yml: Option<&str>,
+    passphrase: Option<&str>,
+) -> Result<(), failure::Error> {
+    let passphrase = if let Some(passphrase) = passphrase {
+        let mut secret = Secret::new(passphrase);
+        secret.resolve(&app_conf.load_secrets()?)?;
+        secret
+    } else if let Some(server_yml) = server_yml {
+        let server = app_conf.load_server_from_yml(server_yml, false)?;
+        match server.server_yml.encryption {
+            Some(encryption) => encryption.passphrase,
+            None => bail!("server yml {} doesn't configure encryption.", server_yml),
+        }
+    } else {
+        bail!("either --passphrase or --server-yml is required to decrypt.");
+    };
+
+    let out = match out {
+        Some(out) => PathBuf::from(out),
+        None => match archive.strip_suffix(ENCRYPTED_POSTFIX) {
+            Some(stripped) => PathBuf::from(stripped),
+            None => bail!("{} doesn't end with {}, please specify --out.", archive, ENCRYPTED_POSTFIX),
+        },
+    };
+    if out.exists() {
+        bail!("{:?} already exists.", out);
+    }
+    let header = archive_crypt::decrypt_archive(Path::new(archive), &out, &passphrase)?;
+    info!("decrypted {} to {:?} with key {}", archive, out, header.fingerprint());
+    println!("decrypted to {:?}, key fingerprint: {}", out, header.fingerprint());
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct PrunePlanItem {
+    #[serde(flatten)]
+    retention: ArchiveRetention,
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct ServerPrunePlan {
+    server: String,
+    archives: Vec<PrunePlanItem>,
+}
+
+/// Nothing is deleted, print why each archive would be kept or deleted.
+fn print_prune_plan(servers: &[Server], json: bool) -> Result<(), failure::Error> {
+    let mut plans = Vec::new();
+    for server in servers {
+        let strategy = &server.server_yml.prune_strategy;
+        let archives = server
+            .plan_prune()?
+            .into_iter()
+            .map(|retention| PrunePlanItem {
+                reason: retention.explain(strategy),
+                retention,
+            })
+            .collect();
+        plans.push(ServerPrunePlan {
+            server: server.get_host().to_string(),
+            archives,
+        });
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&plans)?);
+        return Ok(());
+    }
+    for plan in plans {
+        println!("{}:", plan.server);
+        if plan.archives.is_empty() {
+            println!("  no archives.");
+        }
+        for item in plan.archives {
+            println!(
+                "  {:<8} {:<45} {}",
+                if item.retention.delete { "DELETE" } else { "KEEP" },
+                item.retention
+                    .path
+                    .file_name()
+                    .map(|n| n.to_string_lossy())
+                    .unwrap_or_default(),
+                item.reason,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Every archive with the period deciding it and what the next prune does to it.
+pub fn list_archives(app_conf: &AppConf, server_yml: Option<&str>) -> Result<(), failure::Error> {
+    for server in load_servers(app_conf, server_yml)? {
+        println!("{}:", server.get_host());
+        let plan = server.plan_prune()?;
+        if plan.is_empty() {
+            println!("  no archives.");
+        }
+        for retention in plan {
+            let next_prune = retention.explain(&server.server_yml.prune_strategy);
+            println!(
+                "  {:<45} {:>14} {} {:<9} {}",
+                retention.path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default(),
+                retention.len,
+                retention.time.format("%Y-%m-%d %H:%M:%S"),
+                format!("{:?}", retention.bucket).to_lowercase(),
+                next_prune,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Verify the archive, or all archives of the server if it's None.
+pub fn verify_archive(
+    app_conf: &AppConf,
+    server_yml: &str,
+    archive: Option<&str>,
+) -> Result<(), failure::Error> {
+    let server = app_conf.load_server_from_yml(server_yml, false)?;
+    let archives = match archive {
+        Some(archive) => vec![PathBuf::from(archive)],
+        None => server.list_archive_files()?,
+    };
+    let mut failed = 0;
+    for archive in archives {
+        match server.verify_archive(&archive) {
+            Ok(verification) if verification.is_ok() => println!(
+                "OK {:?}, {} entries, {} bytes{}",
+                archive,
+                verification.entries,
+                verification.bytes,
+                if verification.with_manifest { "" } else { ", no manifest to check against" },
+            ),
+            Ok(verification) => {
+                failed += 1;
+                println!("CORRUPTED {:?}", archive);
+                for problem in verification.problems {
+                    println!("  {}", problem);
+                }
+            }
+            Err(err) => {
+                failed += 1;
+                println!("FAILED {:?}: {}", archive, err);
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} archives failed the verification.", failed);
+    }
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use crate::data_shape::AppConf;
+use crate::db_accesses::{DbAccess, SqliteDbAccess};
+
+/// Print the versions of the path recorded in the catalog and where they are.
+pub fn find(
+    app_conf: &AppConf,
+    path: &str,
+    server: Option<&str>,
+    json: bool,
+) -> Result<(), failure::Error> {
+    let db_file = app_conf.get_sqlite_db_file();
+    if !db_file.exists() {
+        bail!("no catalog in {:?} yet, nothing is synced.", db_file);
+    }
+    let catalog = SqliteDbAccess::open(&db_file)?;
+    let versions = catalog.find_file_versions(server, path)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&versions)?);
+        return Ok(());
+    }
+    if versions.is_empty() {
+        println!("no versions of {} found.", path);
+    }
+    for fv in versions.iter() {
+        println!(
+            "{} {} v{}, {} bytes, modified: {}, sha1: {}, run: {}",
+            fv.server,
+            fv.path,
+            fv.version,
+            fv.len,
+            fv.modified
+                .map(|m| m.to_rfc3339())
+                .unwrap_or_else(|| "-".to_string()),
+            fv.sha1.as_deref().unwrap_or("-"),
+            fv.run_id
+        );
+        println!("  at: {}", fv.location);
+        if let Some(archive) = fv.archive.as_ref() {
+            println!("  in: {}", archive);
+        }
+    }
+    Ok(())
+}
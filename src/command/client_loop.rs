@@ -1,12 +1,19 @@
 // use crate::actions;
-use crate::data_shape::{server, AppConf};
+use crate::data_shape::metrics::MetricsRegistry;
+use crate::data_shape::notification::{self, Notification, NotificationSummary};
+use crate::data_shape::{server, AppConf, MailConf};
+use crate::mail::report_mail;
 // use crate::db_accesses::SqliteDbAccess;
 use job_scheduler::{Job, JobScheduler};
+use std::path::PathBuf;
 // use r2d2_sqlite::SqliteConnectionManager;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use super::*;
 
+/// how often a server running as service checks it's own freshness.
+const FRESHNESS_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
 pub fn client_push_loops(
     app_conf: &AppConf,
     server_yml: Option<&str>,
@@ -22,7 +29,7 @@ pub fn client_push_loops(
     };
 
     
-    client_push_loop_by_spawn(servers, follow_archive, as_service)
+    client_push_loop_by_spawn(servers, Notifiers::from_app_conf(app_conf), follow_archive, as_service)
 }
 
 
@@ -31,12 +38,14 @@ pub fn client_push_loops(
 /// and disconnect from server when task is done.
 fn client_push_loop_by_spawn(
     servers: Vec<Server>,
+    notifiers: Notifiers,
     follow_archive: bool,
     as_service: bool,
 ) -> Result<(), failure::Error> {
+    notifiers.start_metrics(&servers, as_service);
     let handlers = servers
         .into_iter()
-        .map(|pair| client_push_loop_by_spawn_do(pair, follow_archive, as_service))
+        .map(|pair| client_push_loop_by_spawn_do(pair, notifiers.clone(), follow_archive, as_service))
         .filter_map(|i| i)
         .collect::<Vec<thread::JoinHandle<_>>>();
 
@@ -49,35 +58,27 @@ fn client_push_loop_by_spawn(
 
 fn client_push_loop_by_spawn_do(
     server: Server,
+    notifiers: Notifiers,
     follow_archive: bool,
     as_service: bool,
 ) -> Option<thread::JoinHandle<()>> {
     if as_service {
         Some(thread::spawn(move || {
-            if let Some(schedule_item) = server.find_cron_by_name(server::CRON_NAME_SYNC_PULL_DIRS)
-            {
-                let mut sched = JobScheduler::new();
-                sched.add(Job::new(schedule_item.cron.parse().unwrap(), || {
-                    match server.client_push_loop(follow_archive) {
-                        Ok(_result) => {
-                            // indicator.pb_finish();
-                            // actions::write_dir_sync_result(&server, result.as_ref());
-                            // archive when succeeded.
-                            // if follow_archive {
-                            //     server.archive_local().ok();
-                            //     server.prune_backups().ok();
-                            // }
-                        }
-                        Err(err) => println!("client-push-loop failed: {:?}", err),
+            tick_as_service(&server, &notifiers, || {
+                match server.client_push_loop(follow_archive) {
+                    Ok(_result) => {
+                        // indicator.pb_finish();
+                        // actions::write_dir_sync_result(&server, result.as_ref());
+                        // archive when succeeded.
+                        // if follow_archive {
+                        //     server.archive_local().ok();
+                        //     server.prune_backups().ok();
+                        // }
                     }
-                }));
-
-                eprintln!("entering sched ticking.");
-                loop {
-                    sched.tick();
-                    thread::sleep(Duration::from_millis(500));
+                    Err(err) => println!("client-push-loop failed: {:?}", err),
                 }
-            }
+                notifiers.notify_latest_report(&server);
+            });
         }))
     } else {
         match server.client_push_loop(follow_archive) {
@@ -92,6 +93,7 @@ fn client_push_loop_by_spawn_do(
             }
             Err(err) => println!("client-push-loop failed {:?}", err),
         }
+        notifiers.notify_latest_report(&server);
         None
     }
 }
@@ -109,7 +111,7 @@ pub fn client_pull_loops(
     } else {
         app_conf.load_all_server_yml(false)
     };
-    client_pull_loop_by_spawn(servers, follow_archive, as_service)
+    client_pull_loop_by_spawn(servers, Notifiers::from_app_conf(app_conf), follow_archive, as_service)
 }
 
 
@@ -118,12 +120,14 @@ pub fn client_pull_loops(
 /// and disconnect from server when task is done.
 fn client_pull_loop_by_spawn(
     server_indicator_pairs: Vec<Server>,
+    notifiers: Notifiers,
     follow_archive: bool,
     as_service: bool,
 ) -> Result<(), failure::Error> {
+    notifiers.start_metrics(&server_indicator_pairs, as_service);
     let handlers = server_indicator_pairs
         .into_iter()
-        .map(|pair| client_pull_loop_by_spawn_do(pair, follow_archive, as_service))
+        .map(|pair| client_pull_loop_by_spawn_do(pair, notifiers.clone(), follow_archive, as_service))
         .filter_map(|i| i)
         .collect::<Vec<thread::JoinHandle<_>>>();
 
@@ -136,32 +140,24 @@ fn client_pull_loop_by_spawn(
 
 fn client_pull_loop_by_spawn_do(
     server: Server,
+    notifiers: Notifiers,
     follow_archive: bool,
     as_service: bool,
 ) -> Option<thread::JoinHandle<()>> {
     if as_service {
         Some(thread::spawn(move || {
-            if let Some(schedule_item) = server.find_cron_by_name(server::CRON_NAME_SYNC_PULL_DIRS)
-            {
-                let mut sched = JobScheduler::new();
-                sched.add(Job::new(schedule_item.cron.parse().unwrap(), || {
-                    match server.client_pull_loop() {
-                        Ok(_result) => {
-                            if follow_archive {
-                                server.archive_local().ok();
-                                server.prune_backups().ok();
-                            }
+            tick_as_service(&server, &notifiers, || {
+                match server.client_pull_loop() {
+                    Ok(_result) => {
+                        if follow_archive {
+                            server.archive_local().ok();
+                            server.prune_backups().ok();
                         }
-                        Err(err) => println!("client-push-loop failed: {:?}", err),
                     }
-                }));
-
-                eprintln!("entering sched ticking.");
-                loop {
-                    sched.tick();
-                    thread::sleep(Duration::from_millis(500));
+                    Err(err) => println!("client-push-loop failed: {:?}", err),
                 }
-            }
+                notifiers.notify_latest_report(&server);
+            });
         }))
     } else {
         match server.client_pull_loop() {
@@ -173,6 +169,134 @@ fn client_pull_loop_by_spawn_do(
             }
             Err(err) => println!("client-push-loop failed {:?}", err),
         }
+        notifiers.notify_latest_report(&server);
         None
     }
 }
+
+/// Run the sync by the sync-pull-dirs schedule and check the freshness every FRESHNESS_CHECK_INTERVAL.
+/// Without the schedule the server is never synced, the freshness check tells it if max_backup_age is set.
+fn tick_as_service<'a>(server: &'a Server, notifiers: &'a Notifiers, sync: impl FnMut() + 'a) {
+    let mut sched = JobScheduler::new();
+    match server.find_cron_by_name(server::CRON_NAME_SYNC_PULL_DIRS) {
+        Some(schedule_item) => {
+            sched.add(Job::new(schedule_item.cron.parse().unwrap(), sync));
+        }
+        None if server.server_yml.max_backup_age.is_some() => error!(
+            "no schedule named {} for {}, it's never synced.",
+            server::CRON_NAME_SYNC_PULL_DIRS,
+            server.get_host()
+        ),
+        None => {
+            error!(
+                "no schedule named {} for {}, nothing to do.",
+                server::CRON_NAME_SYNC_PULL_DIRS,
+                server.get_host()
+            );
+            return;
+        }
+    }
+
+    eprintln!("entering sched ticking.");
+    let mut last_check: Option<Instant> = None;
+    let mut stale_notified = false;
+    loop {
+        sched.tick();
+        if last_check.is_none_or(|t| t.elapsed() >= FRESHNESS_CHECK_INTERVAL) {
+            last_check.replace(Instant::now());
+            stale_notified = notifiers.notify_if_stale(server, stale_notified);
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// The mails, the notifications and the metrics of the app conf, each server thread gets a copy.
+#[derive(Clone)]
+struct Notifiers {
+    mail_conf: MailConf,
+    notifications: Vec<Notification>,
+    prom_file: Option<PathBuf>,
+    metrics_listen: Option<String>,
+    metrics: MetricsRegistry,
+}
+
+impl Notifiers {
+    fn from_app_conf(app_conf: &AppConf) -> Self {
+        Self {
+            mail_conf: app_conf.get_mail_conf().clone(),
+            notifications: app_conf.get_notifications().to_vec(),
+            prom_file: app_conf.get_prom_file(),
+            metrics_listen: app_conf.get_metrics_conf().listen.clone(),
+            metrics: MetricsRegistry::default(),
+        }
+    }
+
+    /// Fill in the metrics of all the servers before the first run, and serve them if running as service.
+    fn start_metrics(&self, servers: &[Server], as_service: bool) {
+        if self.prom_file.is_none() && (!as_service || self.metrics_listen.is_none()) {
+            return;
+        }
+        for server in servers {
+            self.update_metrics(server);
+        }
+        self.write_prom_file();
+        if let (true, Some(listen)) = (as_service, self.metrics_listen.as_ref()) {
+            if let Err(err) = self.metrics.serve(listen) {
+                error!("serve metrics on {} failed: {:?}", listen, err);
+            }
+        }
+    }
+
+    fn update_metrics(&self, server: &Server) {
+        match server.collect_metrics() {
+            Ok(m) => self.metrics.update(m),
+            Err(err) => error!("collect the metrics of {} failed: {:?}", server.get_host(), err),
+        }
+    }
+
+    fn write_prom_file(&self) {
+        if let Some(prom_file) = self.prom_file.as_ref() {
+            if let Err(err) = self.metrics.write_prom_file(prom_file) {
+                error!("write metrics to {:?} failed: {:?}", prom_file, err);
+            }
+        }
+    }
+
+    /// Notify once when it becomes stale, returns whether it's stale.
+    fn notify_if_stale(&self, server: &Server, notified: bool) -> bool {
+        if server.server_yml.max_backup_age.is_none() {
+            return false;
+        }
+        match server.check_freshness() {
+            Ok(freshness) if freshness.is_stale() => {
+                if !notified {
+                    warn!("{}", freshness.message());
+                    notification::notify_all(
+                        &self.notifications,
+                        &NotificationSummary::stale(server.get_host(), freshness.message()),
+                    );
+                }
+                true
+            }
+            Ok(_) => false,
+            Err(err) => {
+                error!("check the freshness of {} failed: {:?}", server.get_host(), err);
+                notified
+            }
+        }
+    }
+
+    /// The report just saved by the run is the latest one of the server.
+    fn notify_latest_report(&self, server: &Server) {
+        self.update_metrics(server);
+        self.write_prom_file();
+        match server.latest_sync_report() {
+            Ok(Some(report)) => {
+                report_mail::notify_run(&self.mail_conf, &report);
+                notification::notify_all(&self.notifications, &NotificationSummary::from_report(&report));
+            }
+            Ok(None) => warn!("no sync report of {} to notify.", server.get_host()),
+            Err(err) => error!("load the sync report of {} failed: {:?}", server.get_host(), err),
+        }
+    }
+}
@@ -63,3 +63,40 @@ pub fn create_db<'a>(
         Ok(false)
     }
 }
+
+/// db status and db migrate, before the app's db is opened, which applies the migrations.
+pub fn db<'a>(app_conf: &AppConf, m: &'a clap::ArgMatches<'a>) -> Result<bool, failure::Error> {
+    let (migrate, sub_matches) = match m.subcommand() {
+        ("db", Some(db_matches)) => match db_matches.subcommand() {
+            ("status", Some(sub_matches)) => (false, sub_matches),
+            ("migrate", Some(sub_matches)) => (true, sub_matches),
+            _ => bail!("expect db status or db migrate."),
+        },
+        _ => return Ok(false),
+    };
+    let db_file = match sub_matches.value_of("server-yml") {
+        Some(server_yml) => app_conf.load_server_from_yml(server_yml, false)?.get_db_file(),
+        None => app_conf.get_sqlite_db_file(),
+    };
+    let sqlite_db_access = SqliteDbAccess::new(&db_file);
+    if migrate {
+        let applied = sqlite_db_access.migrate()?;
+        if applied.is_empty() {
+            println!("{:?} is up to date.", db_file);
+        }
+        for m in applied {
+            println!("applied {}: {}", m.version, m.name);
+        }
+    } else {
+        println!("{:?}", db_file);
+        for status in sqlite_db_access.schema_status()? {
+            println!(
+                "{:>4} {:<40} {}",
+                status.version,
+                status.name,
+                status.applied.as_deref().unwrap_or("pending")
+            );
+        }
+    }
+    Ok(true)
+}
@@ -1,6 +1,8 @@
 pub mod archives;
+pub mod catalog;
 pub mod db_cmd;
 pub mod misc;
+pub mod report;
 pub mod rsync;
 // pub mod sync_dirs;
 pub mod client_loop;
@@ -0,0 +1,87 @@
+use chrono::Local;
+use log::*;
+use std::path::PathBuf;
+
+use crate::data_shape::freshness::Freshness;
+use crate::data_shape::notification::{self, NotificationSummary};
+use crate::data_shape::sync_report::{self, SyncReport};
+use crate::data_shape::AppConf;
+use crate::mail::report_mail;
+
+/// Print the latest report of the server, or the chosen one, a file name in the reports directory or a path.
+pub fn print_report(
+    app_conf: &AppConf,
+    server_yml: &str,
+    report: Option<&str>,
+    json: bool,
+) -> Result<(), failure::Error> {
+    let server = app_conf.load_server_from_yml(server_yml, false)?;
+    let path = match report {
+        Some(report) if report.contains('/') || report.contains('\\') => PathBuf::from(report),
+        Some(report) => server.get_reports_dir().join(report),
+        None => match sync_report::list_reports(server.get_reports_dir())?.pop() {
+            Some(path) => path,
+            None => bail!("no reports in {:?} yet.", server.get_reports_dir()),
+        },
+    };
+    let report = SyncReport::load(&path)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        println!("report: {:?}", path);
+        print!("{}", report.to_text());
+    }
+    Ok(())
+}
+
+/// Mail the reports of all servers started in the last hours to the recipients.
+pub fn send_report_digest(app_conf: &AppConf, hours: i64) -> Result<(), failure::Error> {
+    let since = Local::now() - chrono::Duration::hours(hours);
+    let mut reports = Vec::new();
+    for server in app_conf.load_all_server_yml(false) {
+        for path in sync_report::list_reports(server.get_reports_dir())? {
+            match SyncReport::load(&path) {
+                Ok(report) if report.started_at >= since => reports.push(report),
+                Ok(_) => (),
+                Err(err) => warn!("{}", err),
+            }
+        }
+    }
+    reports.sort_by_key(|r| r.started_at);
+    report_mail::send_digest(app_conf.get_mail_conf(), &since, &reports)?;
+    println!("mailed the digest of {} runs.", reports.len());
+    Ok(())
+}
+
+/// Print the freshness of the servers, true if any of them is stale.
+pub fn check_freshness(
+    app_conf: &AppConf,
+    server_yml: Option<&str>,
+    json: bool,
+    notify: bool,
+) -> Result<bool, failure::Error> {
+    let servers = match server_yml {
+        Some(server_yml) => vec![app_conf.load_server_from_yml(server_yml, false)?],
+        None => app_conf.load_all_server_yml(false),
+    };
+    let mut freshnesses = Vec::new();
+    for server in servers.iter() {
+        freshnesses.push(server.check_freshness()?);
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&freshnesses)?);
+    } else {
+        for freshness in freshnesses.iter() {
+            println!("{}", freshness.message());
+        }
+    }
+    if notify {
+        for freshness in freshnesses.iter().filter(|f| f.is_stale()) {
+            notification::notify_all(
+                app_conf.get_notifications(),
+                &NotificationSummary::stale(&freshness.server, freshness.message()),
+            );
+        }
+    }
+    Ok(freshnesses.iter().any(Freshness::is_stale))
+}
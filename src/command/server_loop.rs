@@ -1,9 +1,28 @@
-use crate::data_shape::{FileChanged, FullPathFileItem, ServerYml, SlashPath};
+use crate::data_shape::sync_hook::{self, HookStage, SERVER_SCOPE};
+use crate::data_shape::{
+    data_shape_util, Directory, FileChanged, FullPathFileItem, ServerYml, SlashPath,
+};
+use crate::db_accesses::SqliteDbAccess;
 use crate::protocol::{MessageHub, StdInOutMessageHub, StringMessage, TransferType, U64Message};
 use dirs;
 use filetime;
 use log::*;
 use std::io::{self};
+use std::time::Duration;
+
+/// relative to the working dir like the logs, no app conf on this side.
+const DIGEST_CACHE_FILE: &str = "data/digests.db";
+
+/// None if it can't be opened, the files are hashed every time then.
+fn open_digest_cache() -> Option<SqliteDbAccess> {
+    match SqliteDbAccess::open(DIGEST_CACHE_FILE) {
+        Ok(digests) => Some(digests),
+        Err(err) => {
+            error!("open the digest cache {} failed: {:?}", DIGEST_CACHE_FILE, err);
+            None
+        }
+    }
+}
 
 /// how to determine the directories? it's in the user's home directory.
 pub fn server_receive_loop() -> Result<(), failure::Error> {
@@ -31,9 +50,13 @@ pub fn server_receive_loop() -> Result<(), failure::Error> {
     match message_hub.read_type_byte()? {
         TransferType::ServerYml => {
             let string_message = StringMessage::parse(&mut message_hub)?;
-            trace!("got server_yml content: {}", string_message.content);
             match serde_yaml::from_str::<ServerYml>(&string_message.content) {
                 Ok(server_yml) => {
+                    // log the serialized one, the secrets in it are redacted.
+                    trace!(
+                        "got server_yml content: {}",
+                        serde_yaml::to_string(&server_yml).unwrap_or_default()
+                    );
                     server_yml_op.replace(server_yml);
                 }
                 Err(err) => {
@@ -52,6 +75,8 @@ pub fn server_receive_loop() -> Result<(), failure::Error> {
     let mut last_df: Option<SlashPath> = None;
     let mut last_file_item: Option<FullPathFileItem> = None;
     let mut buf = vec![0; 8192];
+    // only the items with a sha1 are hashed here.
+    let digests = open_digest_cache();
     // after read server_yml, we wait the other side to send file items.
     loop {
         let type_byte = match message_hub.read_type_byte() {
@@ -69,7 +94,7 @@ pub fn server_receive_loop() -> Result<(), failure::Error> {
                 match serde_json::from_str::<FullPathFileItem>(&string_message.content) {
                     Ok(file_item) => {
                         let df = home_dir.join_another(&file_item.to_path); // use to path.
-                        match file_item.changed(df.as_path()) {
+                        match file_item.changed(df.as_path(), digests.as_ref()) {
                             FileChanged::NoChange => {
                                 message_hub
                                     .write_transfer_type_only(TransferType::FileItemUnchanged)?;
@@ -109,6 +134,11 @@ pub fn server_receive_loop() -> Result<(), failure::Error> {
                                     "push_primary_file_item has no modified value.",
                                 )?;
                             }
+                            data_shape_util::refresh_digest(
+                                df.as_path(),
+                                file_item.sha1.as_deref(),
+                                digests.as_ref(),
+                            );
                         }
                     }
                 } else {
@@ -144,9 +174,13 @@ pub fn server_send_loop(skip_sha1: bool) -> Result<(), failure::Error> {
     match message_hub.read_type_byte()? {
         TransferType::ServerYml => {
             let string_message = StringMessage::parse(&mut message_hub)?;
-            trace!("got server_yml content: {}", string_message.content);
             match serde_yaml::from_str::<ServerYml>(&string_message.content) {
                 Ok(server_yml) => {
+                    // log the serialized one, the secrets in it are redacted.
+                    trace!(
+                        "got server_yml content: {}",
+                        serde_yaml::to_string(&server_yml).unwrap_or_default()
+                    );
                     server_yml_op.replace(server_yml);
                 }
                 Err(err) => {
@@ -181,39 +215,114 @@ pub fn server_send_loop(skip_sha1: bool) -> Result<(), failure::Error> {
     let mut buf = vec![0; 8192];
 
     let possible_encoding = server_yml.get_possible_encoding();
+    let digests = if skip_sha1 { None } else { open_digest_cache() };
+    let hook_timeout = Duration::from_secs(server_yml.hook_timeout_secs);
+
+    if send_hook_results(
+        &mut message_hub,
+        &server_yml.pre_sync,
+        HookStage::PreSync,
+        SERVER_SCOPE,
+        hook_timeout,
+    )? {
+        for dir in server_yml.directories.iter() {
+            let scope = dir.from_dir.as_str();
+            if send_hook_results(
+                &mut message_hub,
+                &dir.pre_sync,
+                HookStage::PreSync,
+                scope,
+                hook_timeout,
+            )? {
+                send_directory(
+                    &mut message_hub,
+                    dir,
+                    skip_sha1,
+                    &possible_encoding,
+                    digests.as_ref(),
+                    &mut buf,
+                )?;
+            } else {
+                error!("pre_sync of {} failed, skip it.", scope);
+            }
+            send_hook_results(
+                &mut message_hub,
+                &dir.post_sync,
+                HookStage::PostSync,
+                scope,
+                hook_timeout,
+            )?;
+        }
+    } else {
+        error!("pre_sync of the server failed, skip all directories.");
+    }
+    send_hook_results(
+        &mut message_hub,
+        &server_yml.post_sync,
+        HookStage::PostSync,
+        SERVER_SCOPE,
+        hook_timeout,
+    )?;
+    message_hub.write_and_flush(&[TransferType::RepeatDone.to_u8()])?;
+    Ok(())
+}
 
-    for dir in server_yml.directories.iter() {
-        trace!("start proceess directory: {:?}", dir);
-        let push_file_items = dir.file_item_iter("", skip_sha1, &possible_encoding);
-        for fi in push_file_items {
-            match fi {
-                Ok(fi) => {
-                    message_hub.write_and_flush(&fi.as_sent_bytes())?;
-                    match message_hub.read_type_byte().expect("read type byte.") {
-                        TransferType::FileItemChanged => {
-                            let change_message = StringMessage::parse(&mut message_hub)?;
-                            trace!("changed file: {}.", change_message.content);
-                            message_hub.copy_from_file(&mut buf, &fi, None)?;
-                            trace!("send file content done.");
-                        }
-                        TransferType::FileItemUnchanged => {
-                            trace!("unchanged file.");
-                        }
-                        TransferType::StringError => {
-                            let ss = StringMessage::parse(&mut message_hub)?;
-                            error!("string error: {:?}", ss.content);
-                        }
-                        i => error!("got unexpected transfer type {:?}", i),
+/// Run the hooks and send every result to the other side, false if any of them failed.
+fn send_hook_results(
+    message_hub: &mut impl MessageHub,
+    commands: &[String],
+    stage: HookStage,
+    scope: &str,
+    timeout: Duration,
+) -> Result<bool, failure::Error> {
+    let results = sync_hook::run_hooks(commands, stage, scope, timeout);
+    for result in results.iter() {
+        trace!("{}", result.summary());
+        let string_message = StringMessage::new(serde_json::to_string(result)?);
+        message_hub.write_and_flush(
+            &string_message.as_string_sent_bytes_with_header(TransferType::HookResult),
+        )?;
+    }
+    Ok(sync_hook::all_succeeded(&results))
+}
+
+fn send_directory(
+    message_hub: &mut impl MessageHub,
+    dir: &Directory,
+    skip_sha1: bool,
+    possible_encoding: &Vec<&'static encoding_rs::Encoding>,
+    digests: Option<&SqliteDbAccess>,
+    buf: &mut [u8],
+) -> Result<(), failure::Error> {
+    trace!("start proceess directory: {:?}", dir);
+    let push_file_items = dir.file_item_iter("", skip_sha1, possible_encoding, digests);
+    for fi in push_file_items {
+        match fi {
+            Ok(fi) => {
+                message_hub.write_and_flush(&fi.as_sent_bytes())?;
+                match message_hub.read_type_byte().expect("read type byte.") {
+                    TransferType::FileItemChanged => {
+                        let change_message = StringMessage::parse(message_hub)?;
+                        trace!("changed file: {}.", change_message.content);
+                        message_hub.copy_from_file(buf, &fi, None)?;
+                        trace!("send file content done.");
                     }
-                }
-                Err(e) => {
-                    message_hub.write_error_message(format!("{}", e))?;
-                    error!("{:?}", e);
+                    TransferType::FileItemUnchanged => {
+                        trace!("unchanged file.");
+                    }
+                    TransferType::StringError => {
+                        let ss = StringMessage::parse(message_hub)?;
+                        error!("string error: {:?}", ss.content);
+                    }
+                    i => error!("got unexpected transfer type {:?}", i),
                 }
             }
+            Err(e) => {
+                message_hub.write_error_message(format!("{}", e))?;
+                error!("{:?}", e);
+            }
         }
     }
-    message_hub.write_and_flush(&[TransferType::RepeatDone.to_u8()])?;
     Ok(())
 }
 
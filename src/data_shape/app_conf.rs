@@ -1,5 +1,10 @@
-use crate::data_shape::{string_path, Server, ServerYml};
+use crate::data_shape::rolling_files::RetentionTimezone;
+use crate::data_shape::notification::Notification;
+use crate::data_shape::sync_log::SyncLogFormat;
+use crate::data_shape::work_lock::WorkLock;
+use crate::data_shape::{secret, string_path, Secret, Secrets, Server, ServerYml};
 use crate::db_accesses::{SqliteDbAccess};
+use crate::log_util::LogRotation;
 use indicatif::MultiProgress;
 use log::*;
 use log::{trace, warn};
@@ -14,6 +19,8 @@ use r2d2_sqlite::SqliteConnectionManager;
 
 pub const APP_CONFIG_BYTES: &[u8] = include_bytes!("../app_config_demo.yml");
 pub const CONF_FILE_NAME: &str = "bk_over_ssh.yml";
+/// under the data_dir, the catalog of the file versions is in it.
+pub const SQLITE_DB_FILE_NAME: &str = "db.db";
 
 pub const PULL_CONF: &str = "pull-conf";
 pub const PULL_DATA: &str = "pull-data";
@@ -33,6 +40,11 @@ pub const PUSH_DATA: &str = "push-data";
 pub struct LogConf {
     pub log_file: String,
     verbose_modules: Vec<String>,
+    /// of the log_file and the sync.log of each server.
+    #[serde(default)]
+    pub rotation: LogRotation,
+    #[serde(default)]
+    pub sync_log_format: SyncLogFormat,
 }
 
 impl LogConf {
@@ -70,13 +82,29 @@ impl FromStr for AppRole {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize, Default)]
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
 pub struct MailConf {
     pub from: String,
     pub username: String,
-    pub password: String,
+    pub password: Secret,
     pub hostname: String,
     pub port: u16,
+    /// who get the report mails, no mails if it's empty.
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    /// mail the report of a run only when it failed.
+    #[serde(default)]
+    pub on_failure_only: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+pub struct MetricsConf {
+    /// rewritten after each run for node_exporter's textfile collector, relative to data_dir.
+    #[serde(default)]
+    pub prom_file: Option<String>,
+    /// like 127.0.0.1:9184, serves /metrics when running as service.
+    #[serde(default)]
+    pub listen: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -86,6 +114,17 @@ pub struct AppConfYml {
     log_conf: LogConf,
     pub mail_conf: MailConf,
     archive_cmd: Vec<String>,
+    /// relative to data_dir, default to secrets.yml.
+    #[serde(default)]
+    secrets_file: Option<String>,
+    /// the archives are named and pruned by the days and hours in it.
+    #[serde(default)]
+    retention_timezone: RetentionTimezone,
+    /// webhooks and commands told about the runs and the stale servers.
+    #[serde(default)]
+    notifications: Vec<Notification>,
+    #[serde(default)]
+    metrics_conf: MetricsConf,
 }
 
 impl Default for AppConfYml {
@@ -97,6 +136,10 @@ impl Default for AppConfYml {
             mail_conf: MailConf::default(),
             log_conf: LogConf::default(),
             archive_cmd: Vec::new(),
+            secrets_file: None,
+            retention_timezone: RetentionTimezone::Utc,
+            notifications: Vec::new(),
+            metrics_conf: MetricsConf::default(),
         }
     }
 }
@@ -142,6 +185,10 @@ pub struct MiniAppConf {
     pub console_log: bool,
     pub as_service: bool,
     pub show_pb: bool,
+    pub data_dir: PathBuf,
+    pub retention_timezone: RetentionTimezone,
+    pub log_rotation: LogRotation,
+    pub sync_log_format: SyncLogFormat,
 }
 
 #[derive(Debug, Serialize)]
@@ -156,7 +203,7 @@ pub struct AppConf {
     #[serde(skip)]
     _m: PhantomData<SqliteConnectionManager>,
     #[serde(skip)]
-    lock_file: Option<fs::File>,
+    work_lock: Option<WorkLock>,
     #[serde(skip)]
     pub progress_bar: Option<Arc<MultiProgress>>,
     pub mini_app_conf: MiniAppConf,
@@ -190,7 +237,7 @@ pub fn demo_app_conf(data_dir: &str, app_role: AppRole) -> AppConf {
         servers_conf_dir: PathBuf::from("data").join(servers_conf_dir_name),
         _m: PhantomData,
         db_access: None,
-        lock_file: None,
+        work_lock: None,
         progress_bar: None,
         mini_app_conf: MiniAppConf {
             app_instance_id: "demo-app-instance-id".to_string(),
@@ -202,6 +249,10 @@ pub fn demo_app_conf(data_dir: &str, app_role: AppRole) -> AppConf {
             console_log: false,
             as_service: false,
             show_pb: false,
+            data_dir: PathBuf::from(data_dir),
+            retention_timezone: RetentionTimezone::Utc,
+            log_rotation: LogRotation::default(),
+            sync_log_format: SyncLogFormat::Text,
         },
     }
 }
@@ -215,7 +266,7 @@ impl AppConf {
     }
 
     pub fn get_sqlite_db_file(&self) -> PathBuf {
-        self.data_dir_full_path.join("db.db")
+        self.data_dir_full_path.join(SQLITE_DB_FILE_NAME)
     }
 
     #[allow(dead_code)]
@@ -297,16 +348,19 @@ impl AppConf {
 
                         let archive_cmd = app_conf_yml.archive_cmd.clone();
                         let app_instance_id = app_conf_yml.app_instance_id.clone();
+                        let retention_timezone = app_conf_yml.retention_timezone;
+                        let log_rotation = app_conf_yml.log_conf.rotation;
+                        let sync_log_format = app_conf_yml.log_conf.sync_log_format;
 
-                        let app_conf = AppConf {
+                        let mut app_conf = AppConf {
                             inner: app_conf_yml,
                             config_file_path: file.to_path_buf(),
-                            data_dir_full_path,
+                            data_dir_full_path: data_dir_full_path.clone(),
                             log_full_path,
                             servers_conf_dir,
                             db_access: None,
                             _m: PhantomData,
-                            lock_file: None,
+                            work_lock: None,
                             progress_bar: None,
                             mini_app_conf: MiniAppConf {
                                 app_instance_id,
@@ -318,8 +372,17 @@ impl AppConf {
                                 console_log: false,
                                 as_service: false,
                                 show_pb: false,
+                                data_dir: data_dir_full_path,
+                                retention_timezone,
+                                log_rotation,
+                                sync_log_format,
                             },
                         };
+                        let secrets = app_conf.load_secrets()?;
+                        app_conf.inner.mail_conf.password.resolve(&secrets)?;
+                        for notification in app_conf.inner.notifications.iter_mut() {
+                            notification.resolve_secrets(&secrets)?;
+                        }
                         Ok(app_conf)
                     }
                     Err(err) => {
@@ -343,9 +406,36 @@ impl AppConf {
         }
     }
 
+    pub fn get_secrets_file(&self) -> PathBuf {
+        match self.inner.secrets_file.as_ref() {
+            Some(f) => self.data_dir_full_path.join(f),
+            None => self.data_dir_full_path.join(secret::SECRETS_FILE_NAME),
+        }
+    }
+
+    pub fn load_secrets(&self) -> Result<Secrets, failure::Error> {
+        Secrets::load(self.get_secrets_file())
+    }
+
     pub fn get_mail_conf(&self) -> &MailConf {
         &self.inner.mail_conf
     }
+
+    pub fn get_notifications(&self) -> &[Notification] {
+        &self.inner.notifications
+    }
+
+    pub fn get_metrics_conf(&self) -> &MetricsConf {
+        &self.inner.metrics_conf
+    }
+
+    pub fn get_prom_file(&self) -> Option<PathBuf> {
+        self.inner
+            .metrics_conf
+            .prom_file
+            .as_ref()
+            .map(|f| self.data_dir_full_path.join(f))
+    }
     #[allow(dead_code)]
     pub fn write_to_working_dir(&self) -> Result<(), failure::Error> {
         let yml_serialized = serde_yaml::to_string(&self.inner)?;
@@ -393,18 +483,12 @@ impl AppConf {
         AppConf::read_app_conf(app_conf_file, app_role)
     }
     
-    #[allow(dead_code)]
+    /// Taken by the runs over all the servers, only one of them works on the data dir at a time.
     pub fn lock_working_file(&mut self) -> Result<(), failure::Error> {
         let lof = self.data_dir_full_path.as_path().join("working.lock");
         trace!("start locking file: {:?}", lof);
-        if lof.exists() {
-            if fs::remove_file(lof.as_path()).is_err() {
-                eprintln!("create lock file failed: {:?}, if you can sure app isn't running, you can delete it manually.", lof);
-            }
-        } else {
-            self.lock_file
-                .replace(fs::OpenOptions::new().write(true).create(true).open(&lof)?);
-        }
+        let name = format!("app instance {}", self.mini_app_conf.app_instance_id);
+        self.work_lock.replace(WorkLock::acquire(&lof, &name)?);
         trace!("locked!");
         Ok(())
     }
@@ -497,12 +581,14 @@ impl AppConf {
         let mut f = fs::OpenOptions::new().read(true).open(&server_yml_path)?;
         let mut buf = String::new();
         f.read_to_string(&mut buf)?;
-        let server_yml: ServerYml = match serde_yaml::from_str(&buf) {
+        let mut server_yml: ServerYml = match serde_yaml::from_str(&buf) {
             Ok(server_yml) => server_yml,
             Err(err) => {
                 bail!("parse yml file: {:?} failed: {}", server_yml_path, err);
             }
         };
+        server_yml.apply_ssh_config()?;
+        server_yml.resolve_secrets(&self.load_secrets()?)?;
 
         let conf_data_dir = self.data_dir_full_path.as_path();
 
@@ -536,8 +622,10 @@ impl AppConf {
         // };
 
         let mut server = Server::new(self.mini_app_conf.clone(), my_dir, server_yml)?;
-
-        server.lock_working_file()?;
+        // the servers share the db of the app, it's opened and migrated once.
+        if let Some(db_access) = self.db_access.as_ref() {
+            server.set_db_access(db_access.clone());
+        }
 
         if let Some(bl) = self.mini_app_conf.buf_len {
             server.server_yml.buf_len = bl;
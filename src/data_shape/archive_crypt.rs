@@ -0,0 +1,531 @@
+//! Encryption of the archives at rest, the key is derived from a passphrase by scrypt.
+//! The archive is cut into chunks sealed by ChaCha20-Poly1305, the nonce of a chunk is
+//! prefix + counter + last flag, so reordered, truncated or appended chunks fail to open.
+//! The header goes into every chunk as associated data.
+use super::{FinishWrite, Secret};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use log::*;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+pub const ENCRYPTED_POSTFIX: &str = ".enc";
+
+const MAGIC: &[u8; 8] = b"BKOSENC1";
+/// 2 checks the passphrase by a mac of the derived key, 1 by scrypt with a fixed salt.
+const VERSION: u8 = 2;
+const VERSION_FIXED_SALT_FINGERPRINT: u8 = 1;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const SALT_LEN: usize = 16;
+const NONCE_PREFIX_LEN: usize = 7;
+const FINGERPRINT_LEN: usize = 8;
+const HEADER_LEN: usize = 8 + 1 + 1 + 4 + 4 + SALT_LEN + NONCE_PREFIX_LEN + FINGERPRINT_LEN;
+/// the salt of the fingerprint in version 1 archives, the same for every archive.
+const FINGERPRINT_SALT: &[u8] = b"bk-over-ssh fingerprint";
+const FINGERPRINT_LABEL: &[u8] = b"bk-over-ssh fingerprint";
+
+#[derive(Debug, Fail)]
+pub enum ArchiveCryptError {
+    #[fail(display = "not an encrypted archive.")]
+    NotEncrypted,
+    #[fail(display = "unsupported encrypted archive version: {}", _0)]
+    UnsupportedVersion(u8),
+    #[fail(
+        display = "wrong key, the archive was encrypted by key {} but the passphrase is of key {}.",
+        expected, actual
+    )]
+    WrongKey { expected: String, actual: String },
+    #[fail(display = "the archive is corrupted or truncated at chunk {}.", _0)]
+    Corrupted(u32),
+}
+
+fn default_scrypt_log_n() -> u8 {
+    15
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ArchiveEncryption {
+    pub passphrase: Secret,
+    /// scrypt cost as log2(N), higher is slower to brute force and to derive.
+    #[serde(default = "default_scrypt_log_n")]
+    pub scrypt_log_n: u8,
+}
+
+/// What's written at the start of an encrypted archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveHeader {
+    pub version: u8,
+    pub scrypt_log_n: u8,
+    pub scrypt_r: u32,
+    pub scrypt_p: u32,
+    salt: [u8; SALT_LEN],
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    fingerprint: [u8; FINGERPRINT_LEN],
+}
+
+impl ArchiveHeader {
+    /// Checks the passphrase, it's salted by the archive so it differs between archives.
+    pub fn fingerprint(&self) -> String {
+        to_hex(&self.fingerprint)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(self.version);
+        bytes.push(self.scrypt_log_n);
+        bytes.extend_from_slice(&self.scrypt_r.to_be_bytes());
+        bytes.extend_from_slice(&self.scrypt_p.to_be_bytes());
+        bytes.extend_from_slice(&self.salt);
+        bytes.extend_from_slice(&self.nonce_prefix);
+        bytes.extend_from_slice(&self.fingerprint);
+        bytes
+    }
+
+    pub fn read_from(r: &mut impl Read) -> Result<Self, failure::Error> {
+        let mut bytes = [0u8; HEADER_LEN];
+        if read_full(r, &mut bytes)? < HEADER_LEN || &bytes[..8] != MAGIC {
+            return Err(ArchiveCryptError::NotEncrypted.into());
+        }
+        if bytes[8] != VERSION && bytes[8] != VERSION_FIXED_SALT_FINGERPRINT {
+            return Err(ArchiveCryptError::UnsupportedVersion(bytes[8]).into());
+        }
+        let mut header = ArchiveHeader {
+            version: bytes[8],
+            scrypt_log_n: bytes[9],
+            scrypt_r: u32_at(&bytes, 10),
+            scrypt_p: u32_at(&bytes, 14),
+            salt: [0u8; SALT_LEN],
+            nonce_prefix: [0u8; NONCE_PREFIX_LEN],
+            fingerprint: [0u8; FINGERPRINT_LEN],
+        };
+        let mut i = 18;
+        header.salt.copy_from_slice(&bytes[i..i + SALT_LEN]);
+        i += SALT_LEN;
+        header
+            .nonce_prefix
+            .copy_from_slice(&bytes[i..i + NONCE_PREFIX_LEN]);
+        i += NONCE_PREFIX_LEN;
+        header
+            .fingerprint
+            .copy_from_slice(&bytes[i..i + FINGERPRINT_LEN]);
+        Ok(header)
+    }
+
+    fn scrypt(
+        &self,
+        passphrase: &Secret,
+        salt: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), failure::Error> {
+        if passphrase.is_empty() {
+            bail!("the passphrase of archive encryption is empty.");
+        }
+        let params =
+            match scrypt::ScryptParams::new(self.scrypt_log_n, self.scrypt_r, self.scrypt_p) {
+                Ok(params) => params,
+                Err(err) => bail!("invalid scrypt parameters: {}", err),
+            };
+        if let Err(err) = scrypt::scrypt(passphrase.expose().as_bytes(), salt, &params, out) {
+            bail!("derive key failed: {}", err);
+        }
+        Ok(())
+    }
+
+    fn derive_key(&self, passphrase: &Secret) -> Result<[u8; 32], failure::Error> {
+        let mut key = [0u8; 32];
+        self.scrypt(passphrase, &self.salt, &mut key)?;
+        Ok(key)
+    }
+
+    /// A mac of the derived key, nothing of the passphrase can be precomputed across archives.
+    fn key_fingerprint(key: &[u8; 32]) -> [u8; FINGERPRINT_LEN] {
+        let mut fingerprint = [0u8; FINGERPRINT_LEN];
+        let mut mac = Hmac::<Sha256>::new_varkey(key).expect("hmac takes keys of any length.");
+        mac.input(FINGERPRINT_LABEL);
+        fingerprint.copy_from_slice(&mac.result().code()[..FINGERPRINT_LEN]);
+        fingerprint
+    }
+
+    fn fixed_salt_fingerprint(
+        &self,
+        passphrase: &Secret,
+    ) -> Result<[u8; FINGERPRINT_LEN], failure::Error> {
+        let mut fingerprint = [0u8; FINGERPRINT_LEN];
+        self.scrypt(passphrase, FINGERPRINT_SALT, &mut fingerprint)?;
+        Ok(fingerprint)
+    }
+
+    /// The key if the passphrase matches the fingerprint.
+    fn check_passphrase(&self, passphrase: &Secret) -> Result<[u8; 32], failure::Error> {
+        let key = self.derive_key(passphrase)?;
+        let actual = if self.version == VERSION_FIXED_SALT_FINGERPRINT {
+            self.fixed_salt_fingerprint(passphrase)?
+        } else {
+            Self::key_fingerprint(&key)
+        };
+        if actual != self.fingerprint {
+            return Err(ArchiveCryptError::WrongKey {
+                expected: self.fingerprint(),
+                actual: to_hex(&actual),
+            }
+            .into());
+        }
+        Ok(key)
+    }
+
+    fn nonce(&self, counter: u32, last: bool) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_LEN..11].copy_from_slice(&counter.to_be_bytes());
+        nonce[11] = last as u8;
+        nonce
+    }
+}
+
+fn u32_at(bytes: &[u8], i: usize) -> u32 {
+    let mut b = [0u8; 4];
+    b.copy_from_slice(&bytes[i..i + 4]);
+    u32::from_be_bytes(b)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Read until the buf is full or EOF.
+fn read_full(r: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Full chunks are never the last one, the last chunk is the short (maybe empty) one written at finish.
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    header: ArchiveHeader,
+    header_bytes: Vec<u8>,
+    cipher: ChaCha20Poly1305,
+    counter: u32,
+    buf: Vec<u8>,
+    finished: bool,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(mut inner: W, encryption: &ArchiveEncryption) -> Result<Self, failure::Error> {
+        let mut header = ArchiveHeader {
+            version: VERSION,
+            scrypt_log_n: encryption.scrypt_log_n,
+            scrypt_r: 8,
+            scrypt_p: 1,
+            salt: [0u8; SALT_LEN],
+            nonce_prefix: [0u8; NONCE_PREFIX_LEN],
+            fingerprint: [0u8; FINGERPRINT_LEN],
+        };
+        let mut rng = rand::thread_rng();
+        rng.fill_bytes(&mut header.salt);
+        rng.fill_bytes(&mut header.nonce_prefix);
+        let key = header.derive_key(&encryption.passphrase)?;
+        header.fingerprint = ArchiveHeader::key_fingerprint(&key);
+        let cipher = ChaCha20Poly1305::new(&Key::from(key));
+        let header_bytes = header.to_bytes();
+        inner.write_all(&header_bytes)?;
+        info!("encrypt archive with key {}", header.fingerprint());
+        Ok(Self {
+            inner,
+            header,
+            header_bytes,
+            cipher,
+            counter: 0,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            finished: false,
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn header(&self) -> &ArchiveHeader {
+        &self.header
+    }
+
+    fn seal_chunk(&mut self, len: usize, last: bool) -> io::Result<()> {
+        let nonce = self.header.nonce(self.counter, last);
+        let sealed = self
+            .cipher
+            .encrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: &self.buf[..len],
+                    aad: &self.header_bytes,
+                },
+            )
+            .map_err(|_| io::Error::other("seal archive chunk failed."))?;
+        self.inner.write_all(&sealed)?;
+        self.buf.drain(..len);
+        self.counter = match self.counter.checked_add(1) {
+            Some(c) => c,
+            None => return Err(io::Error::other("too many chunks to encrypt.")),
+        };
+        Ok(())
+    }
+
+    /// Seal the last chunk, it's called on drop if not called.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if !self.finished {
+            self.finished = true;
+            self.seal_chunk(self.buf.len(), true)?;
+            self.inner.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::other("write after the encryption finished."));
+        }
+        self.buf.extend_from_slice(data);
+        while self.buf.len() >= CHUNK_SIZE {
+            self.seal_chunk(CHUNK_SIZE, false)?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: FinishWrite> FinishWrite for EncryptWriter<W> {
+    fn finish_write(&mut self) -> io::Result<()> {
+        self.finish()?;
+        self.inner.finish_write()
+    }
+}
+
+/// Only a safety net, finish_write reports the failure.
+impl<W: Write> Drop for EncryptWriter<W> {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            error!("finish archive encryption failed: {:?}", err);
+        }
+    }
+}
+
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    header: ArchiveHeader,
+    header_bytes: Vec<u8>,
+    cipher: ChaCha20Poly1305,
+    counter: u32,
+    plain: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// The passphrase is checked against the fingerprint before any chunk is opened.
+    pub fn new(mut inner: R, passphrase: &Secret) -> Result<Self, failure::Error> {
+        let header = ArchiveHeader::read_from(&mut inner)?;
+        let cipher = ChaCha20Poly1305::new(&Key::from(header.check_passphrase(passphrase)?));
+        Ok(Self {
+            inner,
+            header_bytes: header.to_bytes(),
+            header,
+            cipher,
+            counter: 0,
+            plain: Vec::new(),
+            pos: 0,
+            done: false,
+        })
+    }
+
+    pub fn header(&self) -> &ArchiveHeader {
+        &self.header
+    }
+
+    fn corrupted(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            ArchiveCryptError::Corrupted(self.counter).to_string(),
+        )
+    }
+
+    fn open_next_chunk(&mut self) -> io::Result<()> {
+        let mut sealed = vec![0u8; CHUNK_SIZE + TAG_LEN];
+        let n = read_full(&mut self.inner, &mut sealed)?;
+        let last = n < sealed.len();
+        let nonce = self.header.nonce(self.counter, last);
+        self.plain = self
+            .cipher
+            .decrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: &sealed[..n],
+                    aad: &self.header_bytes,
+                },
+            )
+            .map_err(|_| self.corrupted())?;
+        self.pos = 0;
+        if last {
+            self.done = true;
+        } else {
+            self.counter = self
+                .counter
+                .checked_add(1)
+                .ok_or_else(|| self.corrupted())?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.open_next_chunk()?;
+        }
+        let n = out.len().min(self.plain.len() - self.pos);
+        out[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Decrypt to the out file, which is removed if anything goes wrong.
+pub fn decrypt_archive(
+    archive: &Path,
+    out: &Path,
+    passphrase: &Secret,
+) -> Result<ArchiveHeader, failure::Error> {
+    let mut reader = DecryptReader::new(fs::File::open(archive)?, passphrase)?;
+    let header = reader.header().clone();
+    let result = fs::File::create(out).and_then(|mut w| io::copy(&mut reader, &mut w));
+    if let Err(err) = result {
+        if out.exists() {
+            fs::remove_file(out)?;
+        }
+        bail!("decrypt archive {:?} failed: {}", archive, err);
+    }
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::develope::tutil;
+
+    fn encryption(passphrase: &str) -> ArchiveEncryption {
+        ArchiveEncryption {
+            passphrase: Secret::new(passphrase),
+            scrypt_log_n: 4,
+        }
+    }
+
+    #[test]
+    fn t_encrypt_decrypt_archive() -> Result<(), failure::Error> {
+        let tu = tutil::TestDir::new();
+        let plain: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+
+        let encrypted = tu.tmp_dir_path().join("a.tar.enc");
+        let fingerprint = {
+            let mut w = EncryptWriter::new(fs::File::create(&encrypted)?, &encryption("right"))?;
+            w.write_all(&plain)?;
+            w.header().fingerprint()
+        };
+
+        let out = tu.tmp_dir_path().join("a.tar");
+        let header = decrypt_archive(&encrypted, &out, &Secret::new("right"))?;
+        assert_eq!(header.fingerprint(), fingerprint);
+        assert_eq!(fs::read(&out)?, plain);
+
+        let wrong = DecryptReader::new(fs::File::open(&encrypted)?, &Secret::new("wrong"));
+        match wrong
+            .err()
+            .and_then(|e| e.downcast::<ArchiveCryptError>().ok())
+        {
+            Some(ArchiveCryptError::WrongKey { expected, .. }) => assert_eq!(expected, fingerprint),
+            other => panic!("expect WrongKey, got {:?}", other),
+        }
+
+        // drop the last chunk.
+        let bytes = fs::read(&encrypted)?;
+        let truncated = tu.tmp_dir_path().join("b.tar.enc");
+        fs::write(
+            &truncated,
+            &bytes[..HEADER_LEN + (CHUNK_SIZE + TAG_LEN) * 2],
+        )?;
+        let out_b = tu.tmp_dir_path().join("b.tar");
+        assert!(decrypt_archive(&truncated, &out_b, &Secret::new("right")).is_err());
+        assert!(!out_b.exists());
+
+        let other = tu.tmp_dir_path().join("other.tar.enc");
+        let mut w = EncryptWriter::new(fs::File::create(&other)?, &encryption("right"))?;
+        w.finish_write()?;
+        assert_ne!(
+            w.header().fingerprint(),
+            fingerprint,
+            "salted by the archive, the same passphrase gives another fingerprint."
+        );
+
+        // an empty archive still has a sealed last chunk.
+        let empty = tu.tmp_dir_path().join("c.tar.enc");
+        EncryptWriter::new(fs::File::create(&empty)?, &encryption("right"))?.finish()?;
+        let out_c = tu.tmp_dir_path().join("c.tar");
+        decrypt_archive(&empty, &out_c, &Secret::new("right"))?;
+        assert!(fs::read(&out_c)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn t_decrypt_version_1() -> Result<(), failure::Error> {
+        let tu = tutil::TestDir::new();
+        let passphrase = Secret::new("right");
+        // what the version 1 wrote, the fingerprint is scrypt of the passphrase with a fixed salt.
+        let mut header = ArchiveHeader {
+            version: VERSION_FIXED_SALT_FINGERPRINT,
+            scrypt_log_n: 4,
+            scrypt_r: 8,
+            scrypt_p: 1,
+            salt: [1u8; SALT_LEN],
+            nonce_prefix: [2u8; NONCE_PREFIX_LEN],
+            fingerprint: [0u8; FINGERPRINT_LEN],
+        };
+        header.fingerprint = header.fixed_salt_fingerprint(&passphrase)?;
+        let cipher = ChaCha20Poly1305::new(&Key::from(header.derive_key(&passphrase)?));
+        let mut bytes = header.to_bytes();
+        let sealed = cipher
+            .encrypt(
+                &Nonce::from(header.nonce(0, true)),
+                Payload {
+                    msg: b"abc",
+                    aad: &bytes,
+                },
+            )
+            .expect("seal should succeed.");
+        bytes.extend_from_slice(&sealed);
+        let encrypted = tu.tmp_dir_path().join("v1.tar.enc");
+        fs::write(&encrypted, &bytes)?;
+
+        let out = tu.tmp_dir_path().join("v1.tar");
+        assert_eq!(decrypt_archive(&encrypted, &out, &passphrase)?.version, 1);
+        assert_eq!(fs::read(&out)?, b"abc");
+        assert!(DecryptReader::new(fs::File::open(&encrypted)?, &Secret::new("wrong")).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+//! Manifests of the archives written in incremental mode, one next to each archive.
+//! An incremental archive holds only the files changed since the previous archive, it's parent,
+//! so restoring it needs every archive back to the full one.
+use crate::actions::hash_file_sha1;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use walkdir::WalkDir;
+
+pub const MANIFEST_POSTFIX: &str = ".manifest.json";
+
+fn default_full_every() -> u32 {
+    7
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct IncrementalArchive {
+    /// a full archive every N runs, the runs between are incremental.
+    #[serde(default = "default_full_every")]
+    pub full_every: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveKind {
+    Full,
+    Incremental,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ManifestEntry {
+    /// the path in the tar, starts with the name of the from_dir.
+    pub path: String,
+    pub len: u64,
+    /// seconds since epoch.
+    pub mtime: u64,
+    pub sha1: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ArchiveManifest {
+    /// the file name of the archive.
+    pub archive: String,
+    pub kind: ArchiveKind,
+    pub parent: Option<String>,
+    /// 0 for a full archive, otherwise how many runs since the full one.
+    pub chain_len: u32,
+    /// every file at archive time, including those unchanged.
+    pub files: Vec<ManifestEntry>,
+    /// removed since the parent.
+    pub deleted: Vec<String>,
+}
+
+/// A file found by scan_directory, changed means it goes into the archive.
+pub struct ScannedFile {
+    pub path: PathBuf,
+    pub entry: ManifestEntry,
+    pub changed: bool,
+}
+
+pub fn manifest_path(archive: &Path) -> PathBuf {
+    let mut name = archive.as_os_str().to_os_string();
+    name.push(MANIFEST_POSTFIX);
+    PathBuf::from(name)
+}
+
+impl ArchiveManifest {
+    /// None if the archive has no manifest, e.g. it isn't written in incremental mode.
+    pub fn load(archive: &Path) -> Result<Option<Self>, failure::Error> {
+        let path = manifest_path(archive);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let r = BufReader::new(fs::File::open(&path)?);
+        match serde_json::from_reader(r) {
+            Ok(manifest) => Ok(Some(manifest)),
+            Err(err) => bail!("parse manifest {:?} failed: {}", path, err),
+        }
+    }
+
+    pub fn save(&self, archive: &Path) -> Result<(), failure::Error> {
+        let w = BufWriter::new(fs::File::create(manifest_path(archive))?);
+        serde_json::to_writer(w, self)?;
+        Ok(())
+    }
+
+    pub fn entries_by_path(&self) -> HashMap<&str, &ManifestEntry> {
+        self.files.iter().map(|e| (e.path.as_str(), e)).collect()
+    }
+}
+
+fn mtime_secs(meta: &fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|st| st.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Walk the directory like tar's append_dir_all does, paths are prefixed with the name of it.
+/// A file is unchanged when len and mtime are equal to the previous, or the sha1 is.
+pub fn scan_directory(
+    dir: &Path,
+    previous: &HashMap<&str, &ManifestEntry>,
+    skip_sha1: bool,
+) -> Result<Vec<ScannedFile>, failure::Error> {
+    let dir_name = match dir.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => bail!("can't get the file name of directory: {:?}", dir),
+    };
+    let mut scanned = Vec::new();
+    for entry in WalkDir::new(dir).follow_links(false) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(dir)?;
+        let mut path = dir_name.clone();
+        for component in relative.components() {
+            path.push('/');
+            path.push_str(&component.as_os_str().to_string_lossy());
+        }
+        let meta = entry.metadata()?;
+        let mut current = ManifestEntry {
+            path,
+            len: meta.len(),
+            mtime: mtime_secs(&meta),
+            sha1: None,
+        };
+        let changed = match previous.get(current.path.as_str()) {
+            Some(prev) if prev.len == current.len && prev.mtime == current.mtime => {
+                current.sha1 = prev.sha1.clone();
+                false
+            }
+            Some(prev) => {
+                if !skip_sha1 {
+                    current.sha1 = hash_file_sha1(entry.path());
+                }
+                // touched only.
+                !(prev.len == current.len && current.sha1.is_some() && prev.sha1 == current.sha1)
+            }
+            None => {
+                if !skip_sha1 {
+                    current.sha1 = hash_file_sha1(entry.path());
+                }
+                true
+            }
+        };
+        scanned.push(ScannedFile {
+            path: entry.path().to_path_buf(),
+            entry: current,
+            changed,
+        });
+    }
+    Ok(scanned)
+}
+
+/// The archive and those it depends on back to the full one, newest first.
+/// Err if any of them or their manifests is missing.
+pub fn dependency_chain(dir: &Path, archive_name: &str) -> Result<Vec<String>, failure::Error> {
+    let mut chain: Vec<String> = Vec::new();
+    let mut name = archive_name.to_string();
+    loop {
+        let archive = dir.join(&name);
+        if !archive.exists() {
+            bail!("archive {:?} is missing.", archive);
+        }
+        let manifest = match ArchiveManifest::load(&archive)? {
+            Some(manifest) => manifest,
+            None => bail!("the manifest of {:?} is missing.", archive),
+        };
+        chain.push(name);
+        match manifest.parent {
+            Some(parent) if chain.contains(&parent) => {
+                bail!("archive {} depends on itself.", parent)
+            }
+            Some(parent) => name = parent,
+            None => return Ok(chain),
+        }
+    }
+}
+
+/// Like dependency_chain, with the parents already known, e.g. read from a remote storage.
+/// The archives missing or without a manifest aren't in the parents.
+pub fn chain_from_parents(
+    archive_name: &str,
+    parents: &HashMap<String, Option<String>>,
+) -> Result<Vec<String>, failure::Error> {
+    let mut chain: Vec<String> = Vec::new();
+    let mut name = archive_name;
+    loop {
+        let parent = match parents.get(name) {
+            Some(parent) => parent,
+            None => bail!("archive {} or it's manifest is missing.", name),
+        };
+        chain.push(name.to_string());
+        match parent {
+            Some(parent) if chain.contains(parent) => {
+                bail!("archive {} depends on itself.", parent)
+            }
+            Some(parent) => name = parent,
+            None => return Ok(chain),
+        }
+    }
+}
+
+/// The manifest of the latest archive to build the next one on,
+/// None if the next one should be full.
+pub fn previous_for_incremental(
+    latest_archive: Option<&Path>,
+    full_every: u32,
+) -> Result<Option<ArchiveManifest>, failure::Error> {
+    let latest_archive = match latest_archive {
+        Some(archive) => archive,
+        None => return Ok(None),
+    };
+    let manifest = match ArchiveManifest::load(latest_archive)? {
+        Some(manifest) => manifest,
+        None => return Ok(None),
+    };
+    if manifest.chain_len + 1 >= full_every {
+        return Ok(None);
+    }
+    let dir = latest_archive.parent().unwrap_or_else(|| Path::new(""));
+    if let Err(err) = dependency_chain(dir, &manifest.archive) {
+        warn!("the archive chain is broken, start a full archive. {}", err);
+        return Ok(None);
+    }
+    Ok(Some(manifest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::develope::tutil;
+
+    #[test]
+    fn t_scan_and_chain() -> Result<(), failure::Error> {
+        let tu = tutil::TestDir::new();
+        let data = tu.create_sub_dir("data");
+        tutil::make_a_file_with_content(&data, "a.txt", "a")?;
+        tutil::make_a_file_with_content(&data, "b.txt", "b")?;
+
+        let scanned = scan_directory(&data, &HashMap::new(), false)?;
+        assert_eq!(scanned.len(), 2);
+        assert!(scanned.iter().all(|s| s.changed));
+        assert!(scanned.iter().any(|s| s.entry.path == "data/a.txt"));
+
+        let archives = tu.create_sub_dir("archives");
+        let full = ArchiveManifest {
+            archive: "x_20200101000000.tar".to_string(),
+            kind: ArchiveKind::Full,
+            parent: None,
+            chain_len: 0,
+            files: scanned.into_iter().map(|s| s.entry).collect(),
+            deleted: Vec::new(),
+        };
+        let full_path = archives.join(&full.archive);
+        fs::write(&full_path, "")?;
+        full.save(&full_path)?;
+
+        tutil::make_a_file_with_content(&data, "b.txt", "bb")?;
+        let scanned = scan_directory(&data, &full.entries_by_path(), false)?;
+        let changed: Vec<&str> = scanned
+            .iter()
+            .filter(|s| s.changed)
+            .map(|s| s.entry.path.as_str())
+            .collect();
+        assert_eq!(changed, vec!["data/b.txt"]);
+
+        let inc = ArchiveManifest {
+            archive: "x_20200102000000.tar".to_string(),
+            kind: ArchiveKind::Incremental,
+            parent: Some(full.archive.clone()),
+            chain_len: 1,
+            files: Vec::new(),
+            deleted: vec!["data/c.txt".to_string()],
+        };
+        let inc_path = archives.join(&inc.archive);
+        fs::write(&inc_path, "")?;
+        inc.save(&inc_path)?;
+
+        assert_eq!(
+            dependency_chain(&archives, &inc.archive)?,
+            vec![inc.archive.clone(), full.archive.clone()]
+        );
+        assert!(previous_for_incremental(Some(&inc_path), 7)?.is_some());
+        assert!(previous_for_incremental(Some(&inc_path), 2)?.is_none());
+
+        fs::remove_file(&full_path)?;
+        assert!(dependency_chain(&archives, &inc.archive).is_err());
+        assert!(previous_for_incremental(Some(&inc_path), 7)?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+//! Read an archive to the end through decryption and decompression,
+//! the entries are checked against the manifest if there is one.
+use super::archive_crypt::{ArchiveEncryption, DecryptReader, ENCRYPTED_POSTFIX};
+use super::archive_manifest::{ArchiveKind, ArchiveManifest, ManifestEntry};
+use super::server::CompressionImpl;
+use log::*;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+#[derive(Debug, Default)]
+pub struct ArchiveVerification {
+    pub entries: usize,
+    pub bytes: u64,
+    pub with_manifest: bool,
+    /// empty if the archive is intact.
+    pub problems: Vec<String>,
+}
+
+impl ArchiveVerification {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Err only when the archive can't be opened, the corruptions are in the problems.
+/// The codec and the encryption are the configured ones, the name of the archive doesn't tell.
+pub fn verify_archive(
+    archive: &Path,
+    codec: Option<&CompressionImpl>,
+    encryption: Option<&ArchiveEncryption>,
+) -> Result<ArchiveVerification, failure::Error> {
+    let name = match archive.file_name().and_then(|n| n.to_str()) {
+        Some(name) => name,
+        None => bail!("invalid archive file name: {:?}", archive),
+    };
+    let file = io::BufReader::new(fs::File::open(archive)?);
+    let r: Box<dyn Read> = match encryption {
+        Some(encryption) => Box::new(DecryptReader::new(file, &encryption.passphrase)?),
+        None if name.strip_suffix(ENCRYPTED_POSTFIX).is_some() => {
+            bail!("{} is encrypted but no encryption is configured.", name)
+        }
+        None => Box::new(file),
+    };
+    let r = match codec {
+        Some(codec) => codec.decoder(r)?,
+        None => r,
+    };
+
+    let manifest = ArchiveManifest::load(archive)?;
+    let mut verification = ArchiveVerification {
+        with_manifest: manifest.is_some(),
+        ..ArchiveVerification::default()
+    };
+    let expected = manifest.as_ref().map(ArchiveManifest::entries_by_path);
+    let mut seen = HashSet::new();
+
+    let mut tar = tar::Archive::new(r);
+    if let Err(err) = check_entries(&mut tar, &expected, &mut seen, &mut verification) {
+        verification.problems.push(format!("read failed: {}", err));
+        return Ok(verification);
+    }
+    // the trailer of the compression and the encryption are checked at the end of the stream.
+    if let Err(err) = io::copy(&mut tar.into_inner(), &mut io::sink()) {
+        verification
+            .problems
+            .push(format!("read the end of the archive failed: {}", err));
+    }
+
+    if let Some(manifest) = manifest.as_ref() {
+        if manifest.kind == ArchiveKind::Full {
+            for e in manifest.files.iter().filter(|e| !seen.contains(&e.path)) {
+                verification.problems.push(format!(
+                    "{} is in the manifest but not in the archive.",
+                    e.path
+                ));
+            }
+        }
+    }
+    trace!("verified {:?}: {:?}", archive, verification);
+    Ok(verification)
+}
+
+fn check_entries<R: Read>(
+    tar: &mut tar::Archive<R>,
+    expected: &Option<HashMap<&str, &ManifestEntry>>,
+    seen: &mut HashSet<String>,
+    verification: &mut ArchiveVerification,
+) -> Result<(), failure::Error> {
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().to_string();
+        let mut hasher = Sha1::new();
+        let len = io::copy(&mut entry, &mut hasher)?;
+        verification.entries += 1;
+        verification.bytes += len;
+        if let Some(expected) = expected.as_ref() {
+            match expected.get(path.as_str()) {
+                None => verification
+                    .problems
+                    .push(format!("{} isn't in the manifest.", path)),
+                Some(e) if e.len != len => verification.problems.push(format!(
+                    "{} length mismatch, manifest: {}, archive: {}.",
+                    path, e.len, len
+                )),
+                Some(e) => {
+                    let sha1 = format!("{:x}", hasher.result());
+                    if e.sha1.as_ref().is_some_and(|s| *s != sha1) {
+                        verification
+                            .problems
+                            .push(format!("{} checksum mismatch.", path));
+                    }
+                }
+            }
+        }
+        seen.insert(path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_shape::archive_crypt::EncryptWriter;
+    use crate::data_shape::archive_manifest::scan_directory;
+    use crate::data_shape::{FinishWrite, Secret};
+    use crate::develope::tutil;
+
+    #[test]
+    fn t_verify_archive() -> Result<(), failure::Error> {
+        let tu = tutil::TestDir::new();
+        let data = tu.create_sub_dir("data");
+        tutil::make_a_file_with_content(&data, "a.txt", "abc")?;
+        let files: Vec<ManifestEntry> = scan_directory(&data, &HashMap::new(), false)?
+            .into_iter()
+            .map(|s| s.entry)
+            .collect();
+        let encryption = ArchiveEncryption {
+            passphrase: Secret::new("pass"),
+            scrypt_log_n: 4,
+        };
+
+        let archive = tu.tmp_dir_path().join("x_20200101000000.tar.gz.enc");
+        {
+            let file = fs::File::create(&archive)?;
+            let w = EncryptWriter::new(file, &encryption)?;
+            let w = CompressionImpl::Gzip.encoder(w, None, None)?;
+            let mut builder = tar::Builder::new(w);
+            builder.append_dir_all("data", &data)?;
+            builder.into_inner()?.finish_write()?;
+        }
+        let mut manifest = ArchiveManifest {
+            archive: "x_20200101000000.tar.gz.enc".to_string(),
+            kind: ArchiveKind::Full,
+            parent: None,
+            chain_len: 0,
+            files,
+            deleted: Vec::new(),
+        };
+        manifest.save(&archive)?;
+
+        let verification =
+            verify_archive(&archive, Some(&CompressionImpl::Gzip), Some(&encryption))?;
+        assert!(verification.is_ok(), "{:?}", verification.problems);
+        assert_eq!(verification.entries, 1);
+        assert!(verify_archive(&archive, Some(&CompressionImpl::Gzip), None).is_err());
+
+        manifest.files[0].sha1 = Some("0".repeat(40));
+        manifest.files.push(ManifestEntry {
+            path: "data/b.txt".to_string(),
+            len: 1,
+            mtime: 0,
+            sha1: None,
+        });
+        manifest.save(&archive)?;
+        assert_eq!(
+            verify_archive(&archive, Some(&CompressionImpl::Gzip), Some(&encryption))?
+                .problems
+                .len(),
+            2
+        );
+
+        // cut the tail.
+        let bytes = fs::read(&archive)?;
+        fs::write(&archive, &bytes[..bytes.len() - 10])?;
+
+        // not compressed, named by the archive_postfix like .7z.
+        let plain = tu.tmp_dir_path().join("x_20200102000000.7z");
+        let mut builder = tar::Builder::new(fs::File::create(&plain)?);
+        builder.append_dir_all("data", &data)?;
+        builder.into_inner()?.finish_write()?;
+        let verification = verify_archive(&plain, None, None)?;
+        assert!(verification.is_ok(), "{:?}", verification.problems);
+        assert_eq!(verification.entries, 1);
+        assert!(
+            !verify_archive(&archive, Some(&CompressionImpl::Gzip), Some(&encryption))?.is_ok()
+        );
+        Ok(())
+    }
+}
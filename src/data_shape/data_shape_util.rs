@@ -1,5 +1,7 @@
 use crate::actions::hash_file_sha1;
+use crate::db_accesses::{DbAccess, DigestKey, SqliteDbAccess};
 use log::*;
+use std::fs::Metadata;
 use std::path::Path;
 use std::time::SystemTime;
 use super::{FullPathFileItemError};
@@ -11,7 +13,49 @@ pub struct FileMeta {
     pub created: Option<u64>,
 }
 
-pub fn get_file_meta(file_path: impl AsRef<Path>, skip_sha1: bool) -> Result<FileMeta, failure::Error> {
+/// The sha1 from the digests if the file isn't changed since it's cached,
+/// hashed and cached otherwise.
+pub fn get_sha1(
+    file_path: &Path,
+    meta: &Metadata,
+    digests: Option<&SqliteDbAccess>,
+) -> Option<String> {
+    let digests = match digests {
+        Some(digests) => digests,
+        None => return hash_file_sha1(file_path),
+    };
+    let key = DigestKey::new(file_path, meta);
+    if let Some(sha1) = digests.find_digest(&key) {
+        return Some(sha1);
+    }
+    let sha1 = hash_file_sha1(file_path)?;
+    if let Err(err) = digests.save_digest(&key, &sha1) {
+        warn!("cache the digest of {:?} failed: {:?}", file_path, err);
+    }
+    Some(sha1)
+}
+
+/// After the file is written in place the key may stay the same, like the inode and the restored
+/// mtime, so cache the sha1 of what's written, or forget the path if it's unknown.
+pub fn refresh_digest(file_path: &Path, sha1: Option<&str>, digests: Option<&SqliteDbAccess>) {
+    let digests = match digests {
+        Some(digests) => digests,
+        None => return,
+    };
+    let r = match (sha1, file_path.metadata()) {
+        (Some(sha1), Ok(meta)) => digests.save_digest(&DigestKey::new(file_path, &meta), sha1),
+        _ => digests.delete_digest(file_path.to_string_lossy().as_ref()),
+    };
+    if let Err(err) = r {
+        warn!("refresh the digest of {:?} failed: {:?}", file_path, err);
+    }
+}
+
+pub fn get_file_meta(
+    file_path: impl AsRef<Path>,
+    skip_sha1: bool,
+    digests: Option<&SqliteDbAccess>,
+) -> Result<FileMeta, failure::Error> {
     let lp = file_path.as_ref();
     if !lp.exists() {
         bail!(FullPathFileItemError::NotExist(lp.to_path_buf()));
@@ -30,7 +74,7 @@ pub fn get_file_meta(file_path: impl AsRef<Path>, skip_sha1: bool) -> Result<Fil
                 .and_then(|st| st.duration_since(SystemTime::UNIX_EPOCH).ok())
                 .map(|d| d.as_secs());
             let sha1 = if !skip_sha1 {
-                hash_file_sha1(&lp)
+                get_sha1(lp, &mt, digests)
             } else {
                 None
             };
@@ -47,3 +91,34 @@ pub fn get_file_meta(file_path: impl AsRef<Path>, skip_sha1: bool) -> Result<Fil
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::develope::tutil;
+    use std::fs;
+
+    #[test]
+    fn t_cached_sha1() -> Result<(), failure::Error> {
+        let tu = tutil::TestDir::new();
+        let digests = SqliteDbAccess::open(tu.tmp_dir_path().join("digests.db"))?;
+        let file = tu.make_a_file_with_content("a.txt", "abc")?;
+        let sha1 = get_file_meta(&file, false, Some(&digests))?.sha1;
+        assert_eq!(sha1, hash_file_sha1(&file));
+
+        // a cache hit doesn't read the file.
+        let key = DigestKey::new(&file, &file.metadata()?);
+        digests.save_digest(&key, "cached")?;
+        assert_eq!(
+            get_file_meta(&file, false, Some(&digests))?.sha1.as_deref(),
+            Some("cached")
+        );
+        assert_eq!(get_file_meta(&file, false, None)?.sha1, sha1);
+
+        fs::write(&file, "abcd")?;
+        let sha1 = get_file_meta(&file, false, Some(&digests))?.sha1;
+        assert_eq!(sha1, hash_file_sha1(&file), "len changed, hashed again.");
+        assert_eq!(digests.find_digest(&DigestKey::new(&file, &file.metadata()?)), sha1);
+        Ok(())
+    }
+}
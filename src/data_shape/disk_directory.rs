@@ -2,7 +2,7 @@ use super::{
     string_path::{self, SlashPath},
     AppRole, FullPathFileItem,
 };
-use crate::db_accesses::{DbAccess, RelativeFileItemInDb};
+use crate::db_accesses::{DbAccess, RelativeFileItemInDb, SqliteDbAccess};
 use glob::Pattern;
 use itertools::Itertools;
 use log::*;
@@ -69,6 +69,12 @@ pub struct Directory {
     pub excludes: Vec<String>,
     // #[serde(skip_serializing_if = "Option::is_none")]
     pub file_selector: Option<FileSelector>,
+    /// run on the source host before the files are iterated, a failure skips the directory.
+    #[serde(default)]
+    pub pre_sync: Vec<String>,
+    /// run after the iteration, even if pre_sync failed.
+    #[serde(default)]
+    pub post_sync: Vec<String>,
     #[serde(skip)]
     pub includes_patterns: Option<Vec<Pattern>>,
     #[serde(skip)]
@@ -130,8 +136,24 @@ impl Directory {
         Ok(())
     }
 
-    pub fn count_total_size(&self) -> u64 {
-        WalkDir::new(self.from_dir.as_path())
+    /// The source of archiving, a pull hub archives the mirrored copy which is under my_directories,
+    /// the from_dir is the remote's path.
+    pub fn get_archive_source(
+        &self,
+        app_role: Option<&AppRole>,
+        my_directories: &SlashPath,
+    ) -> PathBuf {
+        match app_role {
+            Some(AppRole::PullHub) => my_directories
+                .join_another(&self.get_to_dir_base(""))
+                .as_path()
+                .to_path_buf(),
+            _ => self.from_dir.as_path().to_path_buf(),
+        }
+    }
+
+    pub fn count_total_size(dir: impl AsRef<Path>) -> u64 {
+        WalkDir::new(dir.as_ref())
             .follow_links(false)
             .into_iter()
             .filter_map(|e| e.ok())
@@ -292,6 +314,7 @@ impl Directory {
         skip_sha1: bool,
         file_selector: &'a FileSelector,
         possible_encoding: &'a Vec<&'static Encoding>,
+        digests: Option<&'a SqliteDbAccess>,
     ) -> impl Iterator<Item = Result<FullPathFileItem, failure::Error>> + 'a {
         match file_selector {
             FileSelector::Latest(num) => {
@@ -320,6 +343,7 @@ impl Directory {
                             &to_dir_base,
                             skip_sha1,
                             possible_encoding,
+                            digests,
                         )
                     })
                     .take(*num)
@@ -342,6 +366,7 @@ impl Directory {
         dir_to_read: SlashPath,
         skip_sha1: bool,
         possible_encoding: &'a Vec<&'static Encoding>,
+        digests: Option<&'a SqliteDbAccess>,
     ) -> impl Iterator<Item = Result<FullPathFileItem, failure::Error>> + 'a {
         let includes_patterns = self.includes_patterns.clone();
         let excludes_patterns = self.excludes_patterns.clone();
@@ -385,6 +410,7 @@ impl Directory {
                     &to_dir_base,
                     skip_sha1,
                     possible_encoding,
+                    digests,
                 )
             })
     }
@@ -407,11 +433,13 @@ impl Directory {
     /// When be pulled the server_distinct_id is unnecessary.
     /// to_dir is made here so it's becaming stateless, the other side just receive item and
     /// join the my_directories dir with to_dir no matter which definition of dir it is.
+    /// The sha1 is looked up in the digests before hashing if any.
     pub fn file_item_iter<'a>(
         &'a self,
         server_distinct_id: impl AsRef<str>,
         skip_sha1: bool,
         possible_encoding: &'a Vec<&'static Encoding>,
+        digests: Option<&'a SqliteDbAccess>,
     ) -> Box<dyn Iterator<Item = Result<FullPathFileItem, failure::Error>> + 'a> {
         let dir_to_read = self.from_dir.clone();
 
@@ -425,6 +453,7 @@ impl Directory {
                 skip_sha1,
                 file_selector,
                 possible_encoding,
+                digests,
             ))
         } else {
             Box::new(self.file_item_iter_no_file_selector(
@@ -432,6 +461,7 @@ impl Directory {
                 dir_to_read,
                 skip_sha1,
                 possible_encoding,
+                digests,
             ))
         }
     }
@@ -596,7 +626,7 @@ excludes:
         let mut d = serde_yaml::from_str::<Directory>(&yml)?;
         d.compile_patterns()?;
         let files = d
-            .file_item_iter("abc", false,&vec![])
+            .file_item_iter("abc", false,&vec![], None)
             .filter_map(|k|k.ok())
             .collect::<Vec<FullPathFileItem>>();
         assert_eq!(files.len(), 3);
@@ -627,7 +657,7 @@ excludes:
         let mut d = serde_yaml::from_str::<Directory>(&yml)?;
         d.compile_patterns()?;
         let files = d
-            .file_item_iter("abc", false, &vec![])
+            .file_item_iter("abc", false, &vec![], None)
             .filter_map(|k|k.ok())
             .collect::<Vec<FullPathFileItem>>();
         assert_eq!(files.len(), 2);
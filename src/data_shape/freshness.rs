@@ -0,0 +1,217 @@
+//! Whether a server has been backed up recently, judged by the last successful run in the reports.
+use super::sync_report::{self, SyncReport};
+use chrono::{DateTime, Duration, Local};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Like 90m, 36h, 2d or 1w.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct MaxBackupAge {
+    minutes: i64,
+}
+
+impl MaxBackupAge {
+    pub fn duration(self) -> Duration {
+        Duration::minutes(self.minutes)
+    }
+}
+
+impl FromStr for MaxBackupAge {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid max_backup_age: {:?}, expect like 90m, 36h, 2d or 1w.",
+                s
+            )
+        };
+        let s = s.trim();
+        // the unit may be any char, don't split in the middle of it.
+        let (number, unit) = match s.char_indices().last() {
+            Some((i, _)) if i > 0 => s.split_at(i),
+            _ => return Err(invalid()),
+        };
+        let number: i64 = number.trim().parse().map_err(|_| invalid())?;
+        let minutes_of_unit = match unit {
+            "m" => 1,
+            "h" => 60,
+            "d" => 60 * 24,
+            "w" => 60 * 24 * 7,
+            _ => return Err(invalid()),
+        };
+        if number <= 0 {
+            return Err(invalid());
+        }
+        // chrono's Duration holds i64 milliseconds.
+        let minutes = number
+            .checked_mul(minutes_of_unit)
+            .filter(|m| *m <= i64::MAX / (60 * 1000))
+            .ok_or_else(invalid)?;
+        Ok(MaxBackupAge { minutes })
+    }
+}
+
+impl TryFrom<String> for MaxBackupAge {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for MaxBackupAge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let m = self.minutes;
+        if m % (60 * 24 * 7) == 0 {
+            write!(f, "{}w", m / (60 * 24 * 7))
+        } else if m % (60 * 24) == 0 {
+            write!(f, "{}d", m / (60 * 24))
+        } else if m % 60 == 0 {
+            write!(f, "{}h", m / 60)
+        } else {
+            write!(f, "{}m", m)
+        }
+    }
+}
+
+impl From<MaxBackupAge> for String {
+    fn from(age: MaxBackupAge) -> Self {
+        age.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FreshnessStatus {
+    Fresh,
+    Stale,
+    /// no successful run at all.
+    Never,
+    /// no max_backup_age configured.
+    Unchecked,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Freshness {
+    pub server: String,
+    pub status: FreshnessStatus,
+    pub last_success: Option<DateTime<Local>>,
+    pub max_backup_age: Option<MaxBackupAge>,
+}
+
+impl Freshness {
+    pub fn is_stale(&self) -> bool {
+        self.status == FreshnessStatus::Stale || self.status == FreshnessStatus::Never
+    }
+
+    pub fn message(&self) -> String {
+        let last = match self.last_success {
+            Some(last) => format!(
+                "last successful run at {}",
+                last.format("%Y-%m-%d %H:%M:%S")
+            ),
+            None => "no successful run".to_string(),
+        };
+        match self.max_backup_age {
+            Some(age) => format!(
+                "{} is {:?}, {}, max_backup_age {}.",
+                self.server, self.status, last, age
+            ),
+            None => format!("{} is {:?}, {}.", self.server, self.status, last),
+        }
+    }
+}
+
+/// The newest report of a succeeded run, the broken reports are skipped.
+pub fn last_successful_run(reports_dir: &Path) -> Result<Option<SyncReport>, failure::Error> {
+    for path in sync_report::list_reports(reports_dir)?.iter().rev() {
+        match SyncReport::load(path) {
+            Ok(report) if report.succeeded() => return Ok(Some(report)),
+            Ok(_) => (),
+            Err(err) => warn!("{}", err),
+        }
+    }
+    Ok(None)
+}
+
+pub fn check_freshness(
+    server: &str,
+    reports_dir: &Path,
+    max_backup_age: Option<MaxBackupAge>,
+    now: DateTime<Local>,
+) -> Result<Freshness, failure::Error> {
+    let last_success = last_successful_run(reports_dir)?
+        .map(|report| report.finished_at.unwrap_or(report.started_at));
+    let status = match (max_backup_age, last_success) {
+        (None, _) => FreshnessStatus::Unchecked,
+        (Some(_), None) => FreshnessStatus::Never,
+        (Some(age), Some(last)) if now - last > age.duration() => FreshnessStatus::Stale,
+        (Some(_), Some(_)) => FreshnessStatus::Fresh,
+    };
+    Ok(Freshness {
+        server: server.to_string(),
+        status,
+        last_success,
+        max_backup_age,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_shape::sync_report::SyncDirection;
+    use crate::develope::tutil;
+
+    #[test]
+    fn t_check_freshness() -> Result<(), failure::Error> {
+        assert_eq!(
+            "36h".parse::<MaxBackupAge>().unwrap().duration(),
+            Duration::hours(36)
+        );
+        assert_eq!("2d".parse::<MaxBackupAge>().unwrap().to_string(), "2d");
+        assert_eq!("90m".parse::<MaxBackupAge>().unwrap().to_string(), "90m");
+        assert!("2x".parse::<MaxBackupAge>().is_err());
+        assert!("0h".parse::<MaxBackupAge>().is_err());
+        assert!("2日".parse::<MaxBackupAge>().is_err());
+        assert!("日".parse::<MaxBackupAge>().is_err());
+        assert!(format!("{}w", i64::MAX / 2)
+            .parse::<MaxBackupAge>()
+            .is_err());
+
+        let tu = tutil::TestDir::new();
+        let reports_dir = tu.tmp_dir_path();
+        let age = Some("1d".parse::<MaxBackupAge>().unwrap());
+        let now = Local::now();
+        assert_eq!(
+            check_freshness("a", reports_dir, age, now)?.status,
+            FreshnessStatus::Never
+        );
+
+        let mut report = SyncReport::new("a", SyncDirection::Pull);
+        report.finish();
+        report.save(reports_dir)?;
+        let mut failed = SyncReport::new("a", SyncDirection::Pull);
+        failed.started_at = report.started_at + Duration::seconds(1);
+        failed.record_failed("copy failed.");
+        failed.finish();
+        failed.save(reports_dir)?;
+
+        let freshness = check_freshness("a", reports_dir, age, now + Duration::hours(1))?;
+        assert_eq!(freshness.status, FreshnessStatus::Fresh);
+        assert_eq!(freshness.last_success, report.finished_at);
+        let freshness = check_freshness("a", reports_dir, age, now + Duration::hours(25))?;
+        assert!(freshness.is_stale());
+        assert!(freshness.message().contains("max_backup_age 1d"));
+        assert_eq!(
+            check_freshness("a", reports_dir, None, now)?.status,
+            FreshnessStatus::Unchecked
+        );
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use super::string_path;
 use super::SlashPath;
 use crate::data_shape::data_shape_util;
+use crate::db_accesses::SqliteDbAccess;
 use crate::protocol::TransferType;
 use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
@@ -54,8 +55,10 @@ impl FullPathFileItem {
         to_dir_base: &SlashPath,
         skip_sha1: bool,
         possible_encoding: &Vec<&'static Encoding>,
+        digests: Option<&SqliteDbAccess>,
     ) -> Result<Self, failure::Error> {
-        let fmeta = data_shape_util::get_file_meta(absolute_file_to_read.as_path(), skip_sha1)?;
+        let fmeta =
+            data_shape_util::get_file_meta(absolute_file_to_read.as_path(), skip_sha1, digests)?;
         let relative_path = from_dir.strip_prefix(absolute_file_to_read.as_path(), possible_encoding)?;
         let from_path = SlashPath::from_path(absolute_file_to_read.as_path(), possible_encoding)?;
 
@@ -69,20 +72,34 @@ impl FullPathFileItem {
         })
     }
 
-    pub fn changed(&self, file_path: impl AsRef<Path>) -> FileChanged {
-        if let Ok(fmeta) = data_shape_util::get_file_meta(file_path, self.sha1.is_none()) {
-            if fmeta.len != self.len {
-                FileChanged::Len(fmeta.len, self.len)
-            } else if fmeta.modified != self.modified {
-                FileChanged::Modified(fmeta.modified, self.modified)
-            } else if fmeta.sha1 != self.sha1 {
-                FileChanged::Sha1(fmeta.sha1, self.sha1.as_ref().cloned())
-            } else {
-                FileChanged::NoChange
+    /// The sha1 is compared only if the item has one,
+    /// and it's computed only if the len and the modified are the same.
+    pub fn changed(
+        &self,
+        file_path: impl AsRef<Path>,
+        digests: Option<&SqliteDbAccess>,
+    ) -> FileChanged {
+        let file_path = file_path.as_ref();
+        let fmeta = match data_shape_util::get_file_meta(file_path, true, None) {
+            Ok(fmeta) => fmeta,
+            Err(_) => return FileChanged::NoMetadata,
+        };
+        if fmeta.len != self.len {
+            return FileChanged::Len(fmeta.len, self.len);
+        }
+        if fmeta.modified != self.modified {
+            return FileChanged::Modified(fmeta.modified, self.modified);
+        }
+        if self.sha1.is_some() {
+            let sha1 = file_path
+                .metadata()
+                .ok()
+                .and_then(|meta| data_shape_util::get_sha1(file_path, &meta, digests));
+            if sha1 != self.sha1 {
+                return FileChanged::Sha1(sha1, self.sha1.as_ref().cloned());
             }
-        } else {
-            FileChanged::NoMetadata
         }
+        FileChanged::NoChange
     }
 
     pub fn as_sent_bytes(&self) -> Vec<u8> {
@@ -102,6 +119,7 @@ impl FullPathFileItem {
 mod tests {
     use super::*;
     use crate::data_shape::Directory;
+    use crate::db_accesses::{DbAccess, DigestKey};
     use crate::develope::tutil;
     use crate::log_util;
 
@@ -155,7 +173,7 @@ excludes:
         assert!(dir.excludes_patterns.is_some());
 
         let files = dir
-            .file_item_iter("abc", false, &vec![])
+            .file_item_iter("abc", false, &vec![], None)
             .filter_map(|k| k.ok())
             .map(|it| {
                 println!("to_path: {:?}", it.to_path.slash);
@@ -192,7 +210,7 @@ excludes:
         dir.compile_patterns()?;
 
         let files = dir
-            .file_item_iter("abc", false, &vec![])
+            .file_item_iter("abc", false, &vec![], None)
             .filter_map(|k| k.ok())
             .map(|it| {
                 println!("to_path: {:?}", it.to_path.slash);
@@ -210,4 +228,48 @@ excludes:
         );
         Ok(())
     }
+
+    #[test]
+    fn t_changed_after_rewrite() -> Result<(), failure::Error> {
+        use crate::actions::hash_file_sha1;
+        use std::fs;
+
+        let tu = tutil::TestDir::new();
+        let digests = SqliteDbAccess::open(tu.tmp_dir_path().join("digests.db"))?;
+        let source = tu.make_a_file_with_content("source.txt", "abd")?;
+        let df = tu.make_a_file_with_content("mirror.txt", "abc")?;
+        let ft = filetime::FileTime::from_unix_time(1_500_000_000, 0);
+        filetime::set_file_mtime(&df, ft)?;
+        let item = FullPathFileItem {
+            from_path: SlashPath::from_path(&source, &vec![])?,
+            to_path: SlashPath::from_path(&df, &vec![])?,
+            sha1: hash_file_sha1(&source),
+            len: 3,
+            modified: Some(1_500_000_000),
+            created: None,
+        };
+        assert!(matches!(item.changed(&df, Some(&digests)), FileChanged::Sha1(..)));
+
+        // like copy_to_file and set_file_mtime, same len, mtime and inode.
+        fs::write(&df, "abd")?;
+        filetime::set_file_mtime(&df, ft)?;
+        data_shape_util::refresh_digest(&df, item.sha1.as_deref(), Some(&digests));
+        assert!(matches!(item.changed(&df, Some(&digests)), FileChanged::NoChange));
+
+        // the source reverts.
+        let original = tu.make_a_file_with_content("original.txt", "abc")?;
+        let reverted = FullPathFileItem {
+            sha1: hash_file_sha1(&original),
+            ..item
+        };
+        assert!(matches!(reverted.changed(&df, Some(&digests)), FileChanged::Sha1(..)));
+
+        data_shape_util::refresh_digest(&df, None, Some(&digests));
+        assert_eq!(
+            digests.find_digest(&DigestKey::new(&df, &df.metadata()?)),
+            None,
+            "forgotten without a sha1."
+        );
+        Ok(())
+    }
 }
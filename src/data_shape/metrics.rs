@@ -0,0 +1,290 @@
+//! Per server metrics in the prometheus text format, written to a .prom file for
+//! node_exporter's textfile collector and served on /metrics when running as service.
+use chrono::{DateTime, Local};
+use log::*;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const METRIC_PREFIX: &str = "bk_over_ssh_";
+/// A client that never finishes the request doesn't hold the listener.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerMetrics {
+    pub server: String,
+    pub last_success: Option<DateTime<Local>>,
+    /// when the latest run finished, None if it never ran.
+    pub last_run: Option<DateTime<Local>>,
+    pub last_run_succeeded: bool,
+    pub last_run_duration_secs: f64,
+    pub bytes_transferred: u64,
+    pub files_changed: u64,
+    pub files_failed: u64,
+    pub archive_bytes: u64,
+    pub archive_count: u64,
+    pub next_run: Option<DateTime<Local>>,
+}
+
+/// name, help and the value of a server, the server is left out if it's None.
+type MetricDef = (
+    &'static str,
+    &'static str,
+    fn(&ServerMetrics) -> Option<f64>,
+);
+
+const METRICS: &[MetricDef] = &[
+    (
+        "last_success_timestamp_seconds",
+        "Unix time of the last successful sync run.",
+        |m| m.last_success.map(|t| t.timestamp() as f64),
+    ),
+    (
+        "last_run_timestamp_seconds",
+        "Unix time the latest sync run finished.",
+        |m| m.last_run.map(|t| t.timestamp() as f64),
+    ),
+    (
+        "last_run_success",
+        "1 if the latest sync run succeeded.",
+        |m| {
+            m.last_run
+                .map(|_| if m.last_run_succeeded { 1.0 } else { 0.0 })
+        },
+    ),
+    (
+        "last_run_duration_seconds",
+        "Duration of the latest sync run.",
+        |m| m.last_run.map(|_| m.last_run_duration_secs),
+    ),
+    (
+        "last_run_bytes_transferred",
+        "Bytes transferred by the latest sync run.",
+        |m| m.last_run.map(|_| m.bytes_transferred as f64),
+    ),
+    (
+        "last_run_files_changed",
+        "Files changed in the latest sync run.",
+        |m| m.last_run.map(|_| m.files_changed as f64),
+    ),
+    (
+        "last_run_files_failed",
+        "Files failed in the latest sync run.",
+        |m| m.last_run.map(|_| m.files_failed as f64),
+    ),
+    ("archive_bytes", "Total size of the archives.", |m| {
+        Some(m.archive_bytes as f64)
+    }),
+    ("archive_count", "Number of the archives.", |m| {
+        Some(m.archive_count as f64)
+    }),
+    (
+        "next_run_timestamp_seconds",
+        "Unix time of the next scheduled sync run.",
+        |m| m.next_run.map(|t| t.timestamp() as f64),
+    ),
+];
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// All gauges, one sample for each server.
+pub fn render(servers: &[&ServerMetrics]) -> String {
+    let mut text = String::new();
+    for (name, help, value) in METRICS {
+        writeln!(text, "# HELP {}{} {}", METRIC_PREFIX, name, help).ok();
+        writeln!(text, "# TYPE {}{} gauge", METRIC_PREFIX, name).ok();
+        for m in servers {
+            if let Some(v) = value(m) {
+                writeln!(
+                    text,
+                    "{}{}{{server=\"{}\"}} {}",
+                    METRIC_PREFIX,
+                    name,
+                    escape_label(&m.server),
+                    v
+                )
+                .ok();
+            }
+        }
+    }
+    text
+}
+
+/// Write to a temporary file then rename, the collector never sees a half written file.
+/// The writers sharing the tmp file should be serialized, like MetricsRegistry::write_prom_file.
+pub fn write_prom_file(path: &Path, content: &str) -> Result<(), failure::Error> {
+    let file_name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => bail!("invalid prom file: {:?}", path),
+    };
+    let tmp = path.with_file_name(format!("{}.tmp", file_name));
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// The latest metrics of the servers, shared by the server threads and the listener.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsRegistry {
+    servers: Arc<Mutex<BTreeMap<String, ServerMetrics>>>,
+}
+
+impl MetricsRegistry {
+    pub fn update(&self, metrics: ServerMetrics) {
+        if let Ok(mut servers) = self.servers.lock() {
+            servers.insert(metrics.server.clone(), metrics);
+        }
+    }
+
+    pub fn render(&self) -> String {
+        match self.servers.lock() {
+            Ok(servers) => render(&servers.values().collect::<Vec<_>>()),
+            Err(_) => String::new(),
+        }
+    }
+
+    /// Hold the lock while writing, the server threads don't race on the tmp file.
+    pub fn write_prom_file(&self, path: &Path) -> Result<(), failure::Error> {
+        let servers = match self.servers.lock() {
+            Ok(servers) => servers,
+            Err(_) => bail!("the metrics registry is poisoned."),
+        };
+        write_prom_file(path, &render(&servers.values().collect::<Vec<_>>()))
+    }
+
+    /// Serve GET /metrics on the address like 127.0.0.1:9184 in a thread.
+    pub fn serve(&self, listen: &str) -> Result<thread::JoinHandle<()>, failure::Error> {
+        let listener = TcpListener::bind(listen)?;
+        info!("serve metrics on http://{}/metrics", listener.local_addr()?);
+        let registry = self.clone();
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(err) = registry.answer(stream) {
+                            warn!("answer the metrics request failed: {}", err);
+                        }
+                    }
+                    Err(err) => warn!("accept the metrics connection failed: {}", err),
+                }
+            }
+        }))
+    }
+
+    fn answer(&self, mut stream: TcpStream) -> Result<(), failure::Error> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+                break;
+            }
+        }
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.render()),
+            _ => ("404 Not Found", "not found, try /metrics.\n".to_string()),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+        stream.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::develope::tutil;
+    use std::io::Read;
+
+    #[test]
+    fn t_metrics() -> Result<(), failure::Error> {
+        let registry = MetricsRegistry::default();
+        registry.update(ServerMetrics {
+            server: "a.example.com".to_string(),
+            last_success: Some(Local::now()),
+            last_run: Some(Local::now()),
+            last_run_succeeded: true,
+            last_run_duration_secs: 1.5,
+            bytes_transferred: 1024,
+            files_changed: 3,
+            archive_bytes: 2048,
+            archive_count: 2,
+            ..ServerMetrics::default()
+        });
+        registry.update(ServerMetrics {
+            server: "b\"x".to_string(),
+            ..ServerMetrics::default()
+        });
+        let text = registry.render();
+        assert!(text.contains("# TYPE bk_over_ssh_archive_bytes gauge\n"));
+        assert!(
+            text.contains("bk_over_ssh_last_run_duration_seconds{server=\"a.example.com\"} 1.5\n")
+        );
+        assert!(text.contains("bk_over_ssh_archive_count{server=\"b\\\"x\"} 0\n"));
+        assert!(
+            !text.contains("bk_over_ssh_last_run_success{server=\"b"),
+            "never ran, no sample."
+        );
+
+        let tu = tutil::TestDir::new();
+        let prom = tu.tmp_dir_path().join("bk_over_ssh.prom");
+        write_prom_file(&prom, &text)?;
+        assert_eq!(fs::read_to_string(&prom)?, text);
+        assert_eq!(
+            fs::read_dir(tu.tmp_dir_path())?.count(),
+            1,
+            "no tmp file left."
+        );
+
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let registry = registry.clone();
+                let prom = prom.clone();
+                thread::spawn(move || {
+                    (0..20).try_for_each(|_| {
+                        registry.write_prom_file(&prom).map_err(|e| e.to_string())
+                    })
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer
+                .join()
+                .expect("writer thread panicked.")
+                .map_err(failure::err_msg)?;
+        }
+        assert_eq!(fs::read_to_string(&prom)?, text);
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        drop(listener);
+        registry.serve(&addr.to_string())?;
+        let mut stream = TcpStream::connect(addr)?;
+        write!(stream, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with(&text));
+        Ok(())
+    }
+}
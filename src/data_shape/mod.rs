@@ -1,13 +1,26 @@
 pub mod app_conf;
+pub mod archive_crypt;
+pub mod archive_manifest;
+pub mod archive_verify;
 pub mod count_reader;
 pub mod disk_directory;
+pub mod freshness;
 // pub mod file_item_map;
 // pub mod file_item_directory;
 pub mod indicator;
+pub mod metrics;
+pub mod notification;
+pub mod offsite;
 // pub mod relative_file_item;
 pub mod rolling_files;
+pub mod secret;
 pub mod server;
 pub mod sha1_reader;
+pub mod ssh_config;
+pub mod sync_hook;
+pub mod sync_log;
+pub mod sync_report;
+pub mod work_lock;
 pub mod string_path;
 pub mod writer_with_progress;
 pub mod full_path_item;
@@ -28,12 +41,14 @@ pub use disk_directory::Directory;
 pub use indicator::{Indicator, PbProperties};
 // pub use relative_file_item::{RelativeFileItem};
 pub use full_path_item::{FullPathFileItem, FileChanged, FullPathFileItemError};
+pub use secret::{Secret, Secrets};
 pub use server::{Server, ServerYml};
 pub use sha1_reader::Sha1Reader;
 pub use string_path::SlashPath;
-pub use writer_with_progress::ProgressWriter;
+pub use writer_with_progress::{FinishWrite, ProgressWriter};
 
 use serde::{Deserialize, Serialize};
+use std::{env, fs};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ScheduleItem {
@@ -56,6 +71,21 @@ pub struct PruneStrategy {
     pub hourly: u8,
     #[builder(default = "1")]
     pub minutely: u8,
+    /// the constraints below are evaluated after the period rules, they evict the oldest copies.
+    #[builder(default = "None")]
+    #[serde(default)]
+    pub max_total_bytes: Option<u64>,
+    #[builder(default = "None")]
+    #[serde(default)]
+    pub max_age_days: Option<u32>,
+    /// the constraints never leave less copies than this.
+    #[builder(default = "1")]
+    #[serde(default = "default_min_keep")]
+    pub min_keep: u32,
+}
+
+fn default_min_keep() -> u32 {
+    1
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -63,4 +93,44 @@ pub enum AuthMethod {
     Password,
     Agent,
     IdentityFile,
+    KeyboardInteractive,
+}
+
+/// Where the passphrase of an encrypted private key comes from.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub enum PassphraseSource {
+    /// Name of an environment variable.
+    Env(String),
+    /// A file holding nothing but the passphrase.
+    File(String),
+    /// Ask on the terminal, so it doesn't work in as-service mode.
+    Prompt,
+}
+
+impl PassphraseSource {
+    pub fn resolve(&self, key_file: &str) -> Result<String, failure::Error> {
+        Ok(match self {
+            PassphraseSource::Env(name) => match env::var(name) {
+                Ok(v) => v,
+                Err(err) => bail!("read passphrase from env {} failed, {:?}", name, err),
+            },
+            PassphraseSource::File(f) => match fs::read_to_string(f) {
+                Ok(content) => content.trim_end_matches(&['\r', '\n'][..]).to_string(),
+                Err(err) => bail!("read passphrase from file {} failed, {:?}", f, err),
+            },
+            PassphraseSource::Prompt => {
+                rpassword::read_password_from_tty(Some(&format!("Passphrase for {}: ", key_file)))?
+            }
+        })
+    }
+}
+
+/// How the server's host key is checked right after the handshake.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub enum HostKeyCheck {
+    /// The host key must already be present in the known_hosts file.
+    #[default]
+    Strict,
+    /// Pin the key into the hub's data dir on first connect, verify against it afterwards.
+    TrustOnFirstUse,
 }
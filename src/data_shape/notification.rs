@@ -0,0 +1,345 @@
+//! Notify other systems when a run finishes or a server goes stale,
+//! by posting the summary to a webhook or piping it to a local program.
+use super::sync_report::SyncReport;
+use super::{Secret, Secrets};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+fn default_on() -> Vec<NotificationEvent> {
+    vec![NotificationEvent::Failure, NotificationEvent::Stale]
+}
+
+fn default_retries() -> u32 {
+    2
+}
+
+fn default_retry_interval_secs() -> u64 {
+    5
+}
+
+fn default_timeout_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationEvent {
+    Success,
+    Failure,
+    /// no successful run for longer than expected.
+    Stale,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Notification {
+    /// shows in the logs.
+    pub name: String,
+    pub target: NotificationTarget,
+    #[serde(default = "default_on")]
+    pub on: Vec<NotificationEvent>,
+    /// the hosts of the servers to notify about, empty means all.
+    #[serde(default)]
+    pub servers: Vec<String>,
+    /// tries after the first failed one.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    #[serde(default = "default_retry_interval_secs")]
+    pub retry_interval_secs: u64,
+    /// the summary json if it's None. {{event}}, {{server}}, {{message}} and {{summary}} are replaced
+    /// by the values escaped as json strings, so they go between the quotes.
+    #[serde(default)]
+    pub payload_template: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationTarget {
+    /// POST the payload as application/json.
+    Webhook {
+        url: String,
+        #[serde(default)]
+        headers: BTreeMap<String, Secret>,
+        #[serde(default = "default_timeout_secs")]
+        timeout_secs: u64,
+    },
+    /// the program and it's arguments, the payload is written to it's stdin.
+    Command { program: Vec<String> },
+}
+
+/// What's sent, the report is None for a stale server.
+#[derive(Debug, Serialize)]
+pub struct NotificationSummary<'a> {
+    pub event: NotificationEvent,
+    pub server: &'a str,
+    pub message: String,
+    pub report: Option<&'a SyncReport>,
+}
+
+impl<'a> NotificationSummary<'a> {
+    pub fn from_report(report: &'a SyncReport) -> Self {
+        let event = if report.succeeded() {
+            NotificationEvent::Success
+        } else {
+            NotificationEvent::Failure
+        };
+        let message = format!(
+            "{:?} of {} {}, {} changed, {} failed, {} errors.",
+            report.direction,
+            report.server,
+            if report.succeeded() {
+                "succeeded"
+            } else {
+                "failed"
+            },
+            report.changed,
+            report.failed,
+            report.errors.len()
+        );
+        Self {
+            event,
+            server: &report.server,
+            message,
+            report: Some(report),
+        }
+    }
+
+    pub fn stale(server: &'a str, message: impl Into<String>) -> Self {
+        Self {
+            event: NotificationEvent::Stale,
+            server,
+            message: message.into(),
+            report: None,
+        }
+    }
+}
+
+/// The content of a json string without the quotes.
+fn json_escaped(value: &str) -> String {
+    let quoted = serde_json::to_string(value).unwrap_or_default();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+impl Notification {
+    pub fn resolve_secrets(&mut self, secrets: &Secrets) -> Result<(), failure::Error> {
+        if let NotificationTarget::Webhook { headers, .. } = &mut self.target {
+            for value in headers.values_mut() {
+                value.resolve(secrets)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn wants(&self, summary: &NotificationSummary) -> bool {
+        self.on.contains(&summary.event)
+            && (self.servers.is_empty() || self.servers.iter().any(|s| s == summary.server))
+    }
+
+    pub fn payload(&self, summary: &NotificationSummary) -> Result<String, failure::Error> {
+        let summary_json = serde_json::to_string(summary)?;
+        Ok(match self.payload_template.as_ref() {
+            Some(template) => template
+                .replace(
+                    "{{event}}",
+                    &json_escaped(&format!("{:?}", summary.event).to_lowercase()),
+                )
+                .replace("{{server}}", &json_escaped(summary.server))
+                .replace("{{message}}", &json_escaped(&summary.message))
+                .replace("{{summary}}", &json_escaped(&summary_json)),
+            None => summary_json,
+        })
+    }
+
+    fn deliver(&self, payload: &str) -> Result<(), failure::Error> {
+        match &self.target {
+            NotificationTarget::Webhook {
+                url,
+                headers,
+                timeout_secs,
+            } => {
+                let mut request = ureq::post(url);
+                request
+                    .timeout(Duration::from_secs(*timeout_secs))
+                    .set("Content-Type", "application/json");
+                for (name, value) in headers.iter() {
+                    request.set(name, value.expose());
+                }
+                let response = request.send_string(payload);
+                if let Some(err) = response.synthetic_error() {
+                    bail!("post to {} failed: {}", url, err);
+                }
+                if !response.ok() {
+                    bail!("post to {} failed: {}", url, response.status_line());
+                }
+            }
+            NotificationTarget::Command { program } => {
+                let (exe, args) = match program.split_first() {
+                    Some(split) => split,
+                    None => bail!("the program of the command is empty."),
+                };
+                let mut child = Command::new(exe).args(args).stdin(Stdio::piped()).spawn()?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin.write_all(payload.as_bytes())?;
+                }
+                let status = child.wait()?;
+                if !status.success() {
+                    bail!("{:?} exited with {}.", program, status);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Deliver it, try again on failure for retries times.
+    pub fn send(&self, summary: &NotificationSummary) -> Result<(), failure::Error> {
+        let payload = self.payload(summary)?;
+        let mut tried = 0;
+        loop {
+            match self.deliver(&payload) {
+                Ok(()) => return Ok(()),
+                Err(err) if tried < self.retries => {
+                    tried += 1;
+                    warn!(
+                        "notification {} failed, retry {}/{}: {}",
+                        self.name, tried, self.retries, err
+                    );
+                    thread::sleep(Duration::from_secs(self.retry_interval_secs));
+                }
+                Err(err) => bail!("notification {} failed: {}", self.name, err),
+            }
+        }
+    }
+}
+
+/// Send to every notification wanting it, the failures are logged only.
+pub fn notify_all(notifications: &[Notification], summary: &NotificationSummary) {
+    for notification in notifications.iter().filter(|n| n.wants(summary)) {
+        match notification.send(summary) {
+            Ok(()) => info!(
+                "notified {} of {:?} of {}.",
+                notification.name, summary.event, summary.server
+            ),
+            Err(err) => error!("{}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_shape::sync_report::SyncDirection;
+    use crate::develope::tutil;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    fn a_notification(target: NotificationTarget) -> Notification {
+        Notification {
+            name: "test".to_string(),
+            target,
+            on: default_on(),
+            servers: Vec::new(),
+            retries: 1,
+            retry_interval_secs: 0,
+            payload_template: None,
+        }
+    }
+
+    /// Answers 500 to the first request and 200 to the second, the bodies are sent back.
+    fn stand_in_webhook() -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind a port.");
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().take(2).enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(v) = line.to_lowercase().strip_prefix("content-length:") {
+                        content_length = v.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                tx.send(String::from_utf8(body).unwrap()).unwrap();
+                let status = if i == 0 {
+                    "500 Internal Server Error"
+                } else {
+                    "200 OK"
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn t_notifications() -> Result<(), failure::Error> {
+        let mut report = SyncReport::new("a.example.com", SyncDirection::Pull);
+        report.record_failed("copy to \"a.txt\" failed.");
+        report.finish();
+        let summary = NotificationSummary::from_report(&report);
+        assert_eq!(summary.event, NotificationEvent::Failure);
+
+        let (url, bodies) = stand_in_webhook();
+        let mut webhook = a_notification(NotificationTarget::Webhook {
+            url,
+            headers: BTreeMap::new(),
+            timeout_secs: 5,
+        });
+        webhook.payload_template = Some(
+            r#"{"text": "{{server}} {{event}}: {{message}}", "raw": "{{summary}}"}"#.to_string(),
+        );
+        assert!(webhook.wants(&summary));
+        webhook.send(&summary)?;
+        let first = bodies.recv()?;
+        assert_eq!(first, bodies.recv()?, "retried with the same payload.");
+        let posted: serde_json::Value = serde_json::from_str(&first)?;
+        assert!(posted["text"]
+            .as_str()
+            .unwrap()
+            .starts_with("a.example.com failure: Pull of a.example.com failed"));
+        let raw: serde_json::Value = serde_json::from_str(posted["raw"].as_str().unwrap())?;
+        assert_eq!(raw["report"]["failed"], 1);
+
+        let tu = tutil::TestDir::new();
+        let out = tu.tmp_dir_path().join("payload.json");
+        let mut command = a_notification(NotificationTarget::Command {
+            program: vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                format!("cat > {}", out.to_string_lossy()),
+            ],
+        });
+        command.servers = vec!["b.example.com".to_string()];
+        assert!(!command.wants(&summary));
+        let stale = NotificationSummary::stale("b.example.com", "no run for 2 days.");
+        assert!(command.wants(&stale));
+        command.send(&stale)?;
+        let piped: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&out)?)?;
+        assert_eq!(piped["event"], "stale");
+        assert!(piped["report"].is_null());
+
+        command.target = NotificationTarget::Command {
+            program: vec!["sh".to_string(), "-c".to_string(), "exit 1".to_string()],
+        };
+        assert!(command.send(&stale).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,415 @@
+//! Copies of the archives on a second storage, uploaded after archive_local succeeds.
+//! Each target prunes it's copies by it's own prune_strategy, the incremental chains are kept whole
+//! because the manifests are uploaded too.
+use super::archive_manifest::{self, ArchiveManifest, MANIFEST_POSTFIX};
+use super::rolling_files::{self, RetentionTimezone};
+use super::server::JumpHost;
+use super::{PruneStrategy, Secret, Secrets};
+use crate::actions::s3_client::S3Client;
+use crate::actions::{copy_a_file_sftp, ssh_util};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct OffsiteTarget {
+    /// shows in the logs.
+    pub name: String,
+    pub destination: OffsiteDestination,
+    /// default to keep every copy on the target.
+    #[serde(default)]
+    pub prune_strategy: Option<PruneStrategy>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OffsiteDestination {
+    /// a directory of this machine, a mounted share for example.
+    Local {
+        dir: String,
+    },
+    /// a directory on another ssh server, ssh takes the same keys as a jump host.
+    Sftp {
+        dir: String,
+        ssh: JumpHost,
+    },
+    S3(S3Target),
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct S3Target {
+    /// like https://s3.amazonaws.com or http://127.0.0.1:9000, the requests are path style.
+    pub endpoint: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    pub bucket: String,
+    /// prepended to the archive names, like "server-a/".
+    #[serde(default)]
+    pub prefix: String,
+    pub access_key: String,
+    pub secret_key: Secret,
+}
+
+/// What a target storage must do, the names are relative to the target.
+trait OffsiteStore {
+    fn put(&self, local: &Path, name: &str) -> Result<(), failure::Error>;
+    /// the names and lengths of the files.
+    fn list(&self) -> Result<Vec<(String, u64)>, failure::Error>;
+    fn read(&self, name: &str) -> Result<Vec<u8>, failure::Error>;
+    fn delete(&self, name: &str) -> Result<(), failure::Error>;
+}
+
+struct LocalStore {
+    dir: PathBuf,
+}
+
+impl OffsiteStore for LocalStore {
+    fn put(&self, local: &Path, name: &str) -> Result<(), failure::Error> {
+        fs::create_dir_all(&self.dir)?;
+        // a half copied archive never looks like a finished one.
+        let part = self.dir.join(format!("{}.part", name));
+        fs::copy(local, &part)?;
+        fs::rename(&part, self.dir.join(name))?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<(String, u64)>, failure::Error> {
+        let mut listing = Vec::new();
+        for entry in self.dir.read_dir()? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_file() {
+                listing.push((entry.file_name().to_string_lossy().to_string(), meta.len()));
+            }
+        }
+        Ok(listing)
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>, failure::Error> {
+        Ok(fs::read(self.dir.join(name))?)
+    }
+
+    fn delete(&self, name: &str) -> Result<(), failure::Error> {
+        Ok(fs::remove_file(self.dir.join(name))?)
+    }
+}
+
+struct SftpStore {
+    sftp: ssh2::Sftp,
+    dir: PathBuf,
+    _sess: ssh2::Session,
+}
+
+impl OffsiteStore for SftpStore {
+    fn put(&self, local: &Path, name: &str) -> Result<(), failure::Error> {
+        if self.sftp.stat(&self.dir).is_err() {
+            self.sftp.mkdir(&self.dir, 0o755)?;
+        }
+        let part = self.dir.join(format!("{}.part", name));
+        let local = match local.to_str() {
+            Some(local) => local,
+            None => bail!("local path isn't valid utf-8: {:?}", local),
+        };
+        copy_a_file_sftp(&self.sftp, local, part.to_string_lossy())?;
+        self.sftp.rename(&part, &self.dir.join(name), None)?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<(String, u64)>, failure::Error> {
+        Ok(self
+            .sftp
+            .readdir(&self.dir)?
+            .into_iter()
+            .filter(|(_, stat)| stat.is_file())
+            .filter_map(|(path, stat)| {
+                let name = path.file_name()?.to_string_lossy().to_string();
+                Some((name, stat.size.unwrap_or(0)))
+            })
+            .collect())
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>, failure::Error> {
+        let mut bytes = Vec::new();
+        self.sftp
+            .open(&self.dir.join(name))?
+            .read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn delete(&self, name: &str) -> Result<(), failure::Error> {
+        Ok(self.sftp.unlink(&self.dir.join(name))?)
+    }
+}
+
+struct S3Store<'a> {
+    target: &'a S3Target,
+}
+
+impl<'a> S3Store<'a> {
+    fn client(&self) -> S3Client<'a> {
+        S3Client {
+            endpoint: self.target.endpoint.as_str(),
+            region: self.target.region.as_str(),
+            bucket: self.target.bucket.as_str(),
+            access_key: self.target.access_key.as_str(),
+            secret_key: self.target.secret_key.expose(),
+        }
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}{}", self.target.prefix, name)
+    }
+}
+
+impl<'a> OffsiteStore for S3Store<'a> {
+    fn put(&self, local: &Path, name: &str) -> Result<(), failure::Error> {
+        self.client().put_file(&self.key(name), local)
+    }
+
+    fn list(&self) -> Result<Vec<(String, u64)>, failure::Error> {
+        let prefix = self.target.prefix.as_str();
+        Ok(self
+            .client()
+            .list(prefix)?
+            .into_iter()
+            .filter_map(|(key, len)| {
+                let name = key.strip_prefix(prefix)?;
+                if name.contains('/') {
+                    None
+                } else {
+                    Some((name.to_string(), len))
+                }
+            })
+            .collect())
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>, failure::Error> {
+        self.client().get_bytes(&self.key(name))
+    }
+
+    fn delete(&self, name: &str) -> Result<(), failure::Error> {
+        self.client().delete(&self.key(name))
+    }
+}
+
+impl OffsiteTarget {
+    pub fn resolve_secrets(&mut self, secrets: &Secrets) -> Result<(), failure::Error> {
+        match &mut self.destination {
+            OffsiteDestination::Local { .. } => Ok(()),
+            OffsiteDestination::Sftp { ssh, .. } => ssh.password.resolve(secrets),
+            OffsiteDestination::S3(s3) => s3.secret_key.resolve(secrets),
+        }
+    }
+
+    /// pin_file is for the trust-on-first-use host keys of a sftp target.
+    fn open_store<'a>(
+        &'a self,
+        pin_file: &Path,
+    ) -> Result<Box<dyn OffsiteStore + 'a>, failure::Error> {
+        Ok(match &self.destination {
+            OffsiteDestination::Local { dir } => Box::new(LocalStore {
+                dir: PathBuf::from(dir),
+            }),
+            OffsiteDestination::Sftp { dir, ssh } => {
+                let sess = ssh_util::create_ssh_session(&ssh.to_endpoint(pin_file), &[])?;
+                Box::new(SftpStore {
+                    sftp: sess.sftp()?,
+                    dir: PathBuf::from(dir),
+                    _sess: sess,
+                })
+            }
+            OffsiteDestination::S3(target) => Box::new(S3Store { target }),
+        })
+    }
+
+    /// Upload the archive and it's manifest if any, then prune the copies on the target.
+    pub fn upload(
+        &self,
+        archive: &Path,
+        tz: RetentionTimezone,
+        name_prefix: &str,
+        name_ext_with_dot: &str,
+        pin_file: &Path,
+    ) -> Result<(), failure::Error> {
+        let name = match archive.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name,
+            None => bail!("invalid archive file name: {:?}", archive),
+        };
+        let store = self.open_store(pin_file)?;
+        store.put(archive, name)?;
+        // after the archive, so an archive with a manifest is complete.
+        let manifest = archive_manifest::manifest_path(archive);
+        if manifest.exists() {
+            store.put(&manifest, &format!("{}{}", name, MANIFEST_POSTFIX))?;
+        }
+        info!("copied {} to offsite target {}.", name, self.name);
+        if let Some(prune_strategy) = self.prune_strategy.as_ref() {
+            prune_store(
+                store.as_ref(),
+                prune_strategy,
+                tz,
+                name_prefix,
+                name_ext_with_dot,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn prune_store(
+    store: &dyn OffsiteStore,
+    prune_strategy: &PruneStrategy,
+    tz: RetentionTimezone,
+    name_prefix: &str,
+    name_ext_with_dot: &str,
+) -> Result<(), failure::Error> {
+    let listing = store.list()?;
+    let names: HashSet<&str> = listing.iter().map(|(name, _)| name.as_str()).collect();
+    let mut parents: HashMap<String, Option<String>> = HashMap::new();
+    for (name, _) in listing.iter() {
+        let archive = match name.strip_suffix(MANIFEST_POSTFIX) {
+            Some(archive) if names.contains(archive) => archive,
+            _ => continue,
+        };
+        match serde_json::from_slice::<ArchiveManifest>(&store.read(name)?) {
+            Ok(manifest) => {
+                parents.insert(archive.to_string(), manifest.parent);
+            }
+            Err(err) => warn!("parse manifest {} failed: {}", name, err),
+        }
+    }
+    let chain_of = |name: &str| {
+        if parents.contains_key(name) {
+            Some(archive_manifest::chain_from_parents(name, &parents))
+        } else {
+            None
+        }
+    };
+    let plan = rolling_files::plan_prune_listing(
+        prune_strategy,
+        tz,
+        listing.clone(),
+        name_prefix,
+        name_ext_with_dot,
+        &chain_of,
+    );
+    for retention in plan.iter().filter(|r| r.delete) {
+        let name = retention.path.to_string_lossy();
+        trace!("delete offsite copy: {}", name);
+        store.delete(&name)?;
+        let manifest = format!("{}{}", name, MANIFEST_POSTFIX);
+        if names.contains(manifest.as_str()) {
+            store.delete(&manifest)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_shape::archive_manifest::ArchiveKind;
+    use crate::data_shape::PruneStrategyBuilder;
+    use crate::develope::tutil;
+
+    #[test]
+    fn t_upload_to_local_target() -> Result<(), failure::Error> {
+        let targets: Vec<OffsiteTarget> = serde_yaml::from_str(
+            r#"
+- name: nas
+  destination:
+    local:
+      dir: /mnt/nas
+- name: minio
+  destination:
+    s3:
+      endpoint: http://127.0.0.1:9000
+      bucket: backups
+      access_key: minio
+      secret_key: "${env:MINIO_SECRET}"
+  prune_strategy:
+    yearly: 1
+    monthly: 1
+    weekly: 0
+    daily: 3
+    hourly: 1
+    minutely: 1
+"#,
+        )?;
+        assert_eq!(targets.len(), 2);
+        match &targets[1].destination {
+            OffsiteDestination::S3(s3) => assert_eq!(s3.region, "us-east-1"),
+            _ => panic!("should be a s3 target."),
+        }
+
+        let tu = tutil::TestDir::new();
+        let archives = tu.create_sub_dir("archives");
+        let target_dir = tu.tmp_dir_path().join("offsite");
+        let target = OffsiteTarget {
+            name: "nas".to_string(),
+            destination: OffsiteDestination::Local {
+                dir: target_dir.to_string_lossy().to_string(),
+            },
+            prune_strategy: Some(
+                PruneStrategyBuilder::default()
+                    .daily(1)
+                    .build()
+                    .map_err(failure::err_msg)?,
+            ),
+        };
+        for (name, parent) in &[
+            ("abc_20130101000000.tar", None),
+            ("abc_20130102000000.tar", None),
+            ("abc_20130103000000.tar", Some("abc_20130102000000.tar")),
+        ] {
+            let archive = archives.join(name);
+            fs::write(&archive, "abc")?;
+            if name.contains("0101") {
+                // written without incremental mode.
+            } else {
+                ArchiveManifest {
+                    archive: name.to_string(),
+                    kind: if parent.is_none() {
+                        ArchiveKind::Full
+                    } else {
+                        ArchiveKind::Incremental
+                    },
+                    parent: parent.map(str::to_string),
+                    chain_len: if parent.is_none() { 0 } else { 1 },
+                    files: Vec::new(),
+                    deleted: Vec::new(),
+                }
+                .save(&archive)?;
+            }
+            target.upload(
+                &archive,
+                RetentionTimezone::Utc,
+                "abc_",
+                ".tar",
+                &tu.tmp_dir_path().join("known_hosts"),
+            )?;
+        }
+        // only the latest day is kept, with the archive it depends on.
+        let mut remains: Vec<String> = fs::read_dir(&target_dir)?
+            .map(|e| e.map(|e| e.file_name().to_string_lossy().to_string()))
+            .collect::<Result<_, _>>()?;
+        remains.sort();
+        assert_eq!(
+            remains,
+            vec![
+                "abc_20130102000000.tar",
+                "abc_20130102000000.tar.manifest.json",
+                "abc_20130103000000.tar",
+                "abc_20130103000000.tar.manifest.json",
+            ]
+        );
+        Ok(())
+    }
+}
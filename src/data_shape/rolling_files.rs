@@ -1,15 +1,55 @@
+use crate::data_shape::archive_manifest::{self, ArchiveManifest};
 use crate::data_shape::PruneStrategy;
 use chrono::{DateTime, Datelike, FixedOffset, Timelike, Utc};
 use log::*;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
 use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
 use std::{fs, io};
 
 #[derive(Debug)]
 struct FileCopy {
-    pub dir_entry: fs::DirEntry,
+    /// a local path, or the bare name if it's listed from a remote storage.
+    pub path: PathBuf,
+    pub len: u64,
     pub copy_trait: DateTime<Utc>,
+    /// the period which decided to keep or to delete it.
+    pub bucket: Option<RetentionBucket>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionBucket {
+    Yearly,
+    Monthly,
+    Weekly,
+    Daily,
+    Hourly,
+    Minutely,
+}
+
+/// How an archive is treated by the next prune.
+#[derive(Debug, Serialize)]
+pub struct ArchiveRetention {
+    pub path: PathBuf,
+    pub time: DateTime<Utc>,
+    pub len: u64,
+    pub bucket: RetentionBucket,
+    pub delete: bool,
+    /// the retained incremental archive which depends on it, it's kept even the bucket drops it.
+    pub required_by: Option<String>,
+    /// the constraint deleting it after the period rules kept it.
+    pub evicted_by: Option<EvictionRule>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionRule {
+    MaxTotalBytes,
+    MaxAgeDays,
 }
 
 #[derive(Debug)]
@@ -43,6 +83,81 @@ impl FileCopies {
     }
 }
 
+/// The timezone the archives are named and grouped by, utc, local or a fixed offset like +08:00.
+/// The names carry the offset unless it's utc, the names without an offset are in utc.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum RetentionTimezone {
+    #[default]
+    Utc,
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl RetentionTimezone {
+    pub fn local_time(self, dt: &DateTime<Utc>) -> DateTime<FixedOffset> {
+        match self {
+            RetentionTimezone::Utc => dt.with_timezone(&FixedOffset::east(0)),
+            RetentionTimezone::Local => {
+                let local = dt.with_timezone(&chrono::Local);
+                local.with_timezone(local.offset())
+            }
+            RetentionTimezone::Fixed(offset) => dt.with_timezone(&offset),
+        }
+    }
+}
+
+impl FromStr for RetentionTimezone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "utc" | "z" => return Ok(RetentionTimezone::Utc),
+            "local" => return Ok(RetentionTimezone::Local),
+            _ => (),
+        }
+        let invalid = || format!("invalid timezone: {:?}, expect utc, local or +HH:MM.", s);
+        let sign = match s.chars().next() {
+            Some('+') => 1,
+            Some('-') => -1,
+            _ => return Err(invalid()),
+        };
+        let digits: String = s[1..].chars().filter(|c| *c != ':').collect();
+        if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let hours: i32 = digits[0..2].parse().map_err(|_| invalid())?;
+        let minutes: i32 = digits[2..4].parse().map_err(|_| invalid())?;
+        FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+            .map(RetentionTimezone::Fixed)
+            .ok_or_else(invalid)
+    }
+}
+
+impl TryFrom<String> for RetentionTimezone {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for RetentionTimezone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RetentionTimezone::Utc => write!(f, "utc"),
+            RetentionTimezone::Local => write!(f, "local"),
+            RetentionTimezone::Fixed(offset) => write!(f, "{}", offset),
+        }
+    }
+}
+
+impl From<RetentionTimezone> for String {
+    fn from(tz: RetentionTimezone) -> Self {
+        tz.to_string()
+    }
+}
+
 enum GroupPeriod {
     Yearly,
     Monthly,
@@ -54,20 +169,34 @@ enum GroupPeriod {
 
 fn format_dt(
     dt: &DateTime<Utc>,
+    tz: RetentionTimezone,
     name_stem: impl AsRef<str>,
     name_ext_with_dot: impl AsRef<str>,
 ) -> String {
+    // keep the names in utc as they were.
+    let offset = if tz == RetentionTimezone::Utc {
+        ""
+    } else {
+        "%z"
+    };
     let s = format!(
-        "{}%Y%m%d%H%M%S{}",
+        "{}%Y%m%d%H%M%S{}{}",
         name_stem.as_ref(),
+        offset,
         name_ext_with_dot.as_ref()
     );
-    dt.format(&s).to_string()
+    tz.local_time(dt).format(&s).to_string()
 }
 
+/// 14 digitals in utc, or followed by an offset like +0800.
 fn parse_date_time(ss: impl AsRef<str>) -> Result<DateTime<FixedOffset>, failure::Error> {
     let ss = ss.as_ref();
-    if ss.len() != 14 {
+    let offset = ss.get(14..).unwrap_or_default();
+    if ss.len() == 19 && (offset.starts_with('+') || offset.starts_with('-')) {
+        return DateTime::parse_from_str(ss, "%Y%m%d%H%M%S%z")
+            .map_err(|e| failure::format_err!("parse_from_str failed.{:?}", e));
+    }
+    if ss.len() != 14 || !ss.bytes().all(|b| b.is_ascii_digit()) {
         bail!("not a 14 digitals string.");
     }
     let s = format!(
@@ -83,42 +212,45 @@ fn parse_date_time(ss: impl AsRef<str>) -> Result<DateTime<FixedOffset>, failure
         .map_err(|e| failure::format_err!("parse_from_rfc3339 failed.{:?}", e))
 }
 
+/// The first of the name_exts the name ends with and parses with wins.
 fn dir_entry_matches(
     dir: impl AsRef<Path>,
     dir_entry: io::Result<fs::DirEntry>,
     name_prefix: impl AsRef<str>,
-    name_ext_with_dot: impl AsRef<str>,
+    name_exts: &[&str],
 ) -> Result<Option<FileCopy>, failure::Error> {
     let dir = dir.as_ref();
     let dir_entry = dir_entry?;
     let name_prefix = name_prefix.as_ref();
-    let name_ext_with_dot = name_ext_with_dot.as_ref();
     if let Some(com) = dir_entry.path().strip_prefix(dir)?.components().next() {
         if let Component::Normal(d_name) = com {
             if let Some(d_name) = d_name.to_str() {
-                if d_name.starts_with(name_prefix) && d_name.ends_with(name_ext_with_dot) {
-                    let mut sn = d_name.splitn(2, name_prefix);
-                    sn.next();
-                    let strip_prefix = sn.next().expect("strip name_prefix should success.");
-                    let mut sn = strip_prefix.rsplitn(2, name_ext_with_dot);
-                    sn.next();
-                    let copy_trait = sn
-                        .next()
-                        .expect("strip name_ext_with_dot should success")
-                        .to_string();
-                    if let Ok(copy_trait) = parse_date_time(&copy_trait) {
+                if d_name.ends_with(archive_manifest::MANIFEST_POSTFIX) {
+                    // goes with it's archive.
+                } else if let Some(strip_prefix) = d_name.strip_prefix(name_prefix) {
+                    let copy_trait = name_exts.iter().find_map(|ext| {
+                        strip_prefix
+                            .strip_suffix(ext)
+                            .and_then(|s| parse_date_time(s).ok())
+                    });
+                    if let Some(copy_trait) = copy_trait {
                         let fc = FileCopy {
-                            dir_entry,
+                            path: dir_entry.path(),
+                            len: dir_entry.metadata().map(|m| m.len()).unwrap_or(0),
                             copy_trait: copy_trait.into(),
+                            bucket: None,
                         };
                         return Ok(Some(fc));
                     } else {
-                        warn!("parse_date_time failed.");
+                        warn!(
+                            "skip item in directory: {:?}, name_exts: {:?}",
+                            d_name, name_exts
+                        );
                     }
                 } else {
                     warn!(
-                        "skip item in directory: {:?}, name_prefix: {:?}, name_ext_with_dot: {:?}",
-                        d_name, name_prefix, name_ext_with_dot
+                        "skip item in directory: {:?}, name_prefix: {:?}, name_exts: {:?}",
+                        d_name, name_prefix, name_exts
                     );
                 }
             } else {
@@ -133,20 +265,16 @@ fn dir_entry_matches(
     Ok(None)
 }
 
+/// The copies of all the name_exts together, like the archives named before the postfix changed.
 fn get_file_copy_vec(
     dir: impl AsRef<Path>,
     name_prefix: impl AsRef<str>,
-    name_ext_with_dot: impl AsRef<str>,
+    name_exts: &[&str],
 ) -> Result<Vec<FileCopy>, failure::Error> {
     let rd = dir.as_ref().read_dir()?;
     let mut dir_entrys: Vec<FileCopy> = rd
         .filter_map(|et| {
-            match dir_entry_matches(
-                dir.as_ref(),
-                et,
-                name_prefix.as_ref(),
-                name_ext_with_dot.as_ref(),
-            ) {
+            match dir_entry_matches(dir.as_ref(), et, name_prefix.as_ref(), name_exts) {
                 Ok(dn) => dn,
                 Err(err) => {
                     error!("dir_entry_matches got error: {:?}", err);
@@ -159,29 +287,65 @@ fn get_file_copy_vec(
     Ok(dir_entrys)
 }
 
-/// dir_entrys were orderby time asc.
+/// Like get_file_copy_vec, but the names and lengths are listed from a remote storage.
+fn listing_file_copies(
+    listing: Vec<(String, u64)>,
+    name_prefix: &str,
+    name_ext_with_dot: &str,
+) -> Vec<FileCopy> {
+    let mut file_copies: Vec<FileCopy> = listing
+        .into_iter()
+        .filter(|(name, _)| {
+            !name.ends_with(archive_manifest::MANIFEST_POSTFIX)
+                && name.len() > name_prefix.len() + name_ext_with_dot.len()
+                && name.starts_with(name_prefix)
+                && name.ends_with(name_ext_with_dot)
+        })
+        .filter_map(|(name, len)| {
+            let copy_trait = &name[name_prefix.len()..name.len() - name_ext_with_dot.len()];
+            match parse_date_time(copy_trait) {
+                Ok(copy_trait) => Some(FileCopy {
+                    copy_trait: copy_trait.into(),
+                    path: PathBuf::from(name),
+                    len,
+                    bucket: None,
+                }),
+                Err(_) => {
+                    warn!("skip item in listing: {:?}", name);
+                    None
+                }
+            }
+        })
+        .collect();
+    file_copies.sort_unstable_by_key(|k| k.copy_trait);
+    file_copies
+}
+
+/// dir_entrys were orderby time asc. The periods are those in the timezone.
 fn group_file_copies(
     dir_entrys: Vec<FileCopy>,
     group_period: GroupPeriod,
+    tz: RetentionTimezone,
 ) -> HashMap<i32, Vec<FileCopy>> {
     dir_entrys
         .into_iter()
         .fold(HashMap::<i32, Vec<FileCopy>>::new(), |mut mp, fc| {
+            let dt = tz.local_time(&fc.copy_trait);
             let s = match &group_period {
-                GroupPeriod::Yearly => fc.copy_trait.year(),
-                GroupPeriod::Monthly => fc.copy_trait.month() as i32,
+                GroupPeriod::Yearly => dt.year(),
+                GroupPeriod::Monthly => dt.month() as i32,
                 GroupPeriod::Weekly => {
-                    let isow = fc.copy_trait.iso_week();
-                    let y = fc.copy_trait.year();
+                    let isow = dt.iso_week();
+                    let y = dt.year();
                     if y != isow.year() {
                         (isow.week() - 53) as i32
                     } else {
                         isow.week() as i32
                     }
                 }
-                GroupPeriod::Daily => fc.copy_trait.day() as i32,
-                GroupPeriod::Hourly => fc.copy_trait.hour() as i32,
-                GroupPeriod::Minutely => fc.copy_trait.minute() as i32,
+                GroupPeriod::Daily => dt.day() as i32,
+                GroupPeriod::Hourly => dt.hour() as i32,
+                GroupPeriod::Minutely => dt.minute() as i32,
             };
             mp.entry(s).or_insert_with(Vec::new).push(fc);
             mp
@@ -244,19 +408,27 @@ fn prune_period(
     (FileCopies { inner: file_group }, file_copies_to_delete)
 }
 
-fn prune_dir_result(
+fn in_bucket(mut file_copies: Vec<FileCopy>, bucket: RetentionBucket) -> Vec<FileCopy> {
+    for fc in file_copies.iter_mut() {
+        fc.bucket = Some(bucket);
+    }
+    file_copies
+}
+
+/// mv were orderby time asc.
+fn prune_file_copies(
     prune_strategy: &PruneStrategy,
-    dir: impl AsRef<Path>,
-    name_prefix: impl AsRef<str>,
-    name_ext_with_dot: impl AsRef<str>,
-) -> Result<(Vec<FileCopy>, Vec<FileCopy>), failure::Error> {
-    // order by created time asc.
-    let mv = get_file_copy_vec(dir, name_prefix, name_ext_with_dot)?;
+    tz: RetentionTimezone,
+    mv: Vec<FileCopy>,
+) -> (Vec<FileCopy>, Vec<FileCopy>) {
+    if mv.is_empty() {
+        return (Vec::new(), Vec::new());
+    }
 
     let mut all_to_delete: Vec<FileCopy> = Vec::new();
     let mut all_remains: Vec<FileCopy> = Vec::new();
 
-    let mp = group_file_copies(mv, GroupPeriod::Yearly); // yearly
+    let mp = group_file_copies(mv, GroupPeriod::Yearly, tz); // yearly
 
     let yearly_keep_num: usize = prune_strategy.yearly.try_into().unwrap();
     let weekly_keep_num: usize = prune_strategy.weekly.try_into().unwrap();
@@ -267,12 +439,15 @@ fn prune_dir_result(
 
     // to_remain may contains another years' data. how to identify this problem.
 
-    let (mut file_copies, mut to_delete) = prune_period(mp, yearly_keep_num, 0);
+    let (mut file_copies, to_delete) = prune_period(mp, yearly_keep_num, 0);
     let lastest_remain = file_copies
         .take_latest()
         .expect("yearly latest remains should't empty.");
-    all_remains.append(&mut file_copies.take_remains());
-    all_to_delete.append(&mut to_delete);
+    all_remains.append(&mut in_bucket(
+        file_copies.take_remains(),
+        RetentionBucket::Yearly,
+    ));
+    all_to_delete.append(&mut in_bucket(to_delete, RetentionBucket::Yearly));
     trace!(
         "yearly keep_num: {:?}, to_remain: {:?}, to_delete: {:?}",
         yearly_keep_num,
@@ -281,14 +456,17 @@ fn prune_dir_result(
     );
 
     let lastest_remain = if prune_strategy.weekly > 0 {
-        let mp = group_file_copies(lastest_remain, GroupPeriod::Weekly); // weekly
-        let (mut file_copies, mut to_delete) = prune_period(mp, weekly_keep_num, daily_keep_num);
+        let mp = group_file_copies(lastest_remain, GroupPeriod::Weekly, tz); // weekly
+        let (mut file_copies, to_delete) = prune_period(mp, weekly_keep_num, daily_keep_num);
 
         let lastest_remain = file_copies
             .take_latest()
             .expect("weekly latest remains should't empty.");
-        all_remains.append(&mut file_copies.take_remains());
-        all_to_delete.append(&mut to_delete);
+        all_remains.append(&mut in_bucket(
+            file_copies.take_remains(),
+            RetentionBucket::Weekly,
+        ));
+        all_to_delete.append(&mut in_bucket(to_delete, RetentionBucket::Weekly));
         trace!(
             "weekly keep_num: {:?}, to_remain: {:?}, to_delete: {:?}",
             weekly_keep_num,
@@ -297,13 +475,16 @@ fn prune_dir_result(
         );
         lastest_remain
     } else {
-        let mp = group_file_copies(lastest_remain, GroupPeriod::Monthly); //monthly
-        let (mut file_copies, mut to_delete) = prune_period(mp, monthly_keep_num, daily_keep_num);
+        let mp = group_file_copies(lastest_remain, GroupPeriod::Monthly, tz); //monthly
+        let (mut file_copies, to_delete) = prune_period(mp, monthly_keep_num, daily_keep_num);
         let lastest_remain = file_copies
             .take_latest()
             .expect("monthly latest remains should't empty.");
-        all_remains.append(&mut file_copies.take_remains());
-        all_to_delete.append(&mut to_delete);
+        all_remains.append(&mut in_bucket(
+            file_copies.take_remains(),
+            RetentionBucket::Monthly,
+        ));
+        all_to_delete.append(&mut in_bucket(to_delete, RetentionBucket::Monthly));
         trace!(
             "monthly keep_num: {:?}, to_remain {:?}, to_delete: {:?}",
             monthly_keep_num,
@@ -313,13 +494,16 @@ fn prune_dir_result(
         lastest_remain
     };
 
-    let mp = group_file_copies(lastest_remain, GroupPeriod::Daily); // daily
-    let (mut file_copies, mut to_delete) = prune_period(mp, daily_keep_num, hourly_keep_num);
+    let mp = group_file_copies(lastest_remain, GroupPeriod::Daily, tz); // daily
+    let (mut file_copies, to_delete) = prune_period(mp, daily_keep_num, hourly_keep_num);
     let lastest_remain = file_copies
         .take_latest()
         .expect("daily latest remains should't empty.");
-    all_remains.append(&mut file_copies.take_remains());
-    all_to_delete.append(&mut to_delete);
+    all_remains.append(&mut in_bucket(
+        file_copies.take_remains(),
+        RetentionBucket::Daily,
+    ));
+    all_to_delete.append(&mut in_bucket(to_delete, RetentionBucket::Daily));
     trace!(
         "daily keep_num: {:?}, to_remain: {:?}, to_delete: {:?}",
         daily_keep_num,
@@ -327,13 +511,16 @@ fn prune_dir_result(
         all_to_delete.len()
     );
 
-    let mp = group_file_copies(lastest_remain, GroupPeriod::Hourly); // hourly
-    let (mut file_copies, mut to_delete) = prune_period(mp, hourly_keep_num, minutely_keep_num);
+    let mp = group_file_copies(lastest_remain, GroupPeriod::Hourly, tz); // hourly
+    let (mut file_copies, to_delete) = prune_period(mp, hourly_keep_num, minutely_keep_num);
     let lastest_remain = file_copies
         .take_latest()
         .expect("hourly latest remains should't empty.");
-    all_remains.append(&mut file_copies.take_remains());
-    all_to_delete.append(&mut to_delete);
+    all_remains.append(&mut in_bucket(
+        file_copies.take_remains(),
+        RetentionBucket::Hourly,
+    ));
+    all_to_delete.append(&mut in_bucket(to_delete, RetentionBucket::Hourly));
     trace!(
         "hourly keep_num: {:?}, to_remain: {:?}, to_delete: {:?}",
         hourly_keep_num,
@@ -341,13 +528,16 @@ fn prune_dir_result(
         all_to_delete.len()
     );
 
-    let mp = group_file_copies(lastest_remain, GroupPeriod::Minutely); // minutely
-    let (mut file_copies, mut to_delete) = prune_period(mp, minutely_keep_num, 0);
-    let mut lastest_remain = file_copies
+    let mp = group_file_copies(lastest_remain, GroupPeriod::Minutely, tz); // minutely
+    let (mut file_copies, to_delete) = prune_period(mp, minutely_keep_num, 0);
+    let lastest_remain = file_copies
         .take_latest()
         .expect("hourly latest remains should't empty.");
-    all_remains.append(&mut file_copies.take_remains());
-    all_to_delete.append(&mut to_delete);
+    all_remains.append(&mut in_bucket(
+        file_copies.take_remains(),
+        RetentionBucket::Minutely,
+    ));
+    all_to_delete.append(&mut in_bucket(to_delete, RetentionBucket::Minutely));
     trace!(
         "hourly keep_num: {:?}, to_remain: {:?}, to_delete: {:?}",
         minutely_keep_num,
@@ -355,31 +545,308 @@ fn prune_dir_result(
         all_to_delete.len()
     );
 
-    all_remains.append(&mut lastest_remain);
+    all_remains.append(&mut in_bucket(lastest_remain, RetentionBucket::Minutely));
     all_remains.sort_unstable_by_key(|it| it.copy_trait);
-    Ok((all_remains, all_to_delete))
+    (all_remains, all_to_delete)
 }
 
 pub fn do_prune_dir(
     prune_strategy: &PruneStrategy,
+    tz: RetentionTimezone,
     dir: impl AsRef<Path>,
     name_prefix: impl AsRef<str>,
-    name_ext_with_dot: impl AsRef<str>,
+    name_exts: &[&str],
 ) -> Result<(), failure::Error> {
-    let (_to_remain, to_delete) =
-        prune_dir_result(prune_strategy, dir, name_prefix, name_ext_with_dot)?;
-    for d in to_delete {
-        let p = d.dir_entry.path();
-        if d.dir_entry.metadata()?.file_type().is_file() {
-            fs::remove_file(p)?;
+    for retention in plan_prune(prune_strategy, tz, dir, name_prefix, name_exts)? {
+        let p = retention.path;
+        if let Some(dependent) = retention.required_by {
+            info!("keep {:?}, the retained {} depends on it.", p, dependent);
+            continue;
+        }
+        if !retention.delete {
+            continue;
+        }
+        if p.metadata()?.file_type().is_file() {
+            fs::remove_file(&p)?;
         } else {
-            fs::remove_dir_all(p)?;
+            fs::remove_dir_all(&p)?;
+        }
+        let manifest = archive_manifest::manifest_path(&p);
+        if manifest.exists() {
+            fs::remove_file(manifest)?;
         }
     }
     Ok(())
 }
 
+impl ArchiveRetention {
+    /// Why it's kept or deleted, in terms of the prune_strategy.
+    pub fn explain(&self, prune_strategy: &PruneStrategy) -> String {
+        let rule = format!("{:?}", self.bucket).to_lowercase();
+        let keep_num = match self.bucket {
+            RetentionBucket::Yearly => prune_strategy.yearly,
+            RetentionBucket::Monthly => prune_strategy.monthly,
+            RetentionBucket::Weekly => prune_strategy.weekly,
+            RetentionBucket::Daily => prune_strategy.daily,
+            RetentionBucket::Hourly => prune_strategy.hourly,
+            RetentionBucket::Minutely => prune_strategy.minutely,
+        };
+        if let Some(evicted_by) = self.evicted_by {
+            let constraint = match evicted_by {
+                EvictionRule::MaxTotalBytes => format!(
+                    "max_total_bytes ({})",
+                    prune_strategy.max_total_bytes.unwrap_or_default()
+                ),
+                EvictionRule::MaxAgeDays => format!(
+                    "max_age_days ({})",
+                    prune_strategy.max_age_days.unwrap_or_default()
+                ),
+            };
+            format!(
+                "scheduled for deletion by {}, the {} rule alone would keep it",
+                constraint, rule
+            )
+        } else if let Some(dependent) = self.required_by.as_ref() {
+            format!(
+                "kept, {} depends on it, the {} rule alone would delete it",
+                dependent, rule
+            )
+        } else if self.delete {
+            format!(
+                "scheduled for deletion by the {} rule (keep {})",
+                rule, keep_num
+            )
+        } else {
+            format!("kept by the {} rule (keep {})", rule, keep_num)
+        }
+    }
+}
+
+/// What the next prune does to every archive in the directory, ordered by time asc.
+/// The archives of all the name_exts are pruned together.
+pub fn plan_prune(
+    prune_strategy: &PruneStrategy,
+    tz: RetentionTimezone,
+    dir: impl AsRef<Path>,
+    name_prefix: impl AsRef<str>,
+    name_exts: &[&str],
+) -> Result<Vec<ArchiveRetention>, failure::Error> {
+    let dir = dir.as_ref();
+    let file_copies = get_file_copy_vec(dir, name_prefix, name_exts)?;
+    let chain_of = |name: &str| {
+        if archive_manifest::manifest_path(&dir.join(name)).exists() {
+            Some(archive_manifest::dependency_chain(dir, name))
+        } else {
+            None
+        }
+    };
+    Ok(plan_file_copies(prune_strategy, tz, file_copies, &chain_of))
+}
+
+/// The dependency chain of an archive like archive_manifest::dependency_chain, None if it has no manifest.
+pub type ChainOf<'a> = dyn Fn(&str) -> Option<Result<Vec<String>, failure::Error>> + 'a;
+
+/// Like plan_prune, on the names and lengths listed from a remote storage, the paths are bare names.
+pub fn plan_prune_listing(
+    prune_strategy: &PruneStrategy,
+    tz: RetentionTimezone,
+    listing: Vec<(String, u64)>,
+    name_prefix: &str,
+    name_ext_with_dot: &str,
+    chain_of: &ChainOf,
+) -> Vec<ArchiveRetention> {
+    let file_copies = listing_file_copies(listing, name_prefix, name_ext_with_dot);
+    plan_file_copies(prune_strategy, tz, file_copies, chain_of)
+}
+
+fn plan_file_copies(
+    prune_strategy: &PruneStrategy,
+    tz: RetentionTimezone,
+    file_copies: Vec<FileCopy>,
+    chain_of: &ChainOf,
+) -> Vec<ArchiveRetention> {
+    let (to_remain, to_delete) = prune_file_copies(prune_strategy, tz, file_copies);
+    let names = to_remain.iter().filter_map(|fc| file_name_str(&fc.path));
+    let depended = depended_archives(names, chain_of);
+    let mut plan = Vec::new();
+    for (fc, delete) in to_remain
+        .into_iter()
+        .map(|fc| (fc, false))
+        .chain(to_delete.into_iter().map(|fc| (fc, true)))
+    {
+        let path = fc.path;
+        let required_by = if delete {
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| depended.get(n))
+                .cloned()
+        } else {
+            None
+        };
+        plan.push(ArchiveRetention {
+            len: fc.len,
+            time: fc.copy_trait,
+            bucket: fc.bucket.expect("bucket should be decided."),
+            delete: delete && required_by.is_none(),
+            required_by,
+            evicted_by: None,
+            path,
+        });
+    }
+    plan.sort_by_key(|r| r.time);
+    evict_by_constraints(&mut plan, prune_strategy, chain_of, Utc::now());
+    plan
+}
+
+fn file_name_str(path: &Path) -> Option<&str> {
+    path.file_name().and_then(|n| n.to_str())
+}
+
+/// Evict the oldest kept copies until max_total_bytes and max_age_days hold,
+/// skipping the copies a kept incremental archive depends on, and leaving at least min_keep.
+fn evict_by_constraints(
+    plan: &mut [ArchiveRetention],
+    prune_strategy: &PruneStrategy,
+    chain_of: &ChainOf,
+    now: DateTime<Utc>,
+) {
+    if prune_strategy.max_total_bytes.is_none() && prune_strategy.max_age_days.is_none() {
+        return;
+    }
+    let ancestors: HashMap<String, Vec<String>> = plan
+        .iter()
+        .filter(|r| !r.delete)
+        .filter_map(|r| {
+            let name = file_name_str(&r.path)?;
+            let chain = chain_of(name)?.ok()?;
+            Some((name.to_string(), chain.into_iter().skip(1).collect()))
+        })
+        .collect();
+    let max_age = prune_strategy
+        .max_age_days
+        .map(|days| chrono::Duration::days(i64::from(days)));
+
+    loop {
+        let kept: Vec<usize> = (0..plan.len()).filter(|i| !plan[*i].delete).collect();
+        if kept.len() <= prune_strategy.min_keep as usize {
+            break;
+        }
+        let total: u64 = kept.iter().map(|i| plan[*i].len).sum();
+        let over_size = prune_strategy
+            .max_total_bytes
+            .is_some_and(|max| total > max);
+        let is_protected = |i: usize| {
+            let name = file_name_str(&plan[i].path);
+            kept.iter().any(|j| {
+                *j != i
+                    && file_name_str(&plan[*j].path)
+                        .and_then(|n| ancestors.get(n))
+                        .is_some_and(|chain| chain.iter().any(|a| Some(a.as_str()) == name))
+            })
+        };
+        // kept is ordered by time asc, the oldest one goes first.
+        let candidate = kept.iter().cloned().find_map(|i| {
+            let too_old = max_age.is_some_and(|age| now - plan[i].time > age);
+            if (over_size || too_old) && !is_protected(i) {
+                Some((i, too_old))
+            } else {
+                None
+            }
+        });
+        match candidate {
+            Some((i, too_old)) => {
+                plan[i].delete = true;
+                plan[i].evicted_by = Some(if too_old {
+                    EvictionRule::MaxAgeDays
+                } else {
+                    EvictionRule::MaxTotalBytes
+                });
+            }
+            None => break,
+        }
+    }
+    release_orphans(plan, chain_of);
+}
+
+/// The copies kept only for a dependent that got evicted are deleted as their rule says,
+/// until every remaining required_by is a kept one.
+fn release_orphans(plan: &mut [ArchiveRetention], chain_of: &ChainOf) {
+    loop {
+        let kept = plan
+            .iter()
+            .filter(|r| !r.delete)
+            .filter_map(|r| file_name_str(&r.path));
+        let depended = depended_archives(kept, chain_of);
+        let mut released = false;
+        for r in plan
+            .iter_mut()
+            .filter(|r| !r.delete && r.required_by.is_some())
+        {
+            match file_name_str(&r.path)
+                .and_then(|n| depended.get(n))
+                .cloned()
+            {
+                Some(dependent) => r.required_by = Some(dependent),
+                None => {
+                    r.required_by = None;
+                    r.delete = true;
+                    released = true;
+                }
+            }
+        }
+        if !released {
+            break;
+        }
+    }
+}
+
+/// The archives the retained incremental ones depend on, to the name of the dependent.
+fn depended_archives<'a>(
+    retained: impl Iterator<Item = &'a str>,
+    chain_of: &ChainOf,
+) -> HashMap<String, String> {
+    let mut depended = HashMap::new();
+    for name in retained {
+        match chain_of(name) {
+            None => (),
+            Some(Ok(chain)) => {
+                for dependency in chain.into_iter().skip(1) {
+                    depended
+                        .entry(dependency)
+                        .or_insert_with(|| name.to_string());
+                }
+            }
+            Some(Err(err)) => warn!("can't follow the archive chain of {}: {}", name, err),
+        }
+    }
+    depended
+}
+
+/// The archives of all the name_exts in the directory, ordered by time asc.
+pub fn list_archive_files(
+    dir: impl AsRef<Path>,
+    name_prefix: impl AsRef<str>,
+    name_exts: &[&str],
+) -> Result<Vec<PathBuf>, failure::Error> {
+    Ok(get_file_copy_vec(dir, name_prefix, name_exts)?
+        .into_iter()
+        .map(|fc| fc.path)
+        .collect())
+}
+
+/// The latest archive to build the next incremental one on.
+pub fn latest_archive_manifest(
+    dir: impl AsRef<Path>,
+    name_prefix: impl AsRef<str>,
+    name_ext_with_dot: impl AsRef<str>,
+    full_every: u32,
+) -> Result<Option<ArchiveManifest>, failure::Error> {
+    let archives = list_archive_files(dir, name_prefix, &[name_ext_with_dot.as_ref()])?;
+    archive_manifest::previous_for_incremental(archives.last().map(PathBuf::as_path), full_every)
+}
+
 pub fn get_next_file_name(
+    tz: RetentionTimezone,
     dir: impl AsRef<Path>,
     name_prefix: impl AsRef<str>,
     name_ext_with_dot: impl AsRef<str>,
@@ -388,13 +855,14 @@ pub fn get_next_file_name(
     let name_stem = name_prefix.as_ref();
     let name_ext_with_dot = name_ext_with_dot.as_ref();
 
-    let s = format_dt(&Utc::now(), name_stem, name_ext_with_dot);
+    let s = format_dt(&Utc::now(), tz, name_stem, name_ext_with_dot);
     dir.join(&s)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::data_shape::archive_manifest::ArchiveKind;
     use crate::data_shape::PruneStrategyBuilder;
     use crate::develope::tutil;
     use crate::log_util;
@@ -419,8 +887,8 @@ mod tests {
         t_dir.make_a_file_with_content("abc_20130117120009.tar", "abc")?;
 
         let t_name = t_dir.tmp_dir_path();
-        let mv = get_file_copy_vec(t_name, "abc_", ".tar")?;
-        let mp = group_file_copies(mv, GroupPeriod::Weekly); // yearly result.
+        let mv = get_file_copy_vec(t_name, "abc_", &[".tar"])?;
+        let mp = group_file_copies(mv, GroupPeriod::Weekly, RetentionTimezone::Utc); // yearly result.
 
         let dt = Utc.ymd(2013, 1, 1).and_hms(1, 1, 55);
         assert_eq!(dt.iso_week().year(), 2013);
@@ -440,6 +908,13 @@ mod tests {
         Ok(())
     }
 
+    /// The kept and the deleted of a plan, both ordered by time asc.
+    fn kept_and_deleted(
+        plan: Vec<ArchiveRetention>,
+    ) -> (Vec<ArchiveRetention>, Vec<ArchiveRetention>) {
+        plan.into_iter().partition(|r| !r.delete)
+    }
+
     #[test]
     fn t_chrono() -> Result<(), failure::Error> {
         log();
@@ -449,7 +924,10 @@ mod tests {
         let dt_parsed = DateTime::parse_from_rfc3339("2014-11-28T12:00:09Z")?;
         assert_eq!(dt, dt_parsed);
 
-        assert_eq!(format_dt(&dt, "", "").to_string(), "20141128120009");
+        assert_eq!(
+            format_dt(&dt, RetentionTimezone::Utc, "", "").to_string(),
+            "20141128120009"
+        );
         let ss = "20141128120009";
         assert_eq!(ss.len(), 14);
         let s = format!(
@@ -462,6 +940,11 @@ mod tests {
             &ss[12..14]
         );
         assert_eq!(s, "2014-11-28T12:00:09Z");
+        assert_eq!(parse_date_time(ss)?, dt);
+        assert_eq!(parse_date_time("20141128200009+0800")?, dt);
+        // multibyte chars don't panic on the char boundaries.
+        assert!(parse_date_time("2014112812000日+00").is_err());
+        assert!(parse_date_time("2014日1128120").is_err());
 
         assert_eq!(dt.year(), 2014);
         assert_eq!(dt.month(), 11);
@@ -473,18 +956,65 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn t_retention_timezone() -> Result<(), failure::Error> {
+        log();
+        let tz: RetentionTimezone = serde_yaml::from_str("\"+08:00\"")?;
+        assert_eq!(tz, RetentionTimezone::Fixed(FixedOffset::east(8 * 3600)));
+        assert_eq!(
+            "-0530".parse(),
+            Ok(RetentionTimezone::Fixed(FixedOffset::west(5 * 3600 + 1800)))
+        );
+        assert_eq!("local".parse(), Ok(RetentionTimezone::Local));
+        assert!("+8".parse::<RetentionTimezone>().is_err());
+        assert_eq!(
+            serde_yaml::to_string(&tz)?.trim_start_matches("---").trim(),
+            "\"+08:00\""
+        );
+
+        // 23:30 and 00:30 of the next day in +08:00, the same day in utc.
+        let before = Utc.ymd(2014, 11, 28).and_hms(15, 30, 0);
+        let after = Utc.ymd(2014, 11, 28).and_hms(16, 30, 0);
+        let name = format_dt(&after, tz, "abc_", ".tar");
+        assert_eq!(name, "abc_20141129003000+0800.tar");
+        // the utc one named before the timezone is configured.
+        let t_dir = tutil::create_a_dir_and_a_file_with_content(
+            format_dt(&before, RetentionTimezone::Utc, "abc_", ".tar"),
+            "abc",
+        )?;
+        t_dir.make_a_file_with_content(&name, "abc")?;
+        let dir = t_dir.tmp_dir_path();
+
+        let times: Vec<DateTime<Utc>> = get_file_copy_vec(dir, "abc_", &[".tar"])?
+            .into_iter()
+            .map(|fc| fc.copy_trait)
+            .collect();
+        assert_eq!(times, vec![before, after]);
+        let days = |tz| {
+            group_file_copies(
+                get_file_copy_vec(dir, "abc_", &[".tar"]).unwrap(),
+                GroupPeriod::Daily,
+                tz,
+            )
+            .len()
+        };
+        assert_eq!(days(RetentionTimezone::Utc), 1);
+        assert_eq!(days(tz), 2);
+        Ok(())
+    }
+
     #[test]
     fn t_rolling_file_1() -> Result<(), failure::Error> {
         log();
         let dt = Utc.ymd(2014, 11, 28).and_hms(12, 0, 9);
-        let file_name = format_dt(&dt, "abc_", ".tar");
+        let file_name = format_dt(&dt, RetentionTimezone::Utc, "abc_", ".tar");
         let t_dir = tutil::create_a_dir_and_a_file_with_content(&file_name, "abc")?;
         let t_name = t_dir.tmp_dir_path();
         let de = dir_entry_matches(
             t_name,
             t_name.read_dir()?.next().expect("at least has one."),
             "abc_",
-            ".tar",
+            &[".tar"],
         )?
         .expect("result has some.");
         assert_eq!(de.copy_trait, dt);
@@ -493,7 +1023,16 @@ mod tests {
             t_name,
             t_name.read_dir()?.next().expect("at least has one."),
             "abc_",
-            "",
+            &[".tar.gz", ".tar"],
+        )?
+        .expect("the second one matches.");
+        assert_eq!(de.copy_trait, dt);
+
+        let de = dir_entry_matches(
+            t_name,
+            t_name.read_dir()?.next().expect("at least has one."),
+            "abc_",
+            &[""],
         )?;
         assert!(de.is_none());
 
@@ -501,7 +1040,7 @@ mod tests {
             t_name,
             t_name.read_dir()?.next().expect("at least has one."),
             "",
-            "",
+            &[""],
         )?;
         assert!(de.is_none());
 
@@ -509,7 +1048,7 @@ mod tests {
             t_name,
             t_name.read_dir()?.next().expect("at least has one."),
             "",
-            "xx",
+            &["xx"],
         )?;
         assert!(de.is_none());
         Ok(())
@@ -531,8 +1070,8 @@ mod tests {
         assert_eq!(t_dir.tmp_dir_path().read_dir()?.count(), 9);
 
         let t_name = t_dir.tmp_dir_path();
-        let mv = get_file_copy_vec(t_name, "abc_", ".tar")?;
-        let mp = group_file_copies(mv, GroupPeriod::Yearly); // yearly result.
+        let mv = get_file_copy_vec(t_name, "abc_", &[".tar"])?;
+        let mp = group_file_copies(mv, GroupPeriod::Yearly, RetentionTimezone::Utc); // yearly result.
         info!("{:?}", mp);
         assert_eq!(mp.keys().len(), 3);
 
@@ -573,7 +1112,7 @@ mod tests {
 
         let _mv = mp.get(&2013).map(|v| {
             if let Some((_last, elements)) = v.split_last() {
-                elements.iter().for_each(|vv| info!("{:?}", vv.dir_entry));
+                elements.iter().for_each(|vv| info!("{:?}", vv.path));
             }
             Option::<u8>::None
         });
@@ -665,7 +1204,7 @@ mod tests {
             };
 
             days.iter().for_each(|dt| {
-                let s = format_dt(dt, &self.prefix, &self.postfix);
+                let s = format_dt(dt, RetentionTimezone::Utc, &self.prefix, &self.postfix);
                 self.t_dir
                     .make_a_file_with_content(&s, "a")
                     .expect("make_a_file_with_content should success.");
@@ -694,18 +1233,19 @@ mod tests {
             .build()
             .map_err(failure::err_msg)?;
 
-        let (to_remain, to_delete) = prune_dir_result(
+        let (to_remain, to_delete) = kept_and_deleted(plan_prune(
             &prune_strategy,
+            RetentionTimezone::Utc,
             fcg.t_dir.tmp_dir_path(),
-            fcg.prefix,
-            fcg.postfix,
-        )?;
+            &fcg.prefix,
+            &[fcg.postfix.as_str()],
+        )?);
         info!("to_remain: {:?}, to_delete: {:?}", to_remain, to_delete);
         assert_eq!(to_remain.len(), 3);
         assert_eq!(to_delete.len(), 6);
         let fc = to_remain.get(0).expect("remain one.");
-        assert_eq!(fc.copy_trait.year(), 2015);
-        assert_eq!(fc.copy_trait.iso_week().week(), 3); // the week 1 of year may span to previous year.
+        assert_eq!(fc.time.year(), 2015);
+        assert_eq!(fc.time.iso_week().week(), 3); // the week 1 of year may span to previous year.
         Ok(())
     }
 
@@ -733,14 +1273,19 @@ mod tests {
             .build()
             .map_err(failure::err_msg)?;
 
-        let (to_remain, to_delete) =
-            prune_dir_result(&p, fcg.t_dir.tmp_dir_path(), fcg.prefix, fcg.postfix)?;
+        let (to_remain, to_delete) = kept_and_deleted(plan_prune(
+            &p,
+            RetentionTimezone::Utc,
+            fcg.t_dir.tmp_dir_path(),
+            &fcg.prefix,
+            &[fcg.postfix.as_str()],
+        )?);
         info!("to_remain: {:?}, to_delete: {:?}", to_remain, to_delete);
         assert_eq!(to_remain.len(), 1);
         assert_eq!(to_delete.len(), 2);
         let fc = to_remain.get(0).expect("remain one.");
-        assert_eq!(fc.copy_trait.year(), 2015);
-        assert_eq!(fc.copy_trait.month(), 3);
+        assert_eq!(fc.time.year(), 2015);
+        assert_eq!(fc.time.month(), 3);
 
         Ok(())
     }
@@ -769,8 +1314,13 @@ mod tests {
             .map_err(failure::err_msg)?;
         info!("prune_strategy: {:?}", p);
 
-        let (to_remain, to_delete) =
-            prune_dir_result(&p, fcg.t_dir.tmp_dir_path(), fcg.prefix, fcg.postfix)?;
+        let (to_remain, to_delete) = kept_and_deleted(plan_prune(
+            &p,
+            RetentionTimezone::Utc,
+            fcg.t_dir.tmp_dir_path(),
+            &fcg.prefix,
+            &[fcg.postfix.as_str()],
+        )?);
         info!("to_remain:");
         for tr in &to_remain {
             info!("{:?}", tr);
@@ -783,14 +1333,14 @@ mod tests {
         assert_eq!(to_remain.len(), 4);
         assert_eq!(to_delete.len(), 17);
         let fc = to_remain.last().expect("remain one.");
-        assert_eq!(fc.copy_trait.year(), 2015);
-        assert_eq!(fc.copy_trait.month(), 3);
-        assert_eq!(fc.copy_trait.day(), 7);
+        assert_eq!(fc.time.year(), 2015);
+        assert_eq!(fc.time.month(), 3);
+        assert_eq!(fc.time.day(), 7);
 
         let fc = to_remain.first().expect("remain one.");
-        assert_eq!(fc.copy_trait.year(), 2015);
-        assert_eq!(fc.copy_trait.month(), 2);
-        assert_eq!(fc.copy_trait.day(), 7);
+        assert_eq!(fc.time.year(), 2015);
+        assert_eq!(fc.time.month(), 2);
+        assert_eq!(fc.time.day(), 7);
         Ok(())
     }
 
@@ -820,8 +1370,13 @@ mod tests {
             .map_err(failure::err_msg)?;
         info!("prune_strategy: {:?}", p);
 
-        let (to_remain, to_delete) =
-            prune_dir_result(&p, fcg.t_dir.tmp_dir_path(), fcg.prefix, fcg.postfix)?;
+        let (to_remain, to_delete) = kept_and_deleted(plan_prune(
+            &p,
+            RetentionTimezone::Utc,
+            fcg.t_dir.tmp_dir_path(),
+            &fcg.prefix,
+            &[fcg.postfix.as_str()],
+        )?);
         info!("to_remain:");
         for tr in &to_remain {
             info!("{:?}", tr);
@@ -834,16 +1389,16 @@ mod tests {
         assert_eq!(to_remain.len(), 5); // because we don't touch the content of the latest day.
         assert_eq!(to_delete.len(), 58);
         let fc = to_remain.last().expect("remain one.");
-        assert_eq!(fc.copy_trait.year(), 2015);
-        assert_eq!(fc.copy_trait.month(), 3);
-        assert_eq!(fc.copy_trait.day(), 7);
-        assert_eq!(fc.copy_trait.hour(), 3);
+        assert_eq!(fc.time.year(), 2015);
+        assert_eq!(fc.time.month(), 3);
+        assert_eq!(fc.time.day(), 7);
+        assert_eq!(fc.time.hour(), 3);
 
         let fc = to_remain.first().expect("remain one.");
-        assert_eq!(fc.copy_trait.year(), 2015);
-        assert_eq!(fc.copy_trait.month(), 2);
-        assert_eq!(fc.copy_trait.day(), 7);
-        assert_eq!(fc.copy_trait.hour(), 3);
+        assert_eq!(fc.time.year(), 2015);
+        assert_eq!(fc.time.month(), 2);
+        assert_eq!(fc.time.day(), 7);
+        assert_eq!(fc.time.hour(), 3);
         Ok(())
     }
 
@@ -872,8 +1427,155 @@ mod tests {
             .build()
             .map_err(failure::err_msg)?;
         info!("prune_strategy: {:?}", p);
-        do_prune_dir(&p, fcg.t_dir.tmp_dir_path(), &fcg.prefix, &fcg.postfix)?;
+        do_prune_dir(
+            &p,
+            RetentionTimezone::Utc,
+            fcg.t_dir.tmp_dir_path(),
+            &fcg.prefix,
+            &[fcg.postfix.as_str()],
+        )?;
         assert_eq!(fcg.t_dir.count_files(), 5);
         Ok(())
     }
+
+    #[test]
+    fn t_prune_keeps_archive_chain() -> Result<(), failure::Error> {
+        log();
+        let t_dir = tutil::create_a_dir_and_a_file_with_content("abc_20121201000000.tar", "abc")?;
+        let dir = t_dir.tmp_dir_path();
+        let names = [
+            "abc_20130101000000.tar",
+            "abc_20130102000000.tar",
+            "abc_20130103000000.tar",
+        ];
+        for (i, name) in names.iter().enumerate() {
+            let archive = t_dir.make_a_file_with_content(name, "abc")?;
+            ArchiveManifest {
+                archive: name.to_string(),
+                kind: if i == 0 {
+                    ArchiveKind::Full
+                } else {
+                    ArchiveKind::Incremental
+                },
+                parent: if i == 0 {
+                    None
+                } else {
+                    Some(names[i - 1].to_string())
+                },
+                chain_len: i as u32,
+                files: Vec::new(),
+                deleted: Vec::new(),
+            }
+            .save(&archive)?;
+        }
+        // without the chain only the latest one remains.
+        let p = PruneStrategyBuilder::default()
+            .build()
+            .map_err(failure::err_msg)?;
+        let plan = plan_prune(&p, RetentionTimezone::Utc, dir, "abc_", &[".tar"])?;
+        assert_eq!(plan.len(), 4);
+        assert!(plan[0].delete && plan[0].bucket == RetentionBucket::Yearly);
+        assert_eq!(plan[1].required_by.as_deref(), Some(names[2]));
+        assert!(!plan[1].delete && plan[1].bucket == RetentionBucket::Daily);
+        assert!(!plan[3].delete && plan[3].bucket == RetentionBucket::Minutely);
+        assert_eq!(
+            plan[0].explain(&p),
+            "scheduled for deletion by the yearly rule (keep 1)"
+        );
+        assert!(plan[1]
+            .explain(&p)
+            .starts_with("kept, abc_20130103000000.tar depends on it"));
+        assert!(serde_json::to_string(&plan[3])?.contains("\"bucket\":\"minutely\""));
+        do_prune_dir(&p, RetentionTimezone::Utc, dir, "abc_", &[".tar"])?;
+        assert_eq!(t_dir.count_files(), 6);
+        assert!(!dir.join("abc_20121201000000.tar").exists());
+
+        fs::remove_file(archive_manifest::manifest_path(&dir.join(names[2])))?;
+        do_prune_dir(&p, RetentionTimezone::Utc, dir, "abc_", &[".tar"])?;
+        assert_eq!(t_dir.count_files(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn t_prune_constraints() -> Result<(), failure::Error> {
+        log();
+        let t_dir = tutil::create_a_dir_and_a_file_with_content("abc_20130101000000.tar", "abc")?;
+        let dir = t_dir.tmp_dir_path();
+        for name in &[
+            "abc_20130102000000.tar",
+            "abc_20130103000000.tar",
+            "abc_20130104000000.tar",
+        ] {
+            t_dir.make_a_file_with_content(name, "abc")?;
+        }
+        for (name, parent) in &[
+            ("abc_20130102000000.tar", None),
+            ("abc_20130103000000.tar", Some("abc_20130102000000.tar")),
+        ] {
+            ArchiveManifest {
+                archive: name.to_string(),
+                kind: if parent.is_none() {
+                    ArchiveKind::Full
+                } else {
+                    ArchiveKind::Incremental
+                },
+                parent: parent.map(str::to_string),
+                chain_len: if parent.is_none() { 0 } else { 1 },
+                files: Vec::new(),
+                deleted: Vec::new(),
+            }
+            .save(&dir.join(name))?;
+        }
+        let deleted = |plan: &[ArchiveRetention]| -> Vec<String> {
+            plan.iter()
+                .filter(|r| r.delete)
+                .map(|r| r.path.file_name().unwrap().to_string_lossy().to_string())
+                .collect()
+        };
+
+        let p = PruneStrategyBuilder::default()
+            .daily(5)
+            .max_total_bytes(Some(7_u64))
+            .build()
+            .map_err(failure::err_msg)?;
+        let plan = plan_prune(&p, RetentionTimezone::Utc, dir, "abc_", &[".tar"])?;
+        // 0102 is skipped while 0103 depends on it.
+        assert_eq!(
+            deleted(&plan),
+            vec!["abc_20130101000000.tar", "abc_20130103000000.tar"]
+        );
+        assert_eq!(plan[0].evicted_by, Some(EvictionRule::MaxTotalBytes));
+        assert!(plan[0]
+            .explain(&p)
+            .starts_with("scheduled for deletion by max_total_bytes (7)"));
+
+        // 0102 is kept only for 0103, it goes when 0103 is evicted.
+        let p = PruneStrategyBuilder::default()
+            .daily(2)
+            .max_total_bytes(Some(7_u64))
+            .build()
+            .map_err(failure::err_msg)?;
+        let plan = plan_prune(&p, RetentionTimezone::Utc, dir, "abc_", &[".tar"])?;
+        assert_eq!(
+            deleted(&plan),
+            vec![
+                "abc_20130101000000.tar",
+                "abc_20130102000000.tar",
+                "abc_20130103000000.tar"
+            ]
+        );
+        assert!(plan[1].required_by.is_none());
+        assert_eq!(plan[2].evicted_by, Some(EvictionRule::MaxTotalBytes));
+
+        let p = PruneStrategyBuilder::default()
+            .daily(5)
+            .max_age_days(Some(1_u32))
+            .min_keep(3_u32)
+            .build()
+            .map_err(failure::err_msg)?;
+        let plan = plan_prune(&p, RetentionTimezone::Utc, dir, "abc_", &[".tar"])?;
+        assert_eq!(deleted(&plan), vec!["abc_20130101000000.tar"]);
+        assert_eq!(plan[0].evicted_by, Some(EvictionRule::MaxAgeDays));
+        Ok(())
+    }
 }
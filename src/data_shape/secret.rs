@@ -0,0 +1,167 @@
+//! Secret values of the yml files. They are resolved at load time and never printed.
+//! A value can be a literal, `${env:NAME}`, `file:/path/to/file` or `${secret:KEY}`,
+//! the last one is looked up in the secrets file.
+use log::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::path::Path;
+use std::{env, fmt, fs};
+
+pub const SECRETS_FILE_NAME: &str = "secrets.yml";
+
+const REDACTED: &str = "******";
+
+#[derive(Clone, Default, PartialEq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Secret(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Replace the reference with the value it points to, a literal stays as is.
+    pub fn resolve(&mut self, secrets: &Secrets) -> Result<(), failure::Error> {
+        let value = self.0.trim();
+        let resolved = if let Some(name) = strip_braces(value, "${env:") {
+            match env::var(name) {
+                Ok(v) => v,
+                Err(err) => bail!("resolve secret from env {} failed, {:?}", name, err),
+            }
+        } else if let Some(key) = strip_braces(value, "${secret:") {
+            secrets.get(key)?.to_string()
+        } else if let Some(file) = value.strip_prefix("file:") {
+            match fs::read_to_string(file) {
+                Ok(content) => content.trim_end_matches(&['\r', '\n'][..]).to_string(),
+                Err(err) => bail!("resolve secret from file {} failed, {:?}", file, err),
+            }
+        } else {
+            return Ok(());
+        };
+        self.0 = resolved;
+        Ok(())
+    }
+}
+
+fn strip_braces<'a>(value: &'a str, prefix: &str) -> Option<&'a str> {
+    value.strip_prefix(prefix)?.strip_suffix('}')
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({})", REDACTED)
+    }
+}
+
+/// Always redacted, so a serialized config can't leak it.
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.is_empty() {
+            serializer.serialize_str("")
+        } else {
+            serializer.serialize_str(REDACTED)
+        }
+    }
+}
+
+/// `password: ~` is an empty secret.
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Secret(
+            Option::<String>::deserialize(deserializer)?.unwrap_or_default(),
+        ))
+    }
+}
+
+/// A yml map of key to secret value, readable by the owner only.
+#[derive(Default)]
+pub struct Secrets {
+    values: HashMap<String, String>,
+}
+
+impl Secrets {
+    /// A missing file is an empty secrets.
+    pub fn load(file: impl AsRef<Path>) -> Result<Self, failure::Error> {
+        let file = file.as_ref();
+        if !file.exists() {
+            return Ok(Secrets::default());
+        }
+        warn_if_readable_by_others(file);
+        let content = fs::read_to_string(file)?;
+        match serde_yaml::from_str::<HashMap<String, String>>(&content) {
+            Ok(values) => Ok(Secrets { values }),
+            Err(err) => bail!("parse secrets file {:?} failed: {}", file, err),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Result<&str, failure::Error> {
+        match self.values.get(key) {
+            Some(v) => Ok(v.as_str()),
+            None => bail!("no secret named {} in the secrets file.", key),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn warn_if_readable_by_others(file: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Ok(meta) = file.metadata() {
+        if meta.permissions().mode() & 0o077 != 0 {
+            warn!(
+                "secrets file {:?} is accessible by others, chmod 600 it.",
+                file
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_readable_by_others(_file: &Path) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::develope::tutil;
+
+    #[test]
+    fn t_secret_resolve() -> Result<(), failure::Error> {
+        let tu = tutil::TestDir::new();
+        let secrets_file = tu.make_a_file_with_content("secrets.yml", "mail: from-secrets")?;
+        let secrets = Secrets::load(&secrets_file)?;
+        let password_file = tu.make_a_file_with_content("pass.txt", "from-file\n")?;
+        env::set_var("BK_T_SECRET", "from-env");
+
+        let mut s: Secret = serde_yaml::from_str("\"${env:BK_T_SECRET}\"")?;
+        s.resolve(&secrets)?;
+        assert_eq!(s.expose(), "from-env");
+
+        let mut s = Secret::new("${secret:mail}");
+        s.resolve(&secrets)?;
+        assert_eq!(s.expose(), "from-secrets");
+
+        let mut s = Secret::new(format!("file:{}", password_file.to_str().unwrap()));
+        s.resolve(&secrets)?;
+        assert_eq!(s.expose(), "from-file");
+
+        let mut s = Secret::new("plain");
+        s.resolve(&secrets)?;
+        assert_eq!(s.expose(), "plain");
+
+        assert!(Secret::new("${secret:none}").resolve(&secrets).is_err());
+
+        let s: Secret = serde_yaml::from_str("~")?;
+        assert!(s.is_empty());
+
+        let printed = serde_yaml::to_string(&Secret::new("from-env"))?;
+        assert!(!printed.contains("from-env"));
+        assert!(!format!("{:?}", Secret::new("from-env")).contains("from-env"));
+        Ok(())
+    }
+}
@@ -1,34 +1,180 @@
+use super::archive_crypt::{ArchiveEncryption, EncryptWriter, ENCRYPTED_POSTFIX};
+use super::archive_manifest::{self, ArchiveKind, ArchiveManifest, IncrementalArchive};
+use super::archive_verify::{self, ArchiveVerification};
+use super::freshness::{self, Freshness, MaxBackupAge};
+use super::metrics::ServerMetrics;
+use super::offsite::OffsiteTarget;
+use super::ssh_config::{self, SshConfig};
+use super::sync_hook::{self, HookResult, HookStage, SERVER_SCOPE};
+use super::sync_log::{FileRecord, SyncLog};
+use super::sync_report::{self, changed_reason, SyncDirection, SyncReport};
+use super::work_lock::WorkLock;
 use super::{
-    app_conf, rolling_files, AppRole, AuthMethod, Directory, FileChanged, FullPathFileItem,
-    Indicator, MiniAppConf, PbProperties, ProgressWriter, PruneStrategy, ScheduleItem, SlashPath,
+    app_conf, data_shape_util, rolling_files, AppRole, AuthMethod, Directory, FileChanged,
+    FinishWrite, FullPathFileItem, HostKeyCheck, Indicator, MiniAppConf, PassphraseSource,
+    PbProperties, ProgressWriter, PruneStrategy, ScheduleItem, Secret, Secrets, SlashPath,
     TransferFileProgressBar,
 };
 use crate::actions::{copy_a_file_sftp, ssh_util};
-use crate::db_accesses::SqliteDbAccess;
+use crate::db_accesses::{scheduler_util, DbAccess, FileVersionInDb, SqliteDbAccess};
 use crate::protocol::{MessageHub, SshChannelMessageHub, StringMessage, TransferType, U64Message};
+use bzip2::read::BzDecoder;
 use bzip2::write::BzEncoder;
 use bzip2::Compression;
-use chrono::Local;
+use chrono::{Local, TimeZone};
 use encoding_rs::*;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
 use indicatif::ProgressStyle;
 use log::*;
 use r2d2_sqlite::SqliteConnectionManager;
 use serde::{Deserialize, Serialize};
 use ssh2;
+use std::collections::HashSet;
 use std::ffi::OsString;
 use std::io::prelude::Read;
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::Duration;
 use std::{fs, io, io::Write};
 use tar::Builder;
+use xz2::read::XzDecoder;
+use xz2::stream::{Check, MtStreamBuilder};
+use xz2::write::XzEncoder;
 
 pub const CRON_NAME_SYNC_PULL_DIRS: &str = "sync-pull-dirs";
 
-#[derive(Deserialize, Debug, Serialize)]
+#[derive(Deserialize, Debug, Serialize, Clone, PartialEq)]
 #[serde(rename_all(deserialize = "snake_case"))]
 pub enum CompressionImpl {
     Bzip2,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl CompressionImpl {
+    /// The archive file name follows the codec.
+    pub fn archive_postfix(&self) -> &'static str {
+        match self {
+            CompressionImpl::Bzip2 => ".tar.bz2",
+            CompressionImpl::Gzip => ".tar.gz",
+            CompressionImpl::Zstd => ".tar.zst",
+            CompressionImpl::Xz => ".tar.xz",
+        }
+    }
+
+    pub fn decoder<'a, R: io::Read + 'a>(
+        &self,
+        r: R,
+    ) -> Result<Box<dyn io::Read + 'a>, failure::Error> {
+        Ok(match self {
+            CompressionImpl::Bzip2 => Box::new(BzDecoder::new(r)),
+            CompressionImpl::Gzip => Box::new(GzDecoder::new(r)),
+            CompressionImpl::Zstd => Box::new(zstd::Decoder::new(r)?),
+            CompressionImpl::Xz => Box::new(XzDecoder::new(r)),
+        })
+    }
+
+    /// bzip2 was always compressed with the best level, keep it.
+    fn default_level(&self) -> u32 {
+        match self {
+            CompressionImpl::Bzip2 => 9,
+            CompressionImpl::Gzip => 6,
+            CompressionImpl::Zstd => 3,
+            CompressionImpl::Xz => 6,
+        }
+    }
+
+    fn level_range(&self) -> (u32, u32) {
+        match self {
+            CompressionImpl::Bzip2 => (1, 9),
+            CompressionImpl::Zstd => (1, 22),
+            CompressionImpl::Gzip | CompressionImpl::Xz => (0, 9),
+        }
+    }
+
+    /// Wrap the writer with the encoder, threads only work for zstd and xz.
+    /// Call finish_write to end the stream, zstd doesn't finish it when dropped.
+    pub fn encoder<'a, W: FinishWrite + 'a>(
+        &self,
+        w: W,
+        level: Option<u32>,
+        threads: Option<u32>,
+    ) -> Result<Box<dyn FinishWrite + 'a>, failure::Error> {
+        let level = level.unwrap_or_else(|| self.default_level());
+        let (min, max) = self.level_range();
+        if level < min || level > max {
+            bail!(
+                "compress_level of {:?} should between {} and {}, but got {}.",
+                self,
+                min,
+                max,
+                level
+            );
+        }
+        let threads = threads.unwrap_or(1);
+        if threads > 1 {
+            if let CompressionImpl::Bzip2 | CompressionImpl::Gzip = self {
+                warn!("{:?} doesn't support compress_threads, ignored.", self);
+            }
+        }
+        Ok(match self {
+            CompressionImpl::Bzip2 => {
+                let compression = match level {
+                    1..=3 => Compression::Fastest,
+                    4..=7 => Compression::Default,
+                    _ => Compression::Best,
+                };
+                Box::new(BzEncoder::new(w, compression))
+            }
+            CompressionImpl::Gzip => Box::new(GzEncoder::new(w, flate2::Compression::new(level))),
+            CompressionImpl::Zstd => {
+                let mut encoder = zstd::Encoder::new(w, level as i32)?;
+                if threads > 1 {
+                    encoder.multithread(threads)?;
+                }
+                Box::new(encoder)
+            }
+            CompressionImpl::Xz => {
+                let stream = MtStreamBuilder::new()
+                    .threads(threads)
+                    .preset(level)
+                    .check(Check::Crc64)
+                    .encoder()?;
+                Box::new(XzEncoder::new_stream(w, stream))
+            }
+        })
+    }
+}
+
+impl<W: FinishWrite> FinishWrite for BzEncoder<W> {
+    fn finish_write(&mut self) -> io::Result<()> {
+        self.try_finish()?;
+        self.get_mut().finish_write()
+    }
+}
+
+impl<W: FinishWrite> FinishWrite for GzEncoder<W> {
+    fn finish_write(&mut self) -> io::Result<()> {
+        self.try_finish()?;
+        self.get_mut().finish_write()
+    }
+}
+
+impl<W: FinishWrite> FinishWrite for zstd::Encoder<W> {
+    fn finish_write(&mut self) -> io::Result<()> {
+        self.do_finish()?;
+        self.get_mut().finish_write()
+    }
+}
+
+impl<W: FinishWrite> FinishWrite for XzEncoder<W> {
+    fn finish_write(&mut self) -> io::Result<()> {
+        self.try_finish()?;
+        self.get_mut().finish_write()
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -39,22 +185,145 @@ pub struct RsyncConfig {
     pub delta_ext: String,
 }
 
+fn default_ssh_port() -> u16 {
+    22
+}
+
+fn default_hook_timeout_secs() -> u64 {
+    3600
+}
+
+/// JumpHost and ServerYml name their ssh settings alike, both make the endpoint by this.
+macro_rules! ssh_endpoint {
+    ($conf:expr, $pin_file:expr) => {
+        ssh_util::SshEndpoint {
+            host: $conf.host.as_str(),
+            port: $conf.port,
+            connect_timeout: $conf.connect_timeout.map(Duration::from_secs),
+            host_key_policy: ssh_util::HostKeyPolicy {
+                check: &$conf.host_key_check,
+                known_hosts: $conf.known_hosts.as_ref().map(Path::new),
+                pin_file: $pin_file,
+            },
+            credentials: ssh_util::SshCredentials {
+                username: $conf.username.as_str(),
+                password: $conf.password.expose(),
+                id_rsa: $conf.id_rsa.as_str(),
+                id_rsa_pub: $conf.id_rsa_pub.as_deref(),
+                id_rsa_passphrase: $conf.id_rsa_passphrase.as_ref(),
+            },
+            auth_methods: if $conf.auth_methods.is_empty() {
+                std::slice::from_ref(&$conf.auth_method)
+            } else {
+                $conf.auth_methods.as_slice()
+            },
+        }
+    };
+}
+
+/// A bastion the session is tunnelled through, it has it's own auth settings.
+#[derive(Deserialize, Serialize)]
+pub struct JumpHost {
+    pub host: String,
+    #[serde(default = "default_ssh_port")]
+    pub port: u16,
+    pub username: String,
+    #[serde(default)]
+    pub password: Secret,
+    #[serde(default)]
+    pub id_rsa: String,
+    #[serde(default)]
+    pub id_rsa_pub: Option<String>,
+    #[serde(default)]
+    pub id_rsa_passphrase: Option<PassphraseSource>,
+    pub auth_method: AuthMethod,
+    #[serde(default)]
+    pub auth_methods: Vec<AuthMethod>,
+    #[serde(default)]
+    pub known_hosts: Option<String>,
+    #[serde(default)]
+    pub host_key_check: HostKeyCheck,
+    /// seconds.
+    #[serde(default)]
+    pub connect_timeout: Option<u64>,
+}
+
+impl JumpHost {
+    pub fn to_endpoint<'a>(&'a self, pin_file: &'a Path) -> ssh_util::SshEndpoint<'a> {
+        ssh_endpoint!(self, pin_file)
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct ServerYml {
+    #[serde(default)]
     pub id_rsa: String,
     pub id_rsa_pub: Option<String>,
+    #[serde(default)]
+    pub id_rsa_passphrase: Option<PassphraseSource>,
     pub auth_method: AuthMethod,
+    /// a fallback chain tried in order, if empty only auth_method is tried.
+    #[serde(default)]
+    pub auth_methods: Vec<AuthMethod>,
+    /// verify host key against this file, default to ~/.ssh/known_hosts.
+    #[serde(default)]
+    pub known_hosts: Option<String>,
+    #[serde(default)]
+    pub host_key_check: HostKeyCheck,
+    /// resolve host, port, username, id_rsa, jump_hosts and connect_timeout from the OpenSSH config,
+    /// the fields present in this yml win.
+    #[serde(default)]
+    pub ssh_config_host: Option<String>,
+    /// default to ~/.ssh/config.
+    #[serde(default)]
+    pub ssh_config_file: Option<String>,
+    #[serde(default)]
     pub host: String,
+    /// 0 means not set, it's 22 unless the ssh config says otherwise.
+    #[serde(default)]
     pub port: u16,
+    /// seconds.
+    #[serde(default)]
+    pub connect_timeout: Option<u64>,
+    /// hops in order, the last one connects to host:port.
+    #[serde(default)]
+    pub jump_hosts: Vec<JumpHost>,
     pub rsync: RsyncConfig,
     pub remote_exec: String,
+    #[serde(default)]
     pub username: String,
-    pub password: String,
+    #[serde(default)]
+    pub password: Secret,
     pub directories: Vec<Directory>,
+    /// like the ones of a directory, run around all the directories.
+    #[serde(default)]
+    pub pre_sync: Vec<String>,
+    #[serde(default)]
+    pub post_sync: Vec<String>,
+    /// a hook running longer is killed and reported as failed.
+    #[serde(default = "default_hook_timeout_secs")]
+    pub hook_timeout_secs: u64,
     pub prune_strategy: PruneStrategy,
     pub archive_prefix: String,
     pub archive_postfix: String,
     pub compress_archive: Option<CompressionImpl>,
+    /// default to a codec specific one.
+    #[serde(default)]
+    pub compress_level: Option<u32>,
+    #[serde(default)]
+    pub compress_threads: Option<u32>,
+    /// encrypt the archives written by archive_local, not applied to archive_cmd.
+    #[serde(default)]
+    pub encryption: Option<ArchiveEncryption>,
+    /// archive only the changed files, a manifest is written next to each archive.
+    #[serde(default)]
+    pub incremental_archive: Option<IncrementalArchive>,
+    /// every new archive is copied to them.
+    #[serde(default)]
+    pub offsite_targets: Vec<OffsiteTarget>,
+    /// stale if no successful run in it, like 36h or 2d. not checked if it's None.
+    #[serde(default)]
+    pub max_backup_age: Option<MaxBackupAge>,
     pub buf_len: usize,
     pub use_db: bool,
     pub skip_sha1: bool,
@@ -65,6 +334,90 @@ pub struct ServerYml {
 }
 
 impl ServerYml {
+    /// Secrets referenced by the yml are resolved here, after that they are plain values in memory.
+    pub fn resolve_secrets(&mut self, secrets: &Secrets) -> Result<(), failure::Error> {
+        self.password.resolve(secrets)?;
+        for jump_host in self.jump_hosts.iter_mut() {
+            jump_host.password.resolve(secrets)?;
+        }
+        if let Some(encryption) = self.encryption.as_mut() {
+            encryption.passphrase.resolve(secrets)?;
+        }
+        for target in self.offsite_targets.iter_mut() {
+            target.resolve_secrets(secrets)?;
+        }
+        Ok(())
+    }
+
+    /// Fill the connection fields missing in the yml from the OpenSSH config.
+    pub fn apply_ssh_config(&mut self) -> Result<(), failure::Error> {
+        if let Some(alias) = self.ssh_config_host.as_ref() {
+            let config = SshConfig::load(self.ssh_config_file.as_ref().map(Path::new))?;
+            let resolved = config.resolve(alias);
+            trace!("resolved ssh config of {}: {:?}", alias, resolved);
+            if self.host.is_empty() {
+                self.host = resolved.host_name.unwrap_or_else(|| alias.clone());
+            }
+            if self.port == 0 {
+                self.port = resolved.port.unwrap_or(0);
+            }
+            if self.username.is_empty() {
+                if let Some(user) = resolved.user {
+                    self.username = user;
+                }
+            }
+            if self.id_rsa.is_empty() {
+                if let Some(id_rsa) = resolved.identity_file {
+                    self.id_rsa = id_rsa;
+                }
+            }
+            if self.connect_timeout.is_none() {
+                self.connect_timeout = resolved.connect_timeout;
+            }
+            if self.jump_hosts.is_empty() {
+                if let Some(proxy_jump) = resolved.proxy_jump {
+                    for hop in ssh_config::parse_proxy_jump(&proxy_jump) {
+                        let hop_conf = config.resolve(&hop.host);
+                        let id_rsa = hop_conf.identity_file.unwrap_or_default();
+                        self.jump_hosts.push(JumpHost {
+                            host: hop_conf.host_name.unwrap_or(hop.host),
+                            port: hop.port.or(hop_conf.port).unwrap_or(22),
+                            username: hop
+                                .user
+                                .or(hop_conf.user)
+                                .or_else(ssh_config::local_user_name)
+                                .unwrap_or_else(|| self.username.clone()),
+                            password: Secret::default(),
+                            auth_methods: if id_rsa.is_empty() {
+                                Vec::new()
+                            } else {
+                                vec![AuthMethod::Agent, AuthMethod::IdentityFile]
+                            },
+                            id_rsa,
+                            id_rsa_pub: None,
+                            id_rsa_passphrase: None,
+                            auth_method: AuthMethod::Agent,
+                            known_hosts: self.known_hosts.clone(),
+                            host_key_check: self.host_key_check.clone(),
+                            connect_timeout: hop_conf.connect_timeout,
+                        });
+                    }
+                }
+            }
+        }
+        if self.port == 0 {
+            self.port = 22;
+        }
+        if self.host.is_empty() {
+            bail!("host is missing, set it in the server yml or use ssh_config_host.");
+        }
+        Ok(())
+    }
+
+    pub fn to_endpoint<'a>(&'a self, pin_file: &'a Path) -> ssh_util::SshEndpoint<'a> {
+        ssh_endpoint!(self, pin_file)
+    }
+
     pub fn get_possible_encoding(&self) -> Vec<&'static Encoding> {
         self.possible_encoding
             .iter()
@@ -84,7 +437,6 @@ pub struct Server {
     session: Option<ssh2::Session>,
     // for passive_leaf node, it's dependent on invoking parameter of app_instance_id.
     my_dir: PathBuf,
-    #[allow(dead_code)]
     reports_dir: PathBuf,
     archives_dir: PathBuf,
     working_dir: PathBuf,
@@ -92,7 +444,6 @@ pub struct Server {
     pub db_access: Option<SqliteDbAccess>,
     _m: PhantomData<SqliteConnectionManager>,
     app_conf: MiniAppConf,
-    lock_file: Option<fs::File>,
 }
 
 unsafe impl Sync for Server {}
@@ -147,7 +498,6 @@ impl Server {
             yml_location: None,
             app_conf,
             _m: PhantomData,
-            lock_file: None,
         })
     }
 
@@ -163,24 +513,26 @@ impl Server {
             .join("directories")
     }
     /// Lock the server, preventing server from concurrently executing.
-    pub fn lock_working_file(&mut self) -> Result<(), failure::Error> {
+    /// Err if another process holds it, the lock is released when the returned one drops.
+    /// Taken by the syncs, the archiving and the pruning only,
+    /// the read only commands see every server.
+    pub fn lock_working_file(&self) -> Result<WorkLock, failure::Error> {
         let lof = self.working_dir.join("working.lock");
         trace!("start locking file: {:?}", lof);
-        if lof.exists() {
-            if fs::remove_file(lof.as_path()).is_err() {
-                eprintln!("create lock file failed: {:?}, if you can sure app isn't running, you can delete it manually.", lof);
-            }
-        } else {
-            self.lock_file
-                .replace(fs::OpenOptions::new().write(true).create(true).open(&lof)?);
-        }
+        let name = format!("server {}", self.server_yml.host);
+        let lock = WorkLock::acquire(&lof, &name)?;
         trace!("locked!");
-        Ok(())
+        Ok(lock)
     }
 
-    pub fn get_access_log(&self) -> Result<fs::File, failure::Error> {
+    /// Appended by each run, rotated by the log_conf.
+    pub fn get_access_log(&self) -> Result<SyncLog, failure::Error> {
         let cf = self.working_dir.join("sync.log");
-        Ok(fs::OpenOptions::new().create(true).write(true).open(cf)?)
+        Ok(SyncLog::open(
+            cf,
+            self.app_conf.log_rotation,
+            self.app_conf.sync_log_format,
+        )?)
     }
 
     pub fn read_last_file_count(&self) -> u64 {
@@ -251,6 +603,7 @@ impl Server {
         self.server_yml.host.as_str()
     }
 
+    #[allow(dead_code)]
     pub fn get_port(&self) -> u16 {
         self.server_yml.port
     }
@@ -321,12 +674,41 @@ impl Server {
         self.server_yml.directories = directories;
     }
 
+    /// When archiving internally with compression the postfix follows the codec,
+    /// otherwise it's the archive_postfix in server yml. Encrypted archives end with .enc.
+    pub fn get_archive_postfix(&self) -> String {
+        if !self.app_conf.archive_cmd.is_empty() {
+            return self.server_yml.archive_postfix.clone();
+        }
+        let postfix = match self.server_yml.compress_archive.as_ref() {
+            Some(compression) => compression.archive_postfix(),
+            None => self.server_yml.archive_postfix.as_str(),
+        };
+        if self.server_yml.encryption.is_some() {
+            format!("{}{}", postfix, ENCRYPTED_POSTFIX)
+        } else {
+            postfix.to_string()
+        }
+    }
+
+    /// The archives compressed before the postfix followed the codec are named by the
+    /// archive_postfix, they are listed, verified and pruned with the current ones.
+    fn get_archive_postfixes(&self) -> Vec<String> {
+        let postfix = self.get_archive_postfix();
+        if postfix == self.server_yml.archive_postfix {
+            vec![postfix]
+        } else {
+            vec![postfix, self.server_yml.archive_postfix.clone()]
+        }
+    }
+
     /// get archives_dir , archive_prefix, archive_postfix from server yml configuration file.
     fn next_archive_file(&self) -> PathBuf {
         rolling_files::get_next_file_name(
+            self.app_conf.retention_timezone,
             &self.archives_dir,
             &self.server_yml.archive_prefix,
-            &self.server_yml.archive_postfix,
+            self.get_archive_postfix(),
         )
     }
 
@@ -334,7 +716,8 @@ impl Server {
     fn current_archive_file_path(&self) -> PathBuf {
         self.archives_dir.join(format!(
             "{}{}",
-            self.server_yml.archive_prefix, self.server_yml.archive_postfix,
+            self.server_yml.archive_prefix,
+            self.get_archive_postfix(),
         ))
     }
 
@@ -343,7 +726,11 @@ impl Server {
             .expect("current_archive_file_path got.")
     }
 
-    fn archive_internal(&self, pb: &mut Indicator) -> Result<PathBuf, failure::Error> {
+    /// The manifest is returned in incremental mode, it's archive name is set after renaming.
+    fn archive_internal(
+        &self,
+        pb: &mut Indicator,
+    ) -> Result<(PathBuf, Option<ArchiveManifest>), failure::Error> {
         let total_size = self.count_from_dirs_size();
 
         let style = ProgressStyle::default_bar()
@@ -368,24 +755,35 @@ impl Server {
                 .open(cur_archive_path.as_path())
         };
 
-        let writer: Box<dyn io::Write> = if let Some(ref sm) = self.server_yml.compress_archive {
-            match sm {
-                CompressionImpl::Bzip2 => {
-                    let w = BzEncoder::new(writer_c()?, Compression::Best);
-                    let w = ProgressWriter::new(w, pb);
-                    Box::new(w)
-                }
-            }
+        // file <- encryption <- compression <- progress <- tar, finished from the outside in.
+        let file: Box<dyn FinishWrite> = if let Some(ref encryption) = self.server_yml.encryption {
+            Box::new(EncryptWriter::new(writer_c()?, encryption)?)
         } else {
-            let w = ProgressWriter::new(writer_c()?, pb);
+            Box::new(writer_c()?)
+        };
+
+        let writer: Box<dyn FinishWrite> = if let Some(ref sm) = self.server_yml.compress_archive {
+            let w = sm.encoder(
+                file,
+                self.server_yml.compress_level,
+                self.server_yml.compress_threads,
+            )?;
+            Box::new(ProgressWriter::new(w, pb))
+        } else {
+            let w = ProgressWriter::new(file, pb);
             Box::new(w)
         };
 
         let mut archive = Builder::new(writer);
 
-        for dir in self.server_yml.directories.iter() {
-            let len = dir.count_total_size();
-            let d_path = &dir.from_dir.as_path();
+        if let Some(ref incremental) = self.server_yml.incremental_archive {
+            let manifest = self.append_incremental(&mut archive, pb, incremental)?;
+            archive.into_inner()?.finish_write()?;
+            return Ok((cur_archive_path, Some(manifest)));
+        }
+
+        for d_path in self.get_archive_sources() {
+            let len = Directory::count_total_size(&d_path);
             if let Some(d_path_name) = d_path.file_name() {
                 if d_path.exists() {
                     pb.set_message_pb_total(format!(
@@ -393,7 +791,7 @@ impl Server {
                         self.get_host(),
                         d_path_name
                     ));
-                    archive.append_dir_all(d_path_name, d_path)?;
+                    archive.append_dir_all(d_path_name, &d_path)?;
                     pb.inc_pb_total(len);
                 } else {
                     warn!("unexist directory: {:?}", d_path);
@@ -402,8 +800,85 @@ impl Server {
                 error!("dir.from_dir get file_name failed: {:?}", d_path);
             }
         }
-        archive.finish()?;
-        Ok(cur_archive_path)
+        archive.into_inner()?.finish_write()?;
+        Ok((cur_archive_path, None))
+    }
+
+    /// Append the files changed since the latest archive, or all of them when it's time for a full one.
+    /// Empty directories aren't recorded in this mode.
+    fn append_incremental<W: io::Write>(
+        &self,
+        archive: &mut Builder<W>,
+        pb: &Indicator,
+        incremental: &IncrementalArchive,
+    ) -> Result<ArchiveManifest, failure::Error> {
+        let previous = rolling_files::latest_archive_manifest(
+            &self.archives_dir,
+            &self.server_yml.archive_prefix,
+            self.get_archive_postfix(),
+            incremental.full_every,
+        )?;
+        let previous_entries = previous
+            .as_ref()
+            .map(ArchiveManifest::entries_by_path)
+            .unwrap_or_default();
+
+        let mut files = Vec::new();
+        for d_path in self.get_archive_sources() {
+            if !d_path.exists() {
+                warn!("unexist directory: {:?}", d_path);
+                continue;
+            }
+            pb.set_message_pb_total(format!(
+                "[{}] processing directory: {:?}",
+                self.get_host(),
+                d_path
+            ));
+            for scanned in archive_manifest::scan_directory(
+                &d_path,
+                &previous_entries,
+                self.server_yml.skip_sha1,
+            )? {
+                if scanned.changed {
+                    archive.append_path_with_name(&scanned.path, &scanned.entry.path)?;
+                }
+                pb.inc_pb_total(scanned.entry.len);
+                files.push(scanned.entry);
+            }
+        }
+
+        let current: HashSet<&str> = files.iter().map(|e| e.path.as_str()).collect();
+        let mut deleted: Vec<String> = previous_entries
+            .keys()
+            .filter(|p| !current.contains(*p))
+            .map(|p| p.to_string())
+            .collect();
+        deleted.sort();
+        drop(current);
+
+        let (kind, parent, chain_len) = match previous {
+            Some(ref prev) => (
+                ArchiveKind::Incremental,
+                Some(prev.archive.clone()),
+                prev.chain_len + 1,
+            ),
+            None => (ArchiveKind::Full, None, 0),
+        };
+        info!(
+            "{:?} archive of {}, {} files, {} deleted.",
+            kind,
+            self.get_host(),
+            files.len(),
+            deleted.len()
+        );
+        Ok(ArchiveManifest {
+            archive: String::new(),
+            kind,
+            parent,
+            chain_len,
+            files,
+            deleted,
+        })
     }
 
     fn archive_out(&self, pb: &mut Indicator) -> Result<PathBuf, failure::Error> {
@@ -490,28 +965,126 @@ impl Server {
     /// Archive need not to schedule standalone.
     /// Because of conflict with the sync operation.
     pub fn archive_local(&self) -> Result<(), failure::Error> {
+        let _lock = self.lock_working_file()?;
         info!(
             "start archive_local on server: {} at: {}",
             self.get_host(),
             Local::now()
         );
         let mut pb = Indicator::new(None);
-        let cur = if self.app_conf.archive_cmd.is_empty() {
+        let (cur, manifest) = if self.app_conf.archive_cmd.is_empty() {
             self.archive_internal(&mut pb)?
         } else {
-            self.archive_out(&mut pb)?
+            if self.server_yml.encryption.is_some() {
+                warn!(
+                    "archive_cmd is used, the archive of {} isn't encrypted.",
+                    self.get_host()
+                );
+            }
+            if self.server_yml.incremental_archive.is_some() {
+                warn!(
+                    "archive_cmd is used, the archive of {} is always full.",
+                    self.get_host()
+                );
+            }
+            (self.archive_out(&mut pb)?, None)
         };
         let nf = self.next_archive_file();
         trace!("move fie to {:?}", nf);
-        fs::rename(cur, nf)?;
+        fs::rename(cur, &nf)?;
+        if let Some(catalog) = self.catalog() {
+            match catalog.set_archive_of_unarchived(self.get_host(), nf.to_string_lossy()) {
+                Ok(c) => info!("{} file versions are in {:?}.", c, nf),
+                Err(err) => error!("set the archive of the file versions failed: {:?}", err),
+            }
+        }
+        if let Some(mut manifest) = manifest {
+            manifest.archive = nf
+                .file_name()
+                .expect("archive file should have a name.")
+                .to_string_lossy()
+                .to_string();
+            manifest.save(&nf)?;
+        }
+        self.copy_offsite(&nf)
+    }
+
+    /// A failed target doesn't stop the others.
+    fn copy_offsite(&self, archive: &Path) -> Result<(), failure::Error> {
+        let pin_file = self.get_pinned_known_hosts();
+        let postfix = self.get_archive_postfix();
+        let mut failed = Vec::new();
+        for target in self.server_yml.offsite_targets.iter() {
+            if let Err(err) = target.upload(
+                archive,
+                self.app_conf.retention_timezone,
+                &self.server_yml.archive_prefix,
+                &postfix,
+                &pin_file,
+            ) {
+                error!(
+                    "copy {:?} to offsite target {} failed: {:?}",
+                    archive, target.name, err
+                );
+                failed.push(target.name.as_str());
+            }
+        }
+        if !failed.is_empty() {
+            bail!("copy to offsite targets failed: {}", failed.join(", "));
+        }
         Ok(())
     }
+    /// Ordered by time asc.
+    pub fn list_archive_files(&self) -> Result<Vec<PathBuf>, failure::Error> {
+        let postfixes = self.get_archive_postfixes();
+        rolling_files::list_archive_files(
+            &self.archives_dir,
+            &self.server_yml.archive_prefix,
+            &postfixes.iter().map(String::as_str).collect::<Vec<_>>(),
+        )
+    }
+
+    /// What prune_backups would do to the archives.
+    pub fn plan_prune(&self) -> Result<Vec<rolling_files::ArchiveRetention>, failure::Error> {
+        let postfixes = self.get_archive_postfixes();
+        rolling_files::plan_prune(
+            &self.server_yml.prune_strategy,
+            self.app_conf.retention_timezone,
+            &self.archives_dir,
+            &self.server_yml.archive_prefix,
+            &postfixes.iter().map(String::as_str).collect::<Vec<_>>(),
+        )
+    }
+
+    /// The archive is a file name under archives_dir or a path.
+    pub fn verify_archive(
+        &self,
+        archive: impl AsRef<Path>,
+    ) -> Result<ArchiveVerification, failure::Error> {
+        let archive = archive.as_ref();
+        let archive = if archive.exists() {
+            archive.to_path_buf()
+        } else {
+            self.archives_dir.join(archive)
+        };
+        // archived by the archive_cmd, it's compressed or not by the command.
+        let codec = if self.app_conf.archive_cmd.is_empty() {
+            self.server_yml.compress_archive.as_ref()
+        } else {
+            None
+        };
+        archive_verify::verify_archive(&archive, codec, self.server_yml.encryption.as_ref())
+    }
+
     pub fn prune_backups(&self) -> Result<(), failure::Error> {
+        let _lock = self.lock_working_file()?;
+        let postfixes = self.get_archive_postfixes();
         rolling_files::do_prune_dir(
             &self.server_yml.prune_strategy,
+            self.app_conf.retention_timezone,
             &self.archives_dir,
             &self.server_yml.archive_prefix,
-            &self.server_yml.archive_postfix,
+            &postfixes.iter().map(String::as_str).collect::<Vec<_>>(),
         )?;
         Ok(())
     }
@@ -525,23 +1098,23 @@ impl Server {
         self.session.as_ref().expect("session should be created.")
     }
 
+    /// Pinned host keys of trust-on-first-use mode live in the hub's data dir.
+    fn get_pinned_known_hosts(&self) -> PathBuf {
+        self.app_conf.data_dir.join("known_hosts")
+    }
+
     fn create_ssh_session(&self) -> Result<ssh2::Session, failure::Error> {
-        let url = format!("{}:{}", self.get_host(), self.get_port());
-        let username = self.server_yml.username.as_str();
-        match self.server_yml.auth_method {
-            AuthMethod::Agent => ssh_util::create_ssh_session_agent(url.as_str(), username),
-            AuthMethod::IdentityFile => ssh_util::create_ssh_session_identity_file(
-                url.as_str(),
-                username,
-                self.server_yml.id_rsa.as_str(),
-                self.server_yml.id_rsa_pub.as_ref().map(|ds| ds.as_str()),
-            ),
-            AuthMethod::Password => ssh_util::create_ssh_session_password(
-                url.as_str(),
-                username,
-                self.server_yml.password.as_str(),
-            ),
-        }
+        let pin_file = self.get_pinned_known_hosts();
+        let jump_hosts: Vec<ssh_util::SshEndpoint> = self
+            .server_yml
+            .jump_hosts
+            .iter()
+            .map(|jh| jh.to_endpoint(pin_file.as_path()))
+            .collect();
+        ssh_util::create_ssh_session(
+            &self.server_yml.to_endpoint(pin_file.as_path()),
+            &jump_hosts,
+        )
     }
 
     pub fn connect(&mut self) -> Result<(), failure::Error> {
@@ -553,11 +1126,20 @@ impl Server {
     }
 
     pub fn count_from_dirs_size(&self) -> u64 {
+        self.get_archive_sources()
+            .iter()
+            .map(Directory::count_total_size)
+            .sum()
+    }
+
+    /// The local directories archive_internal walks, in the order of directories.
+    fn get_archive_sources(&self) -> Vec<PathBuf> {
+        let my_directories = self.get_my_directories();
         self.server_yml
             .directories
             .iter()
-            .map(|d| d.count_total_size())
-            .sum()
+            .map(|d| d.get_archive_source(self.app_conf.app_role.as_ref(), &my_directories))
+            .collect()
     }
 
     pub fn find_cron_by_name(&self, cron_name: &str) -> Option<ScheduleItem> {
@@ -568,7 +1150,132 @@ impl Server {
             .cloned()
     }
 
+    pub fn get_reports_dir(&self) -> &Path {
+        self.reports_dir.as_path()
+    }
+
+    pub fn check_freshness(&self) -> Result<Freshness, failure::Error> {
+        freshness::check_freshness(
+            self.get_host(),
+            &self.reports_dir,
+            self.server_yml.max_backup_age,
+            chrono::Local::now(),
+        )
+    }
+
+    pub fn latest_sync_report(&self) -> Result<Option<SyncReport>, failure::Error> {
+        match sync_report::list_reports(&self.reports_dir)?.pop() {
+            Some(path) => Ok(Some(SyncReport::load(&path)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// When the sync-pull-dirs schedule runs next, None without the schedule.
+    pub fn next_sync_run(&self) -> Option<chrono::DateTime<Local>> {
+        let schedule_item = self.find_cron_by_name(CRON_NAME_SYNC_PULL_DIRS)?;
+        let server_yml_path = self
+            .yml_location
+            .as_ref()
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.get_host().to_string());
+        scheduler_util::next_execute(
+            self.db_access.as_ref(),
+            server_yml_path,
+            &schedule_item.name,
+            &schedule_item.cron,
+        )
+    }
+
+    pub fn collect_metrics(&self) -> Result<ServerMetrics, failure::Error> {
+        let mut metrics = ServerMetrics {
+            server: self.get_host().to_string(),
+            next_run: self.next_sync_run(),
+            ..ServerMetrics::default()
+        };
+        if let Some(report) = self.latest_sync_report()? {
+            metrics.last_run = Some(report.finished_at.unwrap_or(report.started_at));
+            metrics.last_run_succeeded = report.succeeded();
+            metrics.last_run_duration_secs = report.duration_secs;
+            metrics.bytes_transferred = report.bytes_transferred;
+            metrics.files_changed = report.changed;
+            metrics.files_failed = report.failed;
+        }
+        metrics.last_success = freshness::last_successful_run(&self.reports_dir)?
+            .map(|report| report.finished_at.unwrap_or(report.started_at));
+        if self.archives_dir.exists() {
+            for archive in self.list_archive_files()? {
+                metrics.archive_count += 1;
+                metrics.archive_bytes += fs::metadata(&archive).map(|m| m.len()).unwrap_or(0);
+            }
+        }
+        Ok(metrics)
+    }
+
+    /// The catalog in the db of the app, the sync goes on without it if there is no db.
+    pub fn catalog(&self) -> Option<&SqliteDbAccess> {
+        if self.db_access.is_none() {
+            warn!("no db, the file versions of {} aren't cataloged.", self.get_host());
+        }
+        self.db_access.as_ref()
+    }
+
+    /// The digests in the db of the app, None if the sha1 is skipped or there is no db.
+    pub fn digest_cache(&self) -> Option<&SqliteDbAccess> {
+        if self.app_conf.skip_sha1 {
+            return None;
+        }
+        self.db_access.as_ref()
+    }
+
+    /// The failures are logged only.
+    fn record_version(
+        &self,
+        catalog: Option<&SqliteDbAccess>,
+        file_item: &FullPathFileItem,
+        run_id: &str,
+        location: &str,
+    ) {
+        if let Some(catalog) = catalog {
+            let mut fv = FileVersionInDb::new(
+                self.get_host(),
+                file_item.from_path.slash.as_str(),
+                file_item.len,
+                run_id,
+                location,
+            );
+            fv.sha1 = file_item.sha1.clone();
+            fv.modified = file_item.modified.map(|md| chrono::Utc.timestamp(md as i64, 0));
+            if let Err(err) = catalog.insert_file_version(fv) {
+                error!("record {} in the catalog failed: {:?}", file_item.from_path, err);
+            }
+        }
+    }
+
+    /// Keep the report of the run even if it stops by an error.
+    fn save_sync_report<T>(
+        &self,
+        mut report: SyncReport,
+        result: Result<T, failure::Error>,
+    ) -> Result<T, failure::Error> {
+        if let Err(err) = result.as_ref() {
+            report.errors.push(err.to_string());
+        }
+        report.finish();
+        match report.save(&self.reports_dir) {
+            Ok(path) => info!("sync report saved to {:?}", path),
+            Err(err) => error!("save sync report failed: {:?}", err),
+        }
+        result
+    }
+
     pub fn client_pull_loop(&self) -> Result<Option<(u64, u64)>, failure::Error> {
+        let _lock = self.lock_working_file()?;
+        let mut report = SyncReport::new(self.get_host(), SyncDirection::Pull);
+        let result = self.pull_files(&mut report);
+        self.save_sync_report(report, result)
+    }
+
+    fn pull_files(&self, report: &mut SyncReport) -> Result<Option<(u64, u64)>, failure::Error> {
         let session = self.create_ssh_session()?;
         let mut channel: ssh2::Channel = session.channel_session()?;
         let cmd = format!(
@@ -578,13 +1285,16 @@ impl Server {
             if self.app_conf.skip_sha1 {
                 ""
             } else {
-                "--enable-sha1"
+                " --enable-sha1"
             },
         );
         trace!("invoke remote: {}", cmd);
         channel.exec(&cmd).expect("start remote server-loop");
 
         let mut sync_log = self.get_access_log()?;
+        let catalog = self.catalog();
+        let digests = self.digest_cache();
+        let run_id = report.run_id();
 
         let mut message_hub = SshChannelMessageHub::new(channel);
 
@@ -607,6 +1317,7 @@ impl Server {
 
         let mut last_df: Option<SlashPath> = None;
         let mut last_file_item: Option<FullPathFileItem> = None;
+        let mut last_reason = String::new();
         let mut buf = vec![0; 8192];
 
         loop {
@@ -615,6 +1326,9 @@ impl Server {
                     error!("got error type byte: {}", err);
                     error!("last_df: {:?}", last_df);
                     error!("last_file_item: {:?}", last_file_item);
+                    report
+                        .errors
+                        .push(format!("read type byte failed: {}", err));
                     break;
                 }
                 Ok(type_byte) => type_byte,
@@ -623,12 +1337,15 @@ impl Server {
             match type_byte {
                 TransferType::FileItem => {
                     new_file_count += 1;
+                    report.files_seen += 1;
                     let string_message = StringMessage::parse(&mut message_hub)?;
                     trace!("got file item: {}", string_message.content);
                     match serde_json::from_str::<FullPathFileItem>(&string_message.content) {
                         Ok(file_item) => {
                             let df = my_directories.join_another(&file_item.to_path); // use to path.
-                            match file_item.changed(df.as_path()) {
+                            let file_changed = file_item.changed(df.as_path(), digests);
+                            report.record_file_changed(&file_changed);
+                            match file_changed {
                                 FileChanged::NoChange => {
                                     message_hub.write_transfer_type_only(
                                         TransferType::FileItemUnchanged,
@@ -637,6 +1354,7 @@ impl Server {
                                 }
                                 fc => {
                                     let string_message = StringMessage::new(format!("{:?}", fc));
+                                    last_reason = changed_reason(&string_message.content);
                                     message_hub.write_and_flush(
                                         &string_message.as_string_sent_bytes_with_header(
                                             TransferType::FileItemChanged,
@@ -651,6 +1369,7 @@ impl Server {
                             // why send error message to server side?
                             // message_hub.write_error_message(format!("{:?}", err))?;
                             error!("{:?}", err);
+                            report.record_failed(format!("parse file item failed: {}", err));
                         }
                     };
                 }
@@ -659,8 +1378,9 @@ impl Server {
                     // file item is from another side.
                     if let (Some(df), Some(file_item)) = (last_df.take(), last_file_item.take()) {
                         cppb.push_one(file_item.len, &file_item);
-                        writeln!(sync_log, "[{}]{}", chrono::Local::now(), file_item.to_path).ok();
-                        writeln!(sync_log, "copy to file: {:?}", df.as_path()).ok();
+                        let mut record =
+                            FileRecord::new(df.slash.as_str(), content_len.value, &last_reason);
+                        let started = std::time::Instant::now();
                         match message_hub.copy_to_file(
                             &mut buf,
                             content_len.value,
@@ -672,8 +1392,21 @@ impl Server {
                                 // message_hub.write_error_message(format!("{:?}", err))?;
                                 //log at client side.
                                 error!("copy_to_file got error {:?}", err);
+                                record.failed(err.to_string());
+                                report.record_failed(format!(
+                                    "copy to {:?} failed: {}",
+                                    df.as_path(),
+                                    err
+                                ));
                             }
                             Ok(()) => {
+                                report.bytes_transferred += content_len.value;
+                                self.record_version(
+                                    catalog,
+                                    &file_item,
+                                    &run_id,
+                                    df.slash.as_str(),
+                                );
                                 if let Some(md) = file_item.modified {
                                     let ft = filetime::FileTime::from_unix_time(md as i64, 0);
                                     filetime::set_file_mtime(df.as_path(), ft)?;
@@ -687,16 +1420,40 @@ impl Server {
                                         file_item
                                     )
                                 }
+                                data_shape_util::refresh_digest(
+                                    df.as_path(),
+                                    file_item.sha1.as_deref(),
+                                    digests,
+                                );
                             }
                         }
+                        record.duration_ms = started.elapsed().as_millis() as u64;
+                        sync_log.file(&record).ok();
                     } else {
                         error!("empty last_df.");
+                        report
+                            .errors
+                            .push("got file content without a changed file item.".to_string());
                     }
                 }
                 TransferType::StringError => {
                     // must read it or else the stream will stall.
                     let ss = StringMessage::parse(&mut message_hub)?;
                     error!("string error: {:?}", ss.content);
+                    report.record_failed(ss.content);
+                }
+                TransferType::HookResult => {
+                    let string_message = StringMessage::parse(&mut message_hub)?;
+                    match serde_json::from_str::<HookResult>(&string_message.content) {
+                        Ok(result) => {
+                            sync_log.hook(&result).ok();
+                            log_hook_result(&result);
+                            if !result.succeeded() {
+                                report.errors.push(result.summary());
+                            }
+                        }
+                        Err(err) => error!("parse hook result failed: {:?}", err),
+                    }
                 }
                 TransferType::RepeatDone | TransferType::Eof => {
                     info!("got eof, exiting.");
@@ -719,6 +1476,13 @@ impl Server {
         &self,
         _follow_archive: bool,
     ) -> Result<Option<(u64, u64)>, failure::Error> {
+        let _lock = self.lock_working_file()?;
+        let mut report = SyncReport::new(self.get_host(), SyncDirection::Push);
+        let result = self.push_files(&mut report);
+        self.save_sync_report(report, result)
+    }
+
+    fn push_files(&self, report: &mut SyncReport) -> Result<Option<(u64, u64)>, failure::Error> {
         let session = self.create_ssh_session()?;
         let mut channel: ssh2::Channel = session.channel_session()?;
         let cmd = format!(
@@ -731,6 +1495,10 @@ impl Server {
         let file_count = self.read_last_file_count();
         let mut cppb = TransferFileProgressBar::new(file_count, self.app_conf.show_pb);
         let mut message_hub = SshChannelMessageHub::new(channel);
+        let mut sync_log = self.get_access_log()?;
+        let catalog = self.catalog();
+        let digests = self.digest_cache();
+        let run_id = report.run_id();
 
         let mut new_file_count = 0_u64;
 
@@ -746,14 +1514,50 @@ impl Server {
         let mut buf = [0; 8192];
         let possible_encoding = self.server_yml.get_possible_encoding();
         // after sent server_yml, will send push_primary_file_item repeatly, when finish sending follow a RepeatDone message.
-        for dir in self.server_yml.directories.iter() {
+        // the source is here, so are the hooks.
+        let hook_timeout = Duration::from_secs(self.server_yml.hook_timeout_secs);
+        let server_pre_sync = run_local_hooks(
+            &self.server_yml.pre_sync,
+            HookStage::PreSync,
+            SERVER_SCOPE,
+            hook_timeout,
+            report,
+            &mut sync_log,
+        );
+        let directories = if server_pre_sync {
+            self.server_yml.directories.as_slice()
+        } else {
+            &[]
+        };
+        for dir in directories {
+            let scope = dir.from_dir.as_str();
+            if !run_local_hooks(
+                &dir.pre_sync,
+                HookStage::PreSync,
+                scope,
+                hook_timeout,
+                report,
+                &mut sync_log,
+            ) {
+                run_local_hooks(
+                    &dir.post_sync,
+                    HookStage::PostSync,
+                    scope,
+                    hook_timeout,
+                    report,
+                    &mut sync_log,
+                );
+                continue;
+            }
             let push_file_items = dir.file_item_iter(
                 &self.app_conf.app_instance_id,
                 self.app_conf.skip_sha1,
                 &possible_encoding,
+                digests,
             );
             for fi in push_file_items {
                 new_file_count += 1;
+                report.files_seen += 1;
                 match fi {
                     Ok(fi) => {
                         message_hub.write_and_flush(&fi.as_sent_bytes())?;
@@ -762,41 +1566,103 @@ impl Server {
                                 let change_message = StringMessage::parse(&mut message_hub)?;
                                 trace!("changed file: {}.", change_message.content);
                                 cppb.push_one(fi.len, &fi);
-                                message_hub.copy_from_file(&mut buf, &fi, Some(&cppb))?;
+                                let reason = changed_reason(&change_message.content);
+                                let mut record =
+                                    FileRecord::new(fi.from_path.slash.as_str(), fi.len, &reason);
+                                let started = std::time::Instant::now();
+                                let sent = message_hub.copy_from_file(&mut buf, &fi, Some(&cppb));
+                                record.duration_ms = started.elapsed().as_millis() as u64;
+                                if let Err(err) = sent.as_ref() {
+                                    record.failed(err.to_string());
+                                }
+                                sync_log.file(&record).ok();
+                                sent?;
+                                let location = format!("{}:{}", self.get_host(), fi.to_path);
+                                self.record_version(catalog, &fi, &run_id, &location);
                                 changed += 1;
+                                report.record_changed(reason);
+                                report.bytes_transferred += fi.len;
                                 trace!("send file content done.");
                             }
                             TransferType::FileItemUnchanged => {
                                 cppb.skip_one();
                                 unchanged += 1;
+                                report.unchanged += 1;
                                 trace!("unchanged file.");
                             }
                             TransferType::StringError => {
                                 let ss = StringMessage::parse(&mut message_hub)?;
                                 error!("string error: {:?}", ss.content);
+                                report.record_failed(ss.content);
                             }
                             i => error!("got unexpected transfer type {:?}", i),
                         }
                     }
                     Err(err) => {
                         error!("{:?}", err);
+                        report.record_failed(err.to_string());
                     }
                 }
             }
+            run_local_hooks(
+                &dir.post_sync,
+                HookStage::PostSync,
+                scope,
+                hook_timeout,
+                report,
+                &mut sync_log,
+            );
         }
+        run_local_hooks(
+            &self.server_yml.post_sync,
+            HookStage::PostSync,
+            SERVER_SCOPE,
+            hook_timeout,
+            report,
+            &mut sync_log,
+        );
         message_hub.write_and_flush(&[TransferType::RepeatDone.to_u8()])?;
         info!("changed: {}, unchanged: {}", changed, unchanged);
         cppb.pb.finish_with_message("done.");
         self.write_last_file_count(new_file_count);
+        sync_log.flush()?;
         message_hub.close()?;
         Ok(None)
     }
 }
 
+fn log_hook_result(result: &HookResult) {
+    if result.succeeded() {
+        info!("{}", result.summary());
+    } else {
+        error!("{}", result.summary());
+    }
+}
+
+/// false if any of them failed.
+fn run_local_hooks(
+    commands: &[String],
+    stage: HookStage,
+    scope: &str,
+    timeout: Duration,
+    report: &mut SyncReport,
+    sync_log: &mut SyncLog,
+) -> bool {
+    let results = sync_hook::run_hooks(commands, stage, scope, timeout);
+    for result in results.iter() {
+        log_hook_result(result);
+        sync_log.hook(result).ok();
+        if !result.succeeded() {
+            report.errors.push(result.summary());
+        }
+    }
+    sync_hook::all_succeeded(&results)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::data_shape::{string_path::SlashPath, AppRole};
+    use crate::data_shape::{string_path::SlashPath, AppRole, PruneStrategyBuilder};
     // use crate::db_accesses::{DbAccess, SqliteDbAccess};
     use crate::develope::tutil;
     use crate::log_util;
@@ -831,6 +1697,144 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn t_compression_codecs() -> Result<(), failure::Error> {
+        let tu = tutil::TestDir::new();
+        let content = "hello compression. ".repeat(1000);
+        for (name, codec) in [
+            ("a.bz2", CompressionImpl::Bzip2),
+            ("a.gz", CompressionImpl::Gzip),
+            ("a.zst", CompressionImpl::Zstd),
+            ("a.xz", CompressionImpl::Xz),
+        ]
+        .iter()
+        {
+            let f = tu.tmp_dir_path().join(name);
+            {
+                let mut w = codec.encoder(fs::File::create(&f)?, None, Some(2))?;
+                w.write_all(content.as_bytes())?;
+                w.finish_write()?;
+            }
+            let r = fs::File::open(&f)?;
+            let mut decoded = String::new();
+            match codec {
+                CompressionImpl::Bzip2 => {
+                    bzip2::read::BzDecoder::new(r).read_to_string(&mut decoded)?
+                }
+                CompressionImpl::Gzip => {
+                    flate2::read::GzDecoder::new(r).read_to_string(&mut decoded)?
+                }
+                CompressionImpl::Zstd => zstd::Decoder::new(r)?.read_to_string(&mut decoded)?,
+                CompressionImpl::Xz => xz2::read::XzDecoder::new(r).read_to_string(&mut decoded)?,
+            };
+            assert_eq!(decoded, content);
+            assert!(f.metadata()?.len() < content.len() as u64);
+        }
+        assert!(CompressionImpl::Gzip
+            .encoder(io::sink(), Some(10), None)
+            .is_err());
+        assert_eq!(CompressionImpl::Zstd.archive_postfix(), ".tar.zst");
+        Ok(())
+    }
+
+    #[test]
+    fn t_jump_hosts_yml() -> Result<(), failure::Error> {
+        let yml = r#"
+- host: bastion.example.com
+  username: jump
+  auth_method: Agent
+- host: 10.0.0.2
+  port: 2222
+  username: inner
+  id_rsa: /home/inner/.ssh/id_rsa
+  id_rsa_passphrase:
+    Env: INNER_PASSPHRASE
+  auth_method: IdentityFile
+  auth_methods: [Agent, IdentityFile]
+  host_key_check: TrustOnFirstUse
+"#;
+        let jump_hosts: Vec<JumpHost> = serde_yaml::from_str(yml)?;
+        let pin_file = Path::new("known_hosts");
+        let first = jump_hosts[0].to_endpoint(pin_file);
+        assert_eq!(first.port, 22);
+        assert_eq!(first.auth_methods.len(), 1);
+        assert_eq!(*first.host_key_policy.check, HostKeyCheck::Strict);
+
+        let second = jump_hosts[1].to_endpoint(pin_file);
+        assert_eq!(second.port, 2222);
+        assert_eq!(second.auth_methods.len(), 2);
+        assert!(second.credentials.id_rsa_passphrase.is_some());
+        assert_eq!(*second.host_key_policy.check, HostKeyCheck::TrustOnFirstUse);
+        Ok(())
+    }
+
+    #[test]
+    fn t_archive_mirrored_on_pull_hub() -> Result<(), failure::Error> {
+        let tu = tutil::TestDir::new();
+        let app_conf = tutil::load_demo_app_conf_sqlite(Some(tu.tmp_dir_str()), AppRole::PullHub);
+        let mut server_yml: ServerYml =
+            serde_yaml::from_str(include_str!("../server_template.yaml"))?;
+        server_yml.compress_archive = None;
+        server_yml.incremental_archive = Some(IncrementalArchive { full_every: 7 });
+        let server = Server::new(
+            app_conf.mini_app_conf,
+            tu.tmp_dir_path().join("my"),
+            server_yml,
+        )?;
+
+        // the from_dir is the remote's, the mirrored copy is under my_directories.
+        let mirrored = server.get_my_dir().join("directories").join("a-dir");
+        fs::create_dir_all(&mirrored)?;
+        tutil::make_a_file_with_content(&mirrored, "a.txt", "abc")?;
+        assert_eq!(server.count_from_dirs_size(), 3);
+
+        // the lock is taken by the archiving, not by loading the server.
+        let busy = server.lock_working_file()?;
+        assert!(server.archive_local().is_err(), "the server is busy.");
+        drop(busy);
+        server.archive_local()?;
+        let archives = rolling_files::list_archive_files(&server.archives_dir, "backup", &[".7z"])?;
+        assert_eq!(archives.len(), 1);
+        let mut names = Vec::new();
+        for entry in tar::Archive::new(fs::File::open(&archives[0])?).entries()? {
+            names.push(entry?.path()?.to_string_lossy().to_string());
+        }
+        assert_eq!(names, vec!["a-dir/a.txt"]);
+        let manifest = ArchiveManifest::load(&archives[0])?.expect("manifest should exist.");
+        assert_eq!(manifest.kind, ArchiveKind::Full);
+        assert_eq!(manifest.files.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn t_legacy_archive_postfix() -> Result<(), failure::Error> {
+        let tu = tutil::TestDir::new();
+        let app_conf = tutil::load_demo_app_conf_sqlite(Some(tu.tmp_dir_str()), AppRole::PullHub);
+        let mut server_yml: ServerYml =
+            serde_yaml::from_str(include_str!("../server_template.yaml"))?;
+        server_yml.compress_archive = Some(CompressionImpl::Gzip);
+        // only the latest one remains.
+        server_yml.prune_strategy = PruneStrategyBuilder::default()
+            .build()
+            .map_err(failure::err_msg)?;
+        let server = Server::new(
+            app_conf.mini_app_conf,
+            tu.tmp_dir_path().join("my"),
+            server_yml,
+        )?;
+        fs::create_dir_all(&server.archives_dir)?;
+        let legacy = server.archives_dir.join("backup20200101000000.7z");
+        let current = server.archives_dir.join("backup20200102000000.tar.gz");
+        fs::write(&legacy, "a")?;
+        fs::write(&current, "b")?;
+        assert_eq!(server.list_archive_files()?, vec![legacy.clone(), current.clone()]);
+
+        server.prune_backups()?;
+        assert!(!legacy.exists(), "pruned with the current ones.");
+        assert!(current.exists());
+        Ok(())
+    }
+
     #[test]
     fn t_connect_server() -> Result<(), failure::Error> {
         log();
@@ -0,0 +1,250 @@
+//! A small reader of the OpenSSH client config, only the options we care about.
+//! Like OpenSSH, the first obtained value of an option wins. Match and Include aren't supported.
+use log::*;
+use std::fs;
+use std::path::Path;
+
+/// The options resolved for a host alias.
+#[derive(Debug, Default, PartialEq)]
+pub struct SshConfigHost {
+    pub host_name: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub identity_file: Option<String>,
+    pub proxy_jump: Option<String>,
+    pub connect_timeout: Option<u64>,
+}
+
+/// One hop of ProxyJump, [user@]host[:port].
+#[derive(Debug, PartialEq)]
+pub struct ProxyJumpHop {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+pub struct SshConfig {
+    content: String,
+}
+
+impl SshConfig {
+    /// If file is None, read ~/.ssh/config.
+    pub fn load(file: Option<&Path>) -> Result<Self, failure::Error> {
+        let file = match file {
+            Some(f) => f.to_path_buf(),
+            None => match dirs::home_dir() {
+                Some(home) => home.join(".ssh").join("config"),
+                None => bail!("can't find the home directory to locate ~/.ssh/config."),
+            },
+        };
+        match fs::read_to_string(&file) {
+            Ok(content) => Ok(Self { content }),
+            Err(err) => bail!("read ssh config file {:?} failed, {:?}", file, err),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn from_content(content: impl Into<String>) -> Self {
+        Self {
+            content: content.into(),
+        }
+    }
+
+    pub fn resolve(&self, alias: &str) -> SshConfigHost {
+        let mut resolved = SshConfigHost::default();
+        // lines before the first Host apply to every host.
+        let mut active = true;
+        for line in self.content.lines() {
+            let (key, value) = match split_line(line) {
+                Some(kv) => kv,
+                None => continue,
+            };
+            match key.as_str() {
+                "host" => {
+                    let patterns: Vec<&str> = value.split_whitespace().collect();
+                    active = host_matches(&patterns, alias);
+                }
+                "match" => {
+                    trace!("Match block isn't supported, skipped: {}", value);
+                    active = false;
+                }
+                _ if !active => {}
+                "hostname" => set_once(&mut resolved.host_name, value.replace("%h", alias)),
+                "port" if resolved.port.is_none() => resolved.port = value.parse().ok(),
+                "user" => set_once(&mut resolved.user, value),
+                "identityfile" => set_once(&mut resolved.identity_file, expand_tilde(&value)),
+                "proxyjump" => set_once(&mut resolved.proxy_jump, value),
+                "connecttimeout" if resolved.connect_timeout.is_none() => {
+                    resolved.connect_timeout = value.parse().ok()
+                }
+                _ => {}
+            }
+        }
+        resolved
+    }
+}
+
+fn set_once(field: &mut Option<String>, value: String) {
+    if field.is_none() {
+        field.replace(value);
+    }
+}
+
+/// Both "Key value" and "Key=value" are allowed, the value may be quoted.
+fn split_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let idx = line.find(|c: char| c.is_whitespace() || c == '=')?;
+    let key = line[..idx].to_lowercase();
+    let value = line[idx..]
+        .trim_start_matches(|c: char| c.is_whitespace() || c == '=')
+        .trim()
+        .trim_matches('"');
+    Some((key, value.to_string()))
+}
+
+fn expand_tilde(path: &str) -> String {
+    if let Some(rest) = path.strip_prefix("~/") {
+        if let Some(home) = dirs::home_dir() {
+            return home.join(rest).to_string_lossy().to_string();
+        }
+    }
+    path.to_string()
+}
+
+fn wildcard_match(pattern: &[u8], s: &[u8]) -> bool {
+    match (pattern.first(), s.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            wildcard_match(&pattern[1..], s) || (!s.is_empty() && wildcard_match(pattern, &s[1..]))
+        }
+        (Some(b'?'), Some(_)) => wildcard_match(&pattern[1..], &s[1..]),
+        (Some(p), Some(c)) if p.eq_ignore_ascii_case(c) => wildcard_match(&pattern[1..], &s[1..]),
+        _ => false,
+    }
+}
+
+/// Any negated pattern matching wins over the positive ones.
+fn host_matches(patterns: &[&str], alias: &str) -> bool {
+    let mut matched = false;
+    for p in patterns {
+        if let Some(negated) = p.strip_prefix('!') {
+            if wildcard_match(negated.as_bytes(), alias.as_bytes()) {
+                return false;
+            }
+        } else if wildcard_match(p.as_bytes(), alias.as_bytes()) {
+            matched = true;
+        }
+    }
+    matched
+}
+
+pub fn parse_proxy_jump(value: &str) -> Vec<ProxyJumpHop> {
+    if value.eq_ignore_ascii_case("none") {
+        return Vec::new();
+    }
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|hop| {
+            let hop = hop.trim_start_matches("ssh://");
+            let (user, host_port) = match hop.find('@') {
+                Some(idx) => (Some(hop[..idx].to_string()), &hop[idx + 1..]),
+                None => (None, hop),
+            };
+            let (host, port) = if host_port.starts_with('[') {
+                // [ipv6]:port
+                match host_port.find(']') {
+                    Some(idx) => (
+                        &host_port[1..idx],
+                        host_port[idx + 1..].trim_start_matches(':').parse().ok(),
+                    ),
+                    None => (host_port, None),
+                }
+            } else if host_port.matches(':').count() == 1 {
+                let idx = host_port.find(':').expect("colon should exist.");
+                (&host_port[..idx], host_port[idx + 1..].parse().ok())
+            } else {
+                (host_port, None)
+            };
+            ProxyJumpHop {
+                user,
+                host: host.to_string(),
+                port,
+            }
+        })
+        .collect()
+}
+
+/// The login name, OpenSSH falls back to it when a hop has no User.
+pub fn local_user_name() -> Option<String> {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+# global defaults go first.
+ConnectTimeout 10
+
+Host backup-* !backup-skip
+    User backup
+    Port=2222
+
+Host backup-db
+    HostName db.internal.example.com
+    IdentityFile "/keys/db_rsa"
+    ProxyJump jump@bastion.example.com:2200,inner
+
+Host *
+    User nobody
+    IdentityFile /keys/id_rsa
+"#;
+
+    #[test]
+    fn t_resolve_ssh_config() {
+        let config = SshConfig::from_content(CONFIG);
+        let db = config.resolve("backup-db");
+        assert_eq!(
+            db,
+            SshConfigHost {
+                host_name: Some("db.internal.example.com".to_string()),
+                port: Some(2222),
+                user: Some("backup".to_string()),
+                identity_file: Some("/keys/db_rsa".to_string()),
+                proxy_jump: Some("jump@bastion.example.com:2200,inner".to_string()),
+                connect_timeout: Some(10),
+            }
+        );
+
+        let skipped = config.resolve("backup-skip");
+        assert_eq!(skipped.user, Some("nobody".to_string()));
+        assert_eq!(skipped.port, None);
+        assert_eq!(skipped.host_name, None);
+
+        let hops = parse_proxy_jump(db.proxy_jump.as_ref().unwrap());
+        assert_eq!(
+            hops,
+            vec![
+                ProxyJumpHop {
+                    user: Some("jump".to_string()),
+                    host: "bastion.example.com".to_string(),
+                    port: Some(2200),
+                },
+                ProxyJumpHop {
+                    user: None,
+                    host: "inner".to_string(),
+                    port: None,
+                },
+            ]
+        );
+        assert!(parse_proxy_jump("none").is_empty());
+    }
+}
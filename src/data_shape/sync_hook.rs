@@ -0,0 +1,206 @@
+//! Commands run on the source host around the iteration of the directories,
+//! like dumping a database or freezing a filesystem before the files are read.
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// the scope of the hooks in the server yml, the directory hooks are scoped by the from_dir.
+pub const SERVER_SCOPE: &str = "server";
+
+/// only the tail of the output is kept.
+const MAX_OUTPUT_LEN: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookStage {
+    PreSync,
+    PostSync,
+}
+
+/// What one command did, sent back to the pulling side.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HookResult {
+    pub stage: HookStage,
+    pub scope: String,
+    pub command: String,
+    /// None if it can't start, is killed by a signal or timed out.
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl HookResult {
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    pub fn summary(&self) -> String {
+        let exit = match self.exit_code {
+            Some(code) => format!("exit code {}", code),
+            None => "no exit code".to_string(),
+        };
+        format!(
+            "{:?} hook of {}, `{}`, {}, stdout: {:?}, stderr: {:?}",
+            self.stage,
+            self.scope,
+            self.command,
+            exit,
+            self.stdout.trim_end(),
+            self.stderr.trim_end()
+        )
+    }
+}
+
+#[cfg(unix)]
+fn shell() -> Command {
+    let mut c = Command::new("sh");
+    c.arg("-c");
+    c
+}
+
+#[cfg(windows)]
+fn shell() -> Command {
+    let mut c = Command::new("cmd");
+    c.arg("/C");
+    c
+}
+
+fn output_tail(bytes: &[u8]) -> String {
+    let start = bytes.len().saturating_sub(MAX_OUTPUT_LEN);
+    String::from_utf8_lossy(&bytes[start..]).to_string()
+}
+
+/// Read in a thread, the pipes are drained while waiting for the child.
+fn read_in_thread<R: Read + Send + 'static>(r: Option<R>) -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut r) = r {
+            r.read_to_end(&mut buf).ok();
+        }
+        tx.send(buf).ok();
+    });
+    rx
+}
+
+/// The exit code, stdout and stderr. It's killed when running longer than the timeout.
+fn run_command(
+    command: &str,
+    timeout: Duration,
+) -> Result<(Option<i32>, String, String), failure::Error> {
+    let mut child = shell()
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = read_in_thread(child.stdout.take());
+    let stderr = read_in_thread(child.stderr.take());
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if started.elapsed() >= timeout {
+            child.kill().ok();
+            child.wait()?;
+            break None;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    match status {
+        Some(status) => Ok((
+            status.code(),
+            output_tail(&stdout.recv().unwrap_or_default()),
+            output_tail(&stderr.recv().unwrap_or_default()),
+        )),
+        None => {
+            // the children it started may still hold the pipes.
+            let wait = Duration::from_secs(1);
+            let mut err = output_tail(&stderr.recv_timeout(wait).unwrap_or_default());
+            err.push_str(&format!("killed after {:?}.", timeout));
+            Ok((
+                None,
+                output_tail(&stdout.recv_timeout(wait).unwrap_or_default()),
+                err,
+            ))
+        }
+    }
+}
+
+/// Run the commands in order. The pre_sync ones stop at the first failure,
+/// the post_sync ones all run, they may be undoing what the pre_sync did.
+pub fn run_hooks(
+    commands: &[String],
+    stage: HookStage,
+    scope: &str,
+    timeout: Duration,
+) -> Vec<HookResult> {
+    let mut results = Vec::new();
+    for command in commands {
+        let result = match run_command(command, timeout) {
+            Ok((exit_code, stdout, stderr)) => HookResult {
+                stage,
+                scope: scope.to_string(),
+                command: command.clone(),
+                exit_code,
+                stdout,
+                stderr,
+            },
+            Err(err) => HookResult {
+                stage,
+                scope: scope.to_string(),
+                command: command.clone(),
+                exit_code: None,
+                stdout: String::new(),
+                stderr: format!("start command failed: {}", err),
+            },
+        };
+        let failed = !result.succeeded();
+        results.push(result);
+        if failed && stage == HookStage::PreSync {
+            break;
+        }
+    }
+    results
+}
+
+pub fn all_succeeded(results: &[HookResult]) -> bool {
+    results.iter().all(HookResult::succeeded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_run_hooks() {
+        let commands = vec![
+            "echo dumped".to_string(),
+            "exit 3".to_string(),
+            "echo never".to_string(),
+        ];
+        let timeout = Duration::from_secs(60);
+        let results = run_hooks(&commands, HookStage::PreSync, "/var/lib/db", timeout);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].stdout.trim(), "dumped");
+        assert_eq!(results[1].exit_code, Some(3));
+        assert!(!all_succeeded(&results));
+
+        let results = run_hooks(&commands, HookStage::PostSync, SERVER_SCOPE, timeout);
+        assert_eq!(results.len(), 3);
+        assert!(results[2].succeeded());
+
+        let started = Instant::now();
+        let commands = vec!["echo started; exec sleep 10".to_string()];
+        let timeout = Duration::from_millis(300);
+        let results = run_hooks(&commands, HookStage::PreSync, SERVER_SCOPE, timeout);
+        assert!(started.elapsed() < Duration::from_secs(5), "killed.");
+        assert_eq!(results[0].exit_code, None);
+        assert_eq!(results[0].stdout.trim(), "started");
+        assert!(results[0].stderr.contains("killed after"));
+    }
+}
@@ -0,0 +1,176 @@
+//! The sync.log in the working dir of a server, appended by each run and rotated like output.log.
+//! In the json format each line is a record that tools can ingest.
+use super::sync_hook::HookResult;
+use crate::log_util::{LogRotation, RotatingFile};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncLogFormat {
+    #[default]
+    Text,
+    /// one json object a line.
+    Json,
+}
+
+/// One transferred file.
+#[derive(Debug, Serialize)]
+pub struct FileRecord {
+    pub path: String,
+    pub size: u64,
+    /// the variant of FileChanged, like Len or Modified.
+    pub reason: String,
+    pub duration_ms: u64,
+    /// ok or failed.
+    pub result: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl FileRecord {
+    pub fn new(path: impl Into<String>, size: u64, reason: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            size,
+            reason: reason.into(),
+            duration_ms: 0,
+            result: "ok",
+            error: None,
+        }
+    }
+
+    pub fn failed(&mut self, error: impl Into<String>) {
+        self.result = "failed";
+        self.error.replace(error.into());
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Entry<'a> {
+    File {
+        time: DateTime<Local>,
+        #[serde(flatten)]
+        record: &'a FileRecord,
+    },
+    Hook {
+        time: DateTime<Local>,
+        #[serde(flatten)]
+        result: &'a HookResult,
+    },
+}
+
+pub struct SyncLog {
+    writer: LineWriter<RotatingFile>,
+    format: SyncLogFormat,
+}
+
+impl SyncLog {
+    pub fn open(
+        path: impl AsRef<Path>,
+        rotation: LogRotation,
+        format: SyncLogFormat,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            writer: LineWriter::new(RotatingFile::open(path, rotation)?),
+            format,
+        })
+    }
+
+    fn write_entry(&mut self, entry: &Entry, text: String) -> io::Result<()> {
+        match self.format {
+            SyncLogFormat::Text => writeln!(self.writer, "{}", text),
+            SyncLogFormat::Json => {
+                let line = serde_json::to_string(entry)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                writeln!(self.writer, "{}", line)
+            }
+        }
+    }
+
+    pub fn file(&mut self, record: &FileRecord) -> io::Result<()> {
+        let time = Local::now();
+        let result = match record.error.as_ref() {
+            Some(err) => format!("{}: {}", record.result, err),
+            None => record.result.to_string(),
+        };
+        let text = format!(
+            "[{}]{}, {} bytes, {}, {}ms, {}",
+            time, record.path, record.size, record.reason, record.duration_ms, result
+        );
+        self.write_entry(&Entry::File { time, record }, text)
+    }
+
+    pub fn hook(&mut self, result: &HookResult) -> io::Result<()> {
+        let time = Local::now();
+        let text = format!("[{}]{}", time, result.summary());
+        self.write_entry(&Entry::Hook { time, result }, text)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_shape::sync_hook::HookStage;
+    use crate::develope::tutil;
+    use std::fs;
+
+    #[test]
+    fn t_sync_log() -> Result<(), failure::Error> {
+        let tu = tutil::TestDir::new();
+        let path = tu.tmp_dir_path().join("sync.log");
+        let mut record = FileRecord::new("a/b.txt", 3, "Len");
+        record.duration_ms = 5;
+        for _ in 0..2 {
+            let mut log = SyncLog::open(&path, LogRotation::default(), SyncLogFormat::Json)?;
+            log.file(&record)?;
+            log.flush()?;
+        }
+        record.failed("disk full.");
+        let mut log = SyncLog::open(&path, LogRotation::default(), SyncLogFormat::Json)?;
+        log.file(&record)?;
+        log.hook(&HookResult {
+            stage: HookStage::PreSync,
+            scope: "server".to_string(),
+            command: "true".to_string(),
+            exit_code: Some(0),
+            stdout: String::new(),
+            stderr: String::new(),
+        })?;
+        log.flush()?;
+
+        let content = fs::read_to_string(&path)?;
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 4, "appended, not overwritten.");
+        assert_eq!(lines[0]["kind"], "file");
+        assert_eq!(lines[0]["path"], "a/b.txt");
+        assert_eq!(lines[0]["duration_ms"], 5);
+        assert!(lines[0].get("error").is_none());
+        assert_eq!(lines[2]["result"], "failed");
+        assert_eq!(lines[2]["error"], "disk full.");
+        assert_eq!(lines[3]["kind"], "hook");
+        assert_eq!(lines[3]["exit_code"], 0);
+
+        let text_path = tu.tmp_dir_path().join("text.log");
+        let mut log = SyncLog::open(&text_path, LogRotation::default(), SyncLogFormat::Text)?;
+        log.file(&record)?;
+        log.flush()?;
+        let text = fs::read_to_string(&text_path)?;
+        assert!(
+            text.ends_with("a/b.txt, 3 bytes, Len, 5ms, failed: disk full.\n"),
+            "{}",
+            text
+        );
+        Ok(())
+    }
+}
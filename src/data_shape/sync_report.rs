@@ -0,0 +1,210 @@
+//! A report for each run of client-pull-loop or client-push-loop, saved as json in the reports directory.
+use super::FileChanged;
+use chrono::{DateTime, Local};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const REPORT_PREFIX: &str = "sync_";
+pub const REPORT_POSTFIX: &str = ".json";
+/// the oldest ones are removed when saving a new one.
+const MAX_REPORTS: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncDirection {
+    Pull,
+    Push,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SyncReport {
+    pub server: String,
+    pub direction: SyncDirection,
+    pub started_at: DateTime<Local>,
+    pub finished_at: Option<DateTime<Local>>,
+    pub duration_secs: f64,
+    pub files_seen: u64,
+    pub changed: u64,
+    pub unchanged: u64,
+    pub failed: u64,
+    pub bytes_transferred: u64,
+    /// the changed files counted by the variant of FileChanged, like Len or Modified.
+    pub changed_reasons: BTreeMap<String, u64>,
+    pub errors: Vec<String>,
+}
+
+/// The variant name of a FileChanged, also works with the debug format of it from the other side.
+pub fn changed_reason(debug_str: &str) -> String {
+    debug_str
+        .split('(')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_string()
+}
+
+impl SyncReport {
+    pub fn new(server: impl Into<String>, direction: SyncDirection) -> Self {
+        Self {
+            server: server.into(),
+            direction,
+            started_at: Local::now(),
+            finished_at: None,
+            duration_secs: 0.0,
+            files_seen: 0,
+            changed: 0,
+            unchanged: 0,
+            failed: 0,
+            bytes_transferred: 0,
+            changed_reasons: BTreeMap::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn record_changed(&mut self, reason: String) {
+        self.changed += 1;
+        *self.changed_reasons.entry(reason).or_insert(0) += 1;
+    }
+
+    pub fn record_file_changed(&mut self, file_changed: &FileChanged) {
+        match file_changed {
+            FileChanged::NoChange => self.unchanged += 1,
+            fc => self.record_changed(changed_reason(&format!("{:?}", fc))),
+        }
+    }
+
+    /// A file can't be read, sent or written.
+    pub fn record_failed(&mut self, error: impl Into<String>) {
+        self.failed += 1;
+        self.errors.push(error.into());
+    }
+
+    pub fn finish(&mut self) {
+        let now = Local::now();
+        self.duration_secs = (now - self.started_at)
+            .to_std()
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        self.finished_at.replace(now);
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.failed == 0 && self.errors.is_empty()
+    }
+
+    /// The start time, also in the file name of the report.
+    pub fn run_id(&self) -> String {
+        self.started_at.format("%Y%m%d%H%M%S%.3f").to_string()
+    }
+
+    pub fn file_name(&self) -> String {
+        format!("{}{}{}", REPORT_PREFIX, self.run_id(), REPORT_POSTFIX)
+    }
+
+    pub fn save(&self, reports_dir: &Path) -> Result<PathBuf, failure::Error> {
+        let path = reports_dir.join(self.file_name());
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        let reports = list_reports(reports_dir)?;
+        if reports.len() > MAX_REPORTS {
+            for old in &reports[..reports.len() - MAX_REPORTS] {
+                if let Err(err) = fs::remove_file(old) {
+                    warn!("remove old report {:?} failed: {}", old, err);
+                }
+            }
+        }
+        Ok(path)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, failure::Error> {
+        let content = fs::read_to_string(path)?;
+        match serde_json::from_str(&content) {
+            Ok(report) => Ok(report),
+            Err(err) => bail!("parse report {:?} failed: {}", path, err),
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        let finished_at = self
+            .finished_at
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| "unfinished".to_string());
+        writeln!(text, "server: {}, {:?}", self.server, self.direction).ok();
+        writeln!(
+            text,
+            "started: {}, finished: {}, duration: {:.1}s",
+            self.started_at.format("%Y-%m-%d %H:%M:%S"),
+            finished_at,
+            self.duration_secs
+        )
+        .ok();
+        writeln!(
+            text,
+            "files: {}, changed: {}, unchanged: {}, failed: {}, bytes transferred: {}",
+            self.files_seen, self.changed, self.unchanged, self.failed, self.bytes_transferred
+        )
+        .ok();
+        for (reason, count) in self.changed_reasons.iter() {
+            writeln!(text, "  changed by {}: {}", reason, count).ok();
+        }
+        if !self.errors.is_empty() {
+            writeln!(text, "errors:").ok();
+            for error in self.errors.iter() {
+                writeln!(text, "  {}", error).ok();
+            }
+        }
+        text
+    }
+}
+
+/// Oldest first, the names sort by the start time.
+pub fn list_reports(reports_dir: &Path) -> Result<Vec<PathBuf>, failure::Error> {
+    let mut reports: Vec<PathBuf> = fs::read_dir(reports_dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| n.starts_with(REPORT_PREFIX) && n.ends_with(REPORT_POSTFIX))
+                .unwrap_or(false)
+        })
+        .collect();
+    reports.sort();
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::develope::tutil;
+
+    #[test]
+    fn t_sync_report() -> Result<(), failure::Error> {
+        let tu = tutil::TestDir::new();
+        let reports_dir = tu.tmp_dir_path();
+        let mut report = SyncReport::new("a.example.com", SyncDirection::Pull);
+        report.files_seen = 3;
+        report.record_file_changed(&FileChanged::NoChange);
+        report.record_file_changed(&FileChanged::Len(1, 2));
+        report.record_changed(changed_reason("Modified(Some(1), Some(2))"));
+        report.record_failed("copy_to_file failed.");
+        report.finish();
+        assert!(!report.succeeded());
+
+        let path = report.save(reports_dir)?;
+        assert_eq!(list_reports(reports_dir)?, vec![path.clone()]);
+        let loaded = SyncReport::load(&path)?;
+        assert_eq!(loaded.changed, 2);
+        assert_eq!(loaded.unchanged, 1);
+        assert_eq!(loaded.changed_reasons.get("Len"), Some(&1));
+        assert_eq!(loaded.changed_reasons.get("Modified"), Some(&1));
+        let text = loaded.to_text();
+        assert!(text.contains("changed by Len: 1"), "{}", text);
+        assert!(text.contains("copy_to_file failed."));
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+//! An advisory lock on a file for the whole run, so two processes don't work on the same server at once.
+//! The owner's pid and start time are in the file for the message to the one refused.
+//! The OS releases the lock when the process dies, if the file system can't lock,
+//! a lock is taken as stale when the recorded pid isn't alive anymore.
+use chrono::{DateTime, Local};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Fail)]
+pub enum WorkLockError {
+    #[fail(display = "{} is busy since {} (pid {}).", _0, _1, _2)]
+    Busy(String, String, u32),
+    #[fail(display = "{} is busy, lock file: {:?}.", _0, _1)]
+    BusyUnknownOwner(String, PathBuf),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LockOwner {
+    pub pid: u32,
+    pub started_at: DateTime<Local>,
+}
+
+#[derive(Debug)]
+pub struct WorkLock {
+    file: fs::File,
+    path: PathBuf,
+}
+
+impl WorkLock {
+    /// The name is what's busy in the error, like "server a.example.com".
+    pub fn acquire(path: impl AsRef<Path>, name: &str) -> Result<Self, failure::Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let previous = read_owner(&mut file);
+        match try_lock(&file) {
+            Ok(()) => {
+                if let Some(previous) = previous.as_ref() {
+                    warn!(
+                        "take over the stale lock of pid {} since {}, {:?}",
+                        previous.pid, previous.started_at, path
+                    );
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                return Err(busy(name, &path, previous).into());
+            }
+            Err(err) => {
+                warn!(
+                    "lock {:?} failed: {}, check the pid in it instead.",
+                    path, err
+                );
+                match previous {
+                    Some(previous) if is_pid_alive(previous.pid) => {
+                        return Err(busy(name, &path, Some(previous)).into());
+                    }
+                    Some(previous) => warn!(
+                        "pid {} in {:?} is gone, take over the lock.",
+                        previous.pid, path
+                    ),
+                    None => (),
+                }
+            }
+        }
+        let owner = LockOwner {
+            pid: std::process::id(),
+            started_at: Local::now(),
+        };
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        serde_json::to_writer(&mut file, &owner)?;
+        file.flush()?;
+        trace!("locked {:?}: {:?}", path, owner);
+        Ok(Self { file, path })
+    }
+}
+
+impl Drop for WorkLock {
+    /// The file is left in place, removing it would let another process lock a new file
+    /// while one still waits on the old one.
+    fn drop(&mut self) {
+        if let Err(err) = self.file.set_len(0) {
+            warn!("clear lock file {:?} failed: {}", self.path, err);
+        }
+        unlock(&self.file);
+        trace!("unlocked {:?}", self.path);
+    }
+}
+
+fn busy(name: &str, path: &Path, owner: Option<LockOwner>) -> WorkLockError {
+    match owner {
+        Some(owner) => WorkLockError::Busy(
+            name.to_string(),
+            owner.started_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+            owner.pid,
+        ),
+        None => WorkLockError::BusyUnknownOwner(name.to_string(), path.to_path_buf()),
+    }
+}
+
+/// None if the file is empty, the lock isn't held then or the holder is still writing to it.
+fn read_owner(file: &mut fs::File) -> Option<LockOwner> {
+    let mut content = String::new();
+    file.read_to_string(&mut content).ok()?;
+    if content.trim().is_empty() {
+        return None;
+    }
+    match serde_json::from_str(&content) {
+        Ok(owner) => Some(owner),
+        Err(err) => {
+            warn!("unknown content in the lock file: {:?}, {}", content, err);
+            None
+        }
+    }
+}
+
+#[cfg(unix)]
+fn try_lock(file: &fs::File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(unix)]
+fn unlock(file: &fs::File) {
+    use std::os::unix::io::AsRawFd;
+    unsafe {
+        libc::flock(file.as_raw_fd(), libc::LOCK_UN);
+    }
+}
+
+#[cfg(unix)]
+pub fn is_pid_alive(pid: u32) -> bool {
+    // signal 0 checks the existence only, EPERM means it exists but belongs to another user.
+    let alive = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// The range locked is far beyond the content, a locked range can't be read by others on windows.
+#[cfg(windows)]
+fn lock_overlapped() -> winapi::um::minwinbase::OVERLAPPED {
+    let mut overlapped: winapi::um::minwinbase::OVERLAPPED = unsafe { std::mem::zeroed() };
+    unsafe {
+        overlapped.u.s_mut().OffsetHigh = 0x7fff_ffff;
+    }
+    overlapped
+}
+
+#[cfg(windows)]
+fn try_lock(file: &fs::File) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY};
+    let mut overlapped = lock_overlapped();
+    let locked = unsafe {
+        winapi::um::fileapi::LockFileEx(
+            file.as_raw_handle() as _,
+            LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY,
+            0,
+            1,
+            0,
+            &mut overlapped,
+        )
+    };
+    if locked != 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(winapi::shared::winerror::ERROR_LOCK_VIOLATION as i32) {
+        Err(io::Error::new(io::ErrorKind::WouldBlock, err))
+    } else {
+        Err(err)
+    }
+}
+
+#[cfg(windows)]
+fn unlock(file: &fs::File) {
+    use std::os::windows::io::AsRawHandle;
+    let mut overlapped = lock_overlapped();
+    unsafe {
+        winapi::um::fileapi::UnlockFileEx(file.as_raw_handle() as _, 0, 1, 0, &mut overlapped);
+    }
+}
+
+#[cfg(windows)]
+pub fn is_pid_alive(pid: u32) -> bool {
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::minwinbase::STILL_ACTIVE;
+    use winapi::um::processthreadsapi::{GetExitCodeProcess, OpenProcess};
+    use winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION;
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle.is_null() {
+            return false;
+        }
+        let mut code = 0;
+        let alive = GetExitCodeProcess(handle, &mut code) != 0 && code == STILL_ACTIVE;
+        CloseHandle(handle);
+        alive
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::develope::tutil;
+
+    #[test]
+    fn t_work_lock() -> Result<(), failure::Error> {
+        let tu = tutil::TestDir::new();
+        let path = tu.tmp_dir_path().join("working.lock");
+        {
+            let _lock = WorkLock::acquire(&path, "server a")?;
+            let err = WorkLock::acquire(&path, "server a").expect_err("should be busy.");
+            let message = err.to_string();
+            assert!(
+                message.starts_with("server a is busy since "),
+                "{}",
+                message
+            );
+            assert!(message.contains(&format!("(pid {})", std::process::id())));
+        }
+        // released by drop.
+        let _lock = WorkLock::acquire(&path, "server a")?;
+        drop(_lock);
+
+        // left by a crashed process, no OS lock is on it.
+        let stale = LockOwner {
+            pid: u32::MAX / 2,
+            started_at: Local::now(),
+        };
+        fs::write(&path, serde_json::to_string(&stale)?)?;
+        assert!(!is_pid_alive(stale.pid));
+        assert!(is_pid_alive(std::process::id()));
+        let _lock = WorkLock::acquire(&path, "server a")?;
+        assert!(fs::read_to_string(&path)?.contains(&std::process::id().to_string()));
+        Ok(())
+    }
+}
@@ -1,6 +1,33 @@
+use std::fs;
 use std::io::{self, Write};
 use crate::data_shape::{Indicator};
 
+/// A layer of the archive writer, like the compression or the encryption.
+/// Finishing it finishes the inner ones too, so a failed seal or flush fails the archive
+/// rather than being lost in a drop.
+pub trait FinishWrite: Write {
+    fn finish_write(&mut self) -> io::Result<()>;
+}
+
+impl FinishWrite for fs::File {
+    fn finish_write(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.sync_all()
+    }
+}
+
+impl FinishWrite for io::Sink {
+    fn finish_write(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: FinishWrite + ?Sized> FinishWrite for Box<W> {
+    fn finish_write(&mut self) -> io::Result<()> {
+        (**self).finish_write()
+    }
+}
+
 pub struct ProgressWriter<'a, T> where T: Write {
     w: T,
     c: &'a Indicator,
@@ -22,4 +49,10 @@ impl<'a, T> Write for ProgressWriter<'a, T> where T: Write {
     fn flush(&mut self) -> io::Result<()> {
         self.w.flush()
      }
-}
\ No newline at end of file
+}
+
+impl<'a, T> FinishWrite for ProgressWriter<'a, T> where T: FinishWrite {
+    fn finish_write(&mut self) -> io::Result<()> {
+        self.w.finish_write()
+    }
+}
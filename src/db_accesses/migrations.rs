@@ -0,0 +1,230 @@
+//! The schema of the sqlite database as ordered migrations, the applied ones are recorded in schema_version.
+//! Append a new migration for a change, never edit the applied ones.
+//! The tables of the first ones use IF NOT EXISTS, the databases created before the migrations are adopted.
+use chrono::Local;
+use rusqlite::{params, Connection, TransactionBehavior, NO_PARAMS};
+use serde::Serialize;
+
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "directories, file items and schedules",
+        sql: "CREATE TABLE IF NOT EXISTS directory (
+                id  INTEGER PRIMARY KEY,
+                path TEXT NOT NULL UNIQUE
+                );
+             CREATE TABLE IF NOT EXISTS relative_file_item (
+                   id              INTEGER PRIMARY KEY,
+                   path            TEXT NOT NULL,
+                   sha1            TEXT,
+                   len             INTEGER DEFAULT 0,
+                   time_modified   TEXT,
+                   time_created    TEXT,
+                   changed         BOOLEAN,
+                   confirmed       BOOLEAN,
+                   dir_id          INTEGER NOT NULL,
+                   FOREIGN KEY(dir_id) REFERENCES directory(id)
+                   );
+             CREATE UNIQUE INDEX IF NOT EXISTS dir_path ON relative_file_item (path, dir_id);
+             CREATE TABLE IF NOT EXISTS schedule_done (
+                  id  INTEGER PRIMARY KEY,
+                  server_yml_path TEXT NOT NULL,
+                  task_name TEXT NOT NULL,
+                  time_execution   TEXT,
+                  done BOOLEAN,
+                  CONSTRAINT server_task_name UNIQUE (server_yml_path, task_name)
+                  );",
+    },
+    Migration {
+        version: 2,
+        name: "file version catalog",
+        sql: "CREATE TABLE IF NOT EXISTS file_version (
+                  id  INTEGER PRIMARY KEY,
+                  server TEXT NOT NULL,
+                  path TEXT NOT NULL,
+                  version INTEGER NOT NULL,
+                  len INTEGER DEFAULT 0,
+                  time_modified TEXT,
+                  sha1 TEXT,
+                  run_id TEXT NOT NULL,
+                  location TEXT NOT NULL,
+                  archive TEXT,
+                  time_recorded TEXT NOT NULL,
+                  CONSTRAINT server_path_version UNIQUE (server, path, version)
+                  );
+             CREATE INDEX IF NOT EXISTS file_version_path ON file_version (path);",
+    },
+    Migration {
+        version: 3,
+        name: "sha1 digest cache",
+        sql: "CREATE TABLE IF NOT EXISTS digest_cache (
+                  path TEXT PRIMARY KEY,
+                  len INTEGER NOT NULL,
+                  mtime_ns INTEGER NOT NULL,
+                  inode INTEGER NOT NULL,
+                  sha1 TEXT NOT NULL
+                  );",
+    },
+];
+
+const CREATE_SCHEMA_VERSION_SQL: &str = "CREATE TABLE IF NOT EXISTS schema_version (
+                  version INTEGER PRIMARY KEY,
+                  name TEXT NOT NULL,
+                  time_applied TEXT NOT NULL
+                  );";
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// 0 for a database without any migration applied.
+pub fn current_version(conn: &Connection) -> Result<u32, failure::Error> {
+    conn.execute_batch(CREATE_SCHEMA_VERSION_SQL)?;
+    let version: i64 = conn.query_row(
+        "SELECT IFNULL(MAX(version), 0) FROM schema_version",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    Ok(version as u32)
+}
+
+#[derive(Debug, Serialize)]
+pub struct MigrationStatus {
+    pub version: u32,
+    pub name: &'static str,
+    /// None if it's pending.
+    pub applied: Option<String>,
+}
+
+pub fn status(conn: &Connection) -> Result<Vec<MigrationStatus>, failure::Error> {
+    conn.execute_batch(CREATE_SCHEMA_VERSION_SQL)?;
+    let mut stmt = conn.prepare("SELECT time_applied FROM schema_version WHERE version = ?1")?;
+    MIGRATIONS
+        .iter()
+        .map(|m| {
+            let applied = match stmt.query_row(params![m.version], |row| row.get(0)) {
+                Ok(applied) => Some(applied),
+                Err(rusqlite::Error::QueryReturnedNoRows) => None,
+                Err(err) => return Err(err.into()),
+            };
+            Ok(MigrationStatus {
+                version: m.version,
+                name: m.name,
+                applied,
+            })
+        })
+        .collect()
+}
+
+/// Apply the pending migrations in order, each in a transaction. Returns the applied ones.
+/// The version is read after BEGIN IMMEDIATE took the write lock, two processes starting
+/// at the same time don't apply a migration twice.
+pub fn migrate(conn: &mut Connection) -> Result<Vec<&'static Migration>, failure::Error> {
+    let mut applied = Vec::new();
+    loop {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let current = current_version(&tx)?;
+        if current > latest_version() {
+            bail!(
+                "the schema version of the database is {}, newer than {} this program knows.",
+                current,
+                latest_version()
+            );
+        }
+        let m = match MIGRATIONS.iter().find(|m| m.version > current) {
+            Some(m) => m,
+            None => {
+                tx.commit()?;
+                break;
+            }
+        };
+        if let Err(err) = tx.execute_batch(m.sql) {
+            bail!("migration {} {} failed: {}", m.version, m.name, err);
+        }
+        tx.execute(
+            "INSERT INTO schema_version (version, name, time_applied) VALUES (?1, ?2, ?3)",
+            params![m.version, m.name, Local::now().to_rfc3339()],
+        )?;
+        tx.commit()?;
+        applied.push(m);
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::develope::tutil;
+    use std::thread;
+
+    #[test]
+    fn t_migrations() -> Result<(), failure::Error> {
+        let versions: Vec<u32> = MIGRATIONS.iter().map(|m| m.version).collect();
+        assert_eq!(
+            versions,
+            (1..=latest_version()).collect::<Vec<_>>(),
+            "numbered from 1 without a gap."
+        );
+
+        let mut conn = Connection::open_in_memory()?;
+        // like the ones created before the migrations.
+        conn.execute_batch(MIGRATIONS[0].sql)?;
+        conn.execute("INSERT INTO directory (path) VALUES ('/a')", NO_PARAMS)?;
+        assert_eq!(current_version(&conn)?, 0);
+        assert!(status(&conn)?.iter().all(|s| s.applied.is_none()));
+
+        assert_eq!(migrate(&mut conn)?.len(), MIGRATIONS.len());
+        assert_eq!(current_version(&conn)?, latest_version());
+        assert!(status(&conn)?.iter().all(|s| s.applied.is_some()));
+        let dirs: i64 = conn.query_row("SELECT COUNT(*) FROM directory", NO_PARAMS, |row| {
+            row.get(0)
+        })?;
+        assert_eq!(dirs, 1, "the existing rows are kept.");
+        conn.execute_batch("SELECT sha1 FROM digest_cache; SELECT archive FROM file_version;")?;
+
+        assert!(migrate(&mut conn)?.is_empty(), "nothing pending.");
+
+        conn.execute(
+            "INSERT INTO schema_version (version, name, time_applied) VALUES (?1, 'future', '')",
+            params![latest_version() + 1],
+        )?;
+        assert!(migrate(&mut conn).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn t_migrate_concurrently() -> Result<(), failure::Error> {
+        let tu = tutil::TestDir::new();
+        let db_file = tu.tmp_dir_path().join("a.db");
+        let migrators: Vec<_> = (0..4)
+            .map(|_| {
+                let db_file = db_file.clone();
+                thread::spawn(move || {
+                    let mut conn = Connection::open(&db_file).map_err(|e| e.to_string())?;
+                    migrate(&mut conn)
+                        .map(|applied| applied.len())
+                        .map_err(|e| e.to_string())
+                })
+            })
+            .collect();
+        let mut applied = 0;
+        for migrator in migrators {
+            applied += migrator
+                .join()
+                .expect("migrator thread panicked.")
+                .map_err(failure::err_msg)?;
+        }
+        assert_eq!(applied, MIGRATIONS.len(), "each applied once.");
+        assert_eq!(
+            current_version(&Connection::open(&db_file)?)?,
+            latest_version()
+        );
+        Ok(())
+    }
+}
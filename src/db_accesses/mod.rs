@@ -1,3 +1,4 @@
+pub mod migrations;
 pub mod scheduler_util;
 pub mod sqlite_access;
 
@@ -6,7 +7,10 @@ use crate::data_shape::SlashPath;
 use chrono::{DateTime, Local, SecondsFormat, Utc};
 use log::*;
 use r2d2;
-use std::path::PathBuf;
+use serde::Serialize;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use encoding_rs::*;
 
 #[derive(Debug)]
@@ -181,6 +185,81 @@ impl RelativeFileItemInDb {
     }
 }
 
+/// A version of a file in the catalog, the version counts up for each transfer of the path.
+#[derive(Debug, Serialize)]
+pub struct FileVersionInDb {
+    pub id: i64,
+    /// the host of the server.
+    pub server: String,
+    /// the path on the source host.
+    pub path: String,
+    pub version: i64,
+    pub len: i64,
+    pub modified: Option<DateTime<Utc>>,
+    pub sha1: Option<String>,
+    /// the run_id of the SyncReport.
+    pub run_id: String,
+    /// where the transfer put it.
+    pub location: String,
+    /// the first archive made after it's transferred.
+    pub archive: Option<String>,
+    pub recorded: DateTime<Utc>,
+}
+
+impl FileVersionInDb {
+    pub fn new(
+        server: impl Into<String>,
+        path: impl Into<String>,
+        len: u64,
+        run_id: impl Into<String>,
+        location: impl Into<String>,
+    ) -> Self {
+        Self {
+            id: 0,
+            server: server.into(),
+            path: path.into(),
+            version: 0,
+            len: len as i64,
+            modified: None,
+            sha1: None,
+            run_id: run_id.into(),
+            location: location.into(),
+            archive: None,
+            recorded: Utc::now(),
+        }
+    }
+}
+
+/// A cached sha1 is good as long as the file has the same key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DigestKey {
+    pub path: String,
+    pub len: u64,
+    pub mtime_ns: i64,
+    /// 0 where it's not available.
+    pub inode: u64,
+}
+
+impl DigestKey {
+    pub fn new(path: &Path, meta: &Metadata) -> Self {
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(meta);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Self {
+            path: path.to_string_lossy().to_string(),
+            len: meta.len(),
+            mtime_ns: meta
+                .modified()
+                .ok()
+                .and_then(|st| st.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|d| d.as_nanos() as i64)
+                .unwrap_or_default(),
+            inode,
+        }
+    }
+}
+
 pub trait DbAccess<M>: Clone + 'static
 where
     M: r2d2::ManageConnection,
@@ -197,6 +276,7 @@ where
         dir_id: i64,
         path: impl AsRef<str>,
     ) -> Result<RelativeFileItemInDb, failure::Error>;
+    /// Apply the pending migrations, safe to call on an existing database.
     fn create_database(&self) -> Result<(), failure::Error>;
     fn find_directory(&self, path: impl AsRef<str>) -> Result<i64, failure::Error>;
     fn count_directory(&self) -> Result<u64, failure::Error>;
@@ -235,6 +315,27 @@ where
     fn confirm_all(&self) -> Result<u64, failure::Error>;
 
     fn exclude_by_sql(&self, select_id_sql: impl AsRef<str>) -> Result<u64, failure::Error>;
+
+    /// The version and the id are assigned on insert.
+    fn insert_file_version(&self, fv: FileVersionInDb) -> Result<FileVersionInDb, failure::Error>;
+    /// Set the archive of the versions of the server without one, returns how many are set.
+    fn set_archive_of_unarchived(
+        &self,
+        server: impl AsRef<str>,
+        archive: impl AsRef<str>,
+    ) -> Result<u64, failure::Error>;
+    /// The path matches exactly or as a glob.
+    fn find_file_versions(
+        &self,
+        server: Option<&str>,
+        path: impl AsRef<str>,
+    ) -> Result<Vec<FileVersionInDb>, failure::Error>;
+
+    /// None if the path isn't cached or is cached with another key.
+    fn find_digest(&self, key: &DigestKey) -> Option<String>;
+    /// Replace the one of the same path.
+    fn save_digest(&self, key: &DigestKey, sha1: &str) -> Result<(), failure::Error>;
+    fn delete_digest(&self, path: &str) -> Result<(), failure::Error>;
 }
 
 #[cfg(test)]
@@ -86,6 +86,33 @@ where
     b
 }
 
+/// The next run need_execute waits for, without touching the records.
+/// Falls back to the upcoming time of the expression if nothing pending is recorded.
+pub fn next_execute<M, D>(
+    db_access: Option<&D>,
+    server_yml_path: impl AsRef<str>,
+    task_name: impl AsRef<str>,
+    expression: impl AsRef<str>,
+) -> Option<DateTime<Local>>
+where
+    M: r2d2::ManageConnection,
+    D: DbAccess<M>,
+{
+    let pending = db_access
+        .and_then(|db_access| db_access.find_next_execute(server_yml_path, task_name))
+        .filter(|(_, next_execute, done)| !done && *next_execute > Local::now());
+    if let Some((_, next_execute, _)) = pending {
+        return Some(next_execute);
+    }
+    match Schedule::from_str(expression.as_ref()) {
+        Ok(schedule) => schedule.upcoming(Local).next(),
+        Err(err) => {
+            warn!("invalid cron expression {:?}: {}", expression.as_ref(), err);
+            None
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -155,6 +182,10 @@ mod tests {
         assert_eq!(db_access.count_next_execute()?, 1); // already insert new one.
         let ne = db_access.find_next_execute("a.yml", "d").unwrap();
         assert!(!ne.2, "not executed yet.");
+        assert_eq!(
+            next_execute(Some(&db_access), "a.yml", "d", &expression),
+            Some(ne.1)
+        );
 
         Ok(())
     }
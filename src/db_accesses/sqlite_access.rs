@@ -1,4 +1,7 @@
-use super::{CountItemParam, DbAccess, DbAction, RelativeFileItemInDb};
+use super::migrations::{self, Migration, MigrationStatus};
+use super::{
+    CountItemParam, DbAccess, DbAction, DigestKey, FileVersionInDb, RelativeFileItemInDb,
+};
 use chrono::{DateTime, Local};
 use failure;
 use log::*;
@@ -21,11 +24,20 @@ impl Clone for SqliteDbAccess {
 impl SqliteDbAccess {
     pub fn new(db_file: impl AsRef<Path>) -> Self {
         let db_file = db_file.as_ref();
+        // the server threads share the file, wait for each other rather than fail.
         let manager = SqliteConnectionManager::file(db_file)
-            .with_init(|c| c.execute_batch("PRAGMA foreign_keys=1;"));
+            .with_init(|c| c.execute_batch("PRAGMA foreign_keys=1; PRAGMA busy_timeout=5000;"));
         let pool = r2d2::Pool::new(manager).unwrap();
         Self(pool)
     }
+
+    /// Like new, then apply the pending migrations.
+    pub fn open(db_file: impl AsRef<Path>) -> Result<Self, failure::Error> {
+        let db_access = Self::new(db_file);
+        db_access.migrate()?;
+        Ok(db_access)
+    }
+
     #[allow(dead_code)]
     pub fn new_mem() -> Self {
         let manager = SqliteConnectionManager::memory()
@@ -37,6 +49,21 @@ impl SqliteDbAccess {
     pub fn get_pool(&self) -> &SqlitePool {
         &self.0
     }
+
+    /// Returns the applied migrations, empty if the schema is up to date.
+    pub fn migrate(&self) -> Result<Vec<&'static Migration>, failure::Error> {
+        let mut conn = self.0.get()?;
+        let applied = migrations::migrate(&mut conn)?;
+        for m in applied.iter() {
+            info!("applied migration {}: {}", m.version, m.name);
+        }
+        Ok(applied)
+    }
+
+    pub fn schema_status(&self) -> Result<Vec<MigrationStatus>, failure::Error> {
+        let conn = self.0.get()?;
+        migrations::status(&conn)
+    }
 }
 
 fn map_to_file_item(row: &Row) -> Result<RelativeFileItemInDb, rusqlite::Error> {
@@ -53,6 +80,22 @@ fn map_to_file_item(row: &Row) -> Result<RelativeFileItemInDb, rusqlite::Error>
     })
 }
 
+fn map_to_file_version(row: &Row) -> Result<FileVersionInDb, rusqlite::Error> {
+    Ok(FileVersionInDb {
+        id: row.get(0)?,
+        server: row.get(1)?,
+        path: row.get(2)?,
+        version: row.get(3)?,
+        len: row.get(4)?,
+        modified: row.get(5)?,
+        sha1: row.get(6)?,
+        run_id: row.get(7)?,
+        location: row.get(8)?,
+        archive: row.get(9)?,
+        recorded: row.get(10)?,
+    })
+}
+
 impl DbAccess<SqliteConnectionManager> for SqliteDbAccess {
     fn insert_directory(&self, path: impl AsRef<str>) -> Result<i64, failure::Error> {
         let conn = self.get_pool().get().unwrap();
@@ -464,37 +507,123 @@ impl DbAccess<SqliteConnectionManager> for SqliteDbAccess {
         }
     }
 
-    fn create_database(&self) -> Result<(), failure::Error> {
+    fn insert_file_version(
+        &self,
+        mut fv: FileVersionInDb,
+    ) -> Result<FileVersionInDb, failure::Error> {
+        let mut conn = self.0.get().unwrap();
+        let tx = conn.transaction()?;
+        fv.version = tx.query_row_named(
+            "SELECT IFNULL(MAX(version), 0) + 1 FROM file_version WHERE server = :server AND path = :path",
+            named_params! {
+                ":server": fv.server,
+                ":path": fv.path,
+            },
+            |row| row.get(0),
+        )?;
+        tx.execute_named(
+            "INSERT INTO file_version (server, path, version, len, time_modified, sha1, run_id, location, archive, time_recorded)
+             VALUES (:server, :path, :version, :len, :time_modified, :sha1, :run_id, :location, :archive, :time_recorded)",
+            named_params! {
+                ":server": fv.server,
+                ":path": fv.path,
+                ":version": fv.version,
+                ":len": fv.len,
+                ":time_modified": fv.modified,
+                ":sha1": fv.sha1,
+                ":run_id": fv.run_id,
+                ":location": fv.location,
+                ":archive": fv.archive,
+                ":time_recorded": fv.recorded,
+            },
+        )?;
+        fv.id = tx.last_insert_rowid();
+        tx.commit()?;
+        Ok(fv)
+    }
+
+    fn set_archive_of_unarchived(
+        &self,
+        server: impl AsRef<str>,
+        archive: impl AsRef<str>,
+    ) -> Result<u64, failure::Error> {
+        let conn = self.0.get().unwrap();
+        let c = conn.execute(
+            "UPDATE file_version SET archive = ?1 WHERE server = ?2 AND archive IS NULL",
+            params![archive.as_ref(), server.as_ref()],
+        )?;
+        Ok(c as u64)
+    }
+
+    fn find_file_versions(
+        &self,
+        server: Option<&str>,
+        path: impl AsRef<str>,
+    ) -> Result<Vec<FileVersionInDb>, failure::Error> {
         let conn = self.0.get().unwrap();
-        conn.execute_batch(
-            "BEGIN;
-            CREATE TABLE directory (
-                id  INTEGER PRIMARY KEY,
-                path TEXT NOT NULL UNIQUE
-            );
-             CREATE TABLE relative_file_item (
-                   id              INTEGER PRIMARY KEY,
-                   path            TEXT NOT NULL,
-                   sha1            TEXT,
-                   len             INTEGER DEFAULT 0,
-                   time_modified   TEXT,
-                   time_created    TEXT,
-                   changed         BOOLEAN,
-                   confirmed       BOOLEAN,
-                   dir_id          INTEGER NOT NULL,
-                   FOREIGN KEY(dir_id) REFERENCES directory(id)
-                   );
-             CREATE UNIQUE INDEX dir_path ON relative_file_item (path, dir_id);
-             CREATE TABLE schedule_done (
-                  id  INTEGER PRIMARY KEY,
-                  server_yml_path TEXT NOT NULL,
-                  task_name TEXT NOT NULL,
-                  time_execution   TEXT,
-                  done BOOLEAN,
-                  CONSTRAINT server_task_name UNIQUE (server_yml_path, task_name)
-                  );
-                COMMIT;",
+        let mut stmt = conn.prepare(
+            "SELECT id, server, path, version, len, time_modified, sha1, run_id, location, archive, time_recorded
+             FROM file_version WHERE (path = :path OR path GLOB :path) AND (:server IS NULL OR server = :server)
+             ORDER BY server, path, version",
         )?;
+        let r = stmt
+            .query_map_named(
+                named_params! {
+                    ":path": path.as_ref(),
+                    ":server": server,
+                },
+                map_to_file_version,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(r)
+    }
+
+    fn find_digest(&self, key: &DigestKey) -> Option<String> {
+        let conn = self.0.get().unwrap();
+        match conn.query_row_named(
+            "SELECT sha1 FROM digest_cache
+             WHERE path = :path AND len = :len AND mtime_ns = :mtime_ns AND inode = :inode",
+            named_params! {
+                ":path": key.path,
+                ":len": key.len as i64,
+                ":mtime_ns": key.mtime_ns,
+                ":inode": key.inode as i64,
+            },
+            |row| row.get(0),
+        ) {
+            Ok(sha1) => Some(sha1),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(err) => {
+                error!("find digest of {} failed: {:?}", key.path, err);
+                None
+            }
+        }
+    }
+
+    fn save_digest(&self, key: &DigestKey, sha1: &str) -> Result<(), failure::Error> {
+        let conn = self.0.get().unwrap();
+        conn.execute_named(
+            "INSERT OR REPLACE INTO digest_cache (path, len, mtime_ns, inode, sha1)
+             VALUES (:path, :len, :mtime_ns, :inode, :sha1)",
+            named_params! {
+                ":path": key.path,
+                ":len": key.len as i64,
+                ":mtime_ns": key.mtime_ns,
+                ":inode": key.inode as i64,
+                ":sha1": sha1,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn delete_digest(&self, path: &str) -> Result<(), failure::Error> {
+        let conn = self.0.get().unwrap();
+        conn.execute("DELETE FROM digest_cache WHERE path = ?1", params![path])?;
+        Ok(())
+    }
+
+    fn create_database(&self) -> Result<(), failure::Error> {
+        self.migrate()?;
         Ok(())
     }
 }
@@ -790,6 +919,34 @@ mod tests {
     //     Ok(())
     // }
 
+    #[test]
+    fn t_file_catalog() -> Result<(), failure::Error> {
+        let db_dir = tutil::TestDir::new();
+        let db_access = SqliteDbAccess::new(db_dir.tmp_dir_path().join("db.db"));
+        db_access.create_database()?;
+        db_access.create_database()?;
+        let a_version = |server: &str, path: &str| {
+            FileVersionInDb::new(server, path, 3, "20200101000000.000", format!("/backup{}", path))
+        };
+        let first = db_access.insert_file_version(a_version("a", "/etc/nginx/nginx.conf"))?;
+        assert_eq!(first.version, 1);
+        db_access.insert_file_version(a_version("b", "/etc/nginx/nginx.conf"))?;
+        db_access.insert_file_version(a_version("a", "/etc/hosts"))?;
+        assert_eq!(db_access.set_archive_of_unarchived("a", "a_1.tar")?, 2);
+        let second = db_access.insert_file_version(a_version("a", "/etc/nginx/nginx.conf"))?;
+        assert_eq!(second.version, 2);
+
+        let found = db_access.find_file_versions(Some("a"), "/etc/nginx/nginx.conf")?;
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].archive.as_deref(), Some("a_1.tar"));
+        assert_eq!(found[1].archive, None);
+        assert_eq!(found[1].location, "/backup/etc/nginx/nginx.conf");
+        assert_eq!(db_access.find_file_versions(None, "/etc/nginx/*")?.len(), 3);
+        assert_eq!(db_access.find_file_versions(None, "/etc/*")?.len(), 4);
+        assert!(db_access.find_file_versions(Some("c"), "/etc/*")?.is_empty());
+        Ok(())
+    }
+
     #[test]
     fn t_exclude_by_sql() -> Result<(), failure::Error> {
         log();
@@ -1,5 +1,155 @@
+use chrono::{DateTime, Local, NaiveDate};
 use fern::colors::{Color, ColoredLevelConfig};
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+fn default_max_size_mb() -> Option<u64> {
+    Some(10)
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+/// A log is moved aside to log.1, log.2 and so on when it's too big or the day changes.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct LogRotation {
+    /// None for no limit.
+    #[serde(default = "default_max_size_mb")]
+    pub max_size_mb: Option<u64>,
+    #[serde(default)]
+    pub daily: bool,
+    /// how many rotated files are kept.
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        Self {
+            max_size_mb: default_max_size_mb(),
+            daily: false,
+            keep: default_keep(),
+        }
+    }
+}
+
+/// Appends to the file and rotates it by the LogRotation before a write.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: LogRotation,
+    /// None after a failed reopen, tried again on the next write.
+    file: Option<fs::File>,
+    len: u64,
+    date: NaiveDate,
+}
+
+fn open_append(path: &Path) -> io::Result<(fs::File, u64, NaiveDate)> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let meta = file.metadata()?;
+    let date = meta
+        .modified()
+        .map(|m| DateTime::<Local>::from(m).naive_local().date())
+        .unwrap_or_else(|_| Local::today().naive_local());
+    Ok((file, meta.len(), date))
+}
+
+impl RotatingFile {
+    pub fn open(path: impl AsRef<Path>, rotation: LogRotation) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (file, len, date) = open_append(&path)?;
+        let mut rotating = Self {
+            path,
+            rotation,
+            file: Some(file),
+            len,
+            date,
+        };
+        if rotating.should_rotate(0) {
+            rotating.rotate()?;
+        }
+        Ok(rotating)
+    }
+
+    fn rotated_path(&self, i: usize) -> PathBuf {
+        let mut name: OsString = self.path.clone().into_os_string();
+        name.push(format!(".{}", i));
+        PathBuf::from(name)
+    }
+
+    fn should_rotate(&self, incoming: u64) -> bool {
+        if self.len == 0 {
+            return false;
+        }
+        let too_big = self
+            .rotation
+            .max_size_mb
+            .map(|mb| self.len + incoming > mb * 1024 * 1024)
+            .unwrap_or(false);
+        too_big || (self.rotation.daily && self.date != Local::today().naive_local())
+    }
+
+    pub fn rotate(&mut self) -> io::Result<()> {
+        // windows can't rename an opened file.
+        self.file.take();
+        let keep = self.rotation.keep;
+        if keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let oldest = self.rotated_path(keep);
+            if oldest.exists() {
+                fs::remove_file(&oldest)?;
+            }
+            for i in (1..keep).rev() {
+                let from = self.rotated_path(i);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(i + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.reopen()?;
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> io::Result<&mut fs::File> {
+        if self.file.is_none() {
+            let (file, len, _) = open_append(&self.path)?;
+            self.len = len;
+            self.date = Local::today().naive_local();
+            self.file.replace(file);
+        }
+        Ok(self.file.as_mut().expect("the file is just opened."))
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(buf.len() as u64) {
+            // the logger may be the one writing, so not to log it.
+            if let Err(err) = self.rotate() {
+                eprintln!("rotate {:?} failed: {}", self.path, err);
+            }
+        }
+        let written = self.reopen()?.write(buf)?;
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
 
 #[allow(dead_code)]
 pub fn setup_logger_empty() {
@@ -35,12 +185,20 @@ pub fn setup_logger_for_this_app<T, I>(
     log_file_name: impl AsRef<Path>,
     verbose_modules: T,
     verbose: &str,
+    rotation: LogRotation,
 ) -> Result<(), fern::InitError>
 where
     I: AsRef<str>,
     T: IntoIterator<Item = I>,
 {
-    setup_logger_detail(console, log_file_name, verbose_modules, None, verbose)
+    setup_logger_rotated(
+        console,
+        log_file_name,
+        verbose_modules,
+        None,
+        verbose,
+        Some(rotation),
+    )
 }
 
 pub fn setup_logger_detail<T, I>(
@@ -50,6 +208,29 @@ pub fn setup_logger_detail<T, I>(
     other_modules: Option<T>,
     verbose: &str,
 ) -> Result<(), fern::InitError>
+where
+    I: AsRef<str>,
+    T: IntoIterator<Item = I>,
+{
+    setup_logger_rotated(
+        console,
+        log_file_name,
+        verbose_modules,
+        other_modules,
+        verbose,
+        None,
+    )
+}
+
+/// Without the rotation the log file grows forever.
+fn setup_logger_rotated<T, I>(
+    console: bool,
+    log_file_name: impl AsRef<Path>,
+    verbose_modules: T,
+    other_modules: Option<T>,
+    verbose: &str,
+    rotation: Option<LogRotation>,
+) -> Result<(), fern::InitError>
 where
     I: AsRef<str>,
     T: IntoIterator<Item = I>,
@@ -97,6 +278,14 @@ where
     //     }
     // }
 
+    let log_file: fern::Output = match rotation {
+        Some(rotation) => {
+            // a line goes to the file in one write, not split by a rotation.
+            let writer = io::LineWriter::new(RotatingFile::open(log_file_name, rotation)?);
+            (Box::new(writer) as Box<dyn Write + Send>).into()
+        }
+        None => fern::log_file(log_file_name)?.into(),
+    };
     let file_config = fern::Dispatch::new()
         .format(move |out, message, record| {
             out.finish(format_args!(
@@ -107,8 +296,40 @@ where
                 message
             ))
         })
-        .chain(fern::log_file(log_file_name)?);
+        .chain(log_file);
     base_config = base_config.chain(file_config);
     base_config.apply()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::develope::tutil;
+
+    #[test]
+    fn t_rotating_file() -> Result<(), failure::Error> {
+        let tu = tutil::TestDir::new();
+        let path = tu.tmp_dir_path().join("a.log");
+        fs::write(&path, "old\n")?;
+        let rotation = LogRotation {
+            max_size_mb: Some(1),
+            daily: false,
+            keep: 2,
+        };
+        let mut log = RotatingFile::open(&path, rotation)?;
+        log.write_all(b"appended\n")?;
+        assert_eq!(fs::read_to_string(&path)?, "old\nappended\n");
+
+        let line = vec![b'x'; 600 * 1024];
+        for _ in 0..4 {
+            log.write_all(&line)?;
+        }
+        log.flush()?;
+        assert_eq!(fs::metadata(&path)?.len(), 600 * 1024);
+        assert!(fs::read_to_string(log.rotated_path(1))?.starts_with('x'));
+        assert!(log.rotated_path(2).exists());
+        assert!(!log.rotated_path(3).exists(), "only keep 2.");
+        Ok(())
+    }
+}
@@ -1,10 +1,24 @@
+use super::report_mail::RenderedMail;
 use lettre::smtp::authentication::{Credentials, Mechanism};
 use lettre::smtp::ConnectionReuseParameters;
 use lettre::{SmtpClient, Transport};
-use lettre_email::{mime::TEXT_PLAIN, Email};
-use std::path::Path;
+use lettre_email::{mime::TEXT_PLAIN_UTF_8, Email, EmailBuilder};
 use crate::data_shape::MailConf;
 
+pub struct MailAttachment {
+    pub name: String,
+    pub content: Vec<u8>,
+}
+
+impl MailAttachment {
+    pub fn text(name: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            content: content.into().into_bytes(),
+        }
+    }
+}
+
 pub fn send_test_mail(mail_conf: &MailConf, mail_to: impl AsRef<str>) -> Result<(), failure::Error> {
     send_text_mail(mail_conf, mail_to, "this is a test mail from bk-over-ssh.", "Hello world.")
 }
@@ -17,11 +31,26 @@ pub fn send_text_mail(mail_conf: &MailConf, mail_to: impl AsRef<str>, subject: i
         .from(mail_conf.from.as_str())
         .subject(subject.as_ref())
         .text(text.as_ref())
-        .attachment_from_file(Path::new("Cargo.toml"), None, &TEXT_PLAIN)
-        .unwrap()
-        .build()
-        .unwrap();
+        .build()?;
+    send_mail(mail_conf, email)
+}
+
+/// To all the recipients of the mail_conf, the text is the alternative of the html.
+pub fn build_html_mail(mail_conf: &MailConf, rendered: RenderedMail) -> Result<Email, failure::Error> {
+    let mut builder: EmailBuilder = mail_conf
+        .recipients
+        .iter()
+        .fold(Email::builder(), |builder, to| builder.to(to.as_str()))
+        .from(mail_conf.from.as_str())
+        .subject(rendered.subject)
+        .alternative(rendered.html, rendered.text);
+    for attachment in rendered.attachments.iter() {
+        builder = builder.attachment(&attachment.content, &attachment.name, &TEXT_PLAIN_UTF_8)?;
+    }
+    Ok(builder.build()?)
+}
 
+pub fn send_mail(mail_conf: &MailConf, email: Email) -> Result<(), failure::Error> {
     // Open a local connection on port 25
     // let mut mailer = SmtpClient::new_unencrypted_localhost().unwrap().transport();
     let mut mailer = SmtpClient::new_simple(mail_conf.hostname.as_str())?
@@ -30,7 +59,7 @@ pub fn send_text_mail(mail_conf: &MailConf, mail_to: impl AsRef<str>, subject: i
         // Add credentials for authentication
         .credentials(Credentials::new(
             mail_conf.username.clone(),
-            mail_conf.password.clone(),
+            mail_conf.password.expose().to_string(),
         ))
         // Enable SMTPUTF8 if the server supports it
         .smtp_utf8(true)
@@ -1,3 +1,4 @@
 mod mail_util;
+pub mod report_mail;
 
-pub use mail_util::{send_test_mail, send_text_mail};
\ No newline at end of file
+pub use mail_util::{send_test_mail, send_text_mail};
@@ -0,0 +1,246 @@
+//! The html mails of the sync reports, one for a run or a digest of the runs in a period.
+use super::mail_util::{self, MailAttachment};
+use crate::data_shape::sync_report::SyncReport;
+use crate::data_shape::MailConf;
+use askama::Template;
+use chrono::{DateTime, Local};
+use lettre_email::Email;
+use log::*;
+
+/// the rest of the errors are in the attachment.
+const MAX_ERRORS_IN_BODY: usize = 20;
+
+/// A report with the values formatted for the templates.
+pub struct ReportRow {
+    pub server: String,
+    pub direction: String,
+    pub started: String,
+    pub finished: String,
+    pub duration: String,
+    pub files_seen: u64,
+    pub changed: u64,
+    pub unchanged: u64,
+    pub failed: u64,
+    pub bytes_transferred: u64,
+    pub changed_reasons: Vec<(String, u64)>,
+    pub errors: Vec<String>,
+    pub errors_omitted: usize,
+    pub succeeded: bool,
+}
+
+impl From<&SyncReport> for ReportRow {
+    fn from(report: &SyncReport) -> Self {
+        Self {
+            server: report.server.clone(),
+            direction: format!("{:?}", report.direction).to_lowercase(),
+            started: report.started_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+            finished: report
+                .finished_at
+                .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_else(|| "unfinished".to_string()),
+            duration: format!("{:.1}s", report.duration_secs),
+            files_seen: report.files_seen,
+            changed: report.changed,
+            unchanged: report.unchanged,
+            failed: report.failed,
+            bytes_transferred: report.bytes_transferred,
+            changed_reasons: report
+                .changed_reasons
+                .iter()
+                .map(|(k, v)| (k.clone(), *v))
+                .collect(),
+            errors: report
+                .errors
+                .iter()
+                .take(MAX_ERRORS_IN_BODY)
+                .cloned()
+                .collect(),
+            errors_omitted: report.errors.len().saturating_sub(MAX_ERRORS_IN_BODY),
+            succeeded: report.succeeded(),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "run_report.html")]
+struct RunReportTemplate<'a> {
+    row: &'a ReportRow,
+}
+
+#[derive(Template)]
+#[template(path = "digest_report.html")]
+struct DigestTemplate<'a> {
+    since: String,
+    rows: &'a [ReportRow],
+    failed_runs: usize,
+}
+
+/// What goes into a mail, rendered but not sent.
+pub struct RenderedMail {
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+    pub attachments: Vec<MailAttachment>,
+}
+
+fn errors_attachment<'a>(reports: impl Iterator<Item = &'a SyncReport>) -> Option<MailAttachment> {
+    let mut content = String::new();
+    for report in reports.filter(|r| !r.errors.is_empty()) {
+        content.push_str(&format!(
+            "{} {:?} started at {}:\n",
+            report.server, report.direction, report.started_at
+        ));
+        for error in report.errors.iter() {
+            content.push_str(&format!("  {}\n", error));
+        }
+    }
+    if content.is_empty() {
+        None
+    } else {
+        Some(MailAttachment::text("errors.txt", content))
+    }
+}
+
+pub fn render_run_report(report: &SyncReport) -> Result<RenderedMail, failure::Error> {
+    let row = ReportRow::from(report);
+    let subject = format!(
+        "[bk-over-ssh] {} of {} {}",
+        row.direction,
+        row.server,
+        if row.succeeded { "succeeded" } else { "failed" }
+    );
+    Ok(RenderedMail {
+        subject,
+        html: RunReportTemplate { row: &row }.render()?,
+        text: report.to_text(),
+        attachments: errors_attachment(std::iter::once(report))
+            .into_iter()
+            .collect(),
+    })
+}
+
+pub fn render_digest(
+    since: &DateTime<Local>,
+    reports: &[SyncReport],
+) -> Result<RenderedMail, failure::Error> {
+    let rows: Vec<ReportRow> = reports.iter().map(ReportRow::from).collect();
+    let failed_runs = rows.iter().filter(|r| !r.succeeded).count();
+    let since = since.format("%Y-%m-%d %H:%M").to_string();
+    let subject = format!(
+        "[bk-over-ssh] {} runs since {}, {} failed",
+        rows.len(),
+        since,
+        failed_runs
+    );
+    let text = reports
+        .iter()
+        .map(SyncReport::to_text)
+        .collect::<Vec<String>>()
+        .join("\n");
+    Ok(RenderedMail {
+        subject,
+        html: DigestTemplate {
+            since,
+            rows: &rows,
+            failed_runs,
+        }
+        .render()?,
+        text,
+        attachments: errors_attachment(reports.iter()).into_iter().collect(),
+    })
+}
+
+/// None if the recipients are empty, or it succeeded and only the failures are wanted.
+pub fn run_report_mail(
+    mail_conf: &MailConf,
+    report: &SyncReport,
+) -> Result<Option<Email>, failure::Error> {
+    if mail_conf.recipients.is_empty() || (mail_conf.on_failure_only && report.succeeded()) {
+        return Ok(None);
+    }
+    let rendered = render_run_report(report)?;
+    Ok(Some(mail_util::build_html_mail(mail_conf, rendered)?))
+}
+
+pub fn notify_run(mail_conf: &MailConf, report: &SyncReport) {
+    let result = run_report_mail(mail_conf, report)
+        .and_then(|email| email.map_or(Ok(()), |email| mail_util::send_mail(mail_conf, email)));
+    if let Err(err) = result {
+        error!(
+            "send the report mail of {} failed: {:?}",
+            report.server, err
+        );
+    }
+}
+
+/// The digest is sent even if all succeeded, it tells the runs happened.
+pub fn send_digest(
+    mail_conf: &MailConf,
+    since: &DateTime<Local>,
+    reports: &[SyncReport],
+) -> Result<(), failure::Error> {
+    if mail_conf.recipients.is_empty() {
+        bail!("no recipients in the mail_conf.");
+    }
+    let rendered = render_digest(since, reports)?;
+    mail_util::send_mail(mail_conf, mail_util::build_html_mail(mail_conf, rendered)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_shape::sync_report::SyncDirection;
+    use crate::data_shape::FileChanged;
+    use lettre::SendableEmail;
+
+    fn a_report(failed: bool) -> SyncReport {
+        let mut report = SyncReport::new("a.example.com", SyncDirection::Pull);
+        report.files_seen = 2;
+        report.record_file_changed(&FileChanged::Len(1, 2));
+        report.record_file_changed(&FileChanged::NoChange);
+        if failed {
+            report.record_failed("copy to <b>.txt failed");
+        }
+        report.finish();
+        report
+    }
+
+    #[test]
+    fn t_render_report_mail() -> Result<(), failure::Error> {
+        let mut mail_conf = MailConf {
+            from: "bk@example.com".to_string(),
+            ..MailConf::default()
+        };
+        let report = a_report(true);
+        let rendered = render_run_report(&report)?;
+        assert_eq!(
+            rendered.subject,
+            "[bk-over-ssh] pull of a.example.com failed"
+        );
+        assert!(
+            rendered.html.contains("changed by Len"),
+            "{}",
+            rendered.html
+        );
+        assert!(rendered.html.contains("copy to &lt;b&gt;.txt failed"));
+        assert_eq!(rendered.attachments.len(), 1);
+        assert!(render_run_report(&a_report(false))?.attachments.is_empty());
+
+        assert!(run_report_mail(&mail_conf, &report)?.is_none());
+        mail_conf.recipients = vec!["ops@example.com".to_string()];
+        mail_conf.on_failure_only = true;
+        assert!(run_report_mail(&mail_conf, &a_report(false))?.is_none());
+        let email = run_report_mail(&mail_conf, &report)?.expect("a failed run is mailed.");
+        let sendable: SendableEmail = email.into();
+        let message = sendable.message_to_string()?;
+        assert!(message.contains("errors.txt"));
+        assert!(message.contains("text/html"));
+
+        let since = Local::now();
+        let digest = render_digest(&since, &[a_report(false), report])?;
+        assert!(digest.subject.starts_with("[bk-over-ssh] 2 runs since "));
+        assert!(digest.subject.ends_with(", 1 failed"));
+        assert_eq!(digest.html.matches("a.example.com").count(), 2);
+        Ok(())
+    }
+}
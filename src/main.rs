@@ -81,7 +81,13 @@ fn main() -> Result<(), failure::Error> {
             }
         }
 
-        log_util::setup_logger_for_this_app(console_log, log_file, Vec::<String>::new(), verbose)?;
+        log_util::setup_logger_for_this_app(
+            console_log,
+            log_file,
+            Vec::<String>::new(),
+            verbose,
+            log_util::LogRotation::default(),
+        )?;
         if let Err(err) = command::server_loop::server_receive_loop() {
             error!("server-receive-loop caught error: {:?}", err);
         }
@@ -98,7 +104,13 @@ fn main() -> Result<(), failure::Error> {
             }
         }
 
-        log_util::setup_logger_for_this_app(console_log, log_file, Vec::<String>::new(), verbose)?;
+        log_util::setup_logger_for_this_app(
+            console_log,
+            log_file,
+            Vec::<String>::new(),
+            verbose,
+            log_util::LogRotation::default(),
+        )?;
         if let Err(err) = command::server_loop::server_send_loop(skip_sha1) {
             error!("server-send-loop caught error: {:?}", err);
         }
@@ -113,6 +125,30 @@ fn main() -> Result<(), failure::Error> {
         Some(AppRole::PullHub)
     } else if let ("archive-local", Some(_)) = m.subcommand() {
         Some(AppRole::PullHub)
+    } else if let ("decrypt-archive", Some(_)) = m.subcommand() {
+        Some(AppRole::PullHub)
+    } else if let ("list-archives", Some(_)) = m.subcommand() {
+        Some(AppRole::PullHub)
+    } else if let ("verify-archive", Some(_)) = m.subcommand() {
+        Some(AppRole::PullHub)
+    } else if let ("check-freshness", Some(sub_matches)) = m.subcommand() {
+        if sub_matches.is_present("push") {
+            Some(AppRole::ActiveLeaf)
+        } else {
+            Some(AppRole::PullHub)
+        }
+    } else if let ("send-report-digest", Some(sub_matches)) = m.subcommand() {
+        if sub_matches.is_present("push") {
+            Some(AppRole::ActiveLeaf)
+        } else {
+            Some(AppRole::PullHub)
+        }
+    } else if let ("print-report", Some(sub_matches)) = m.subcommand() {
+        if sub_matches.is_present("push") {
+            Some(AppRole::ActiveLeaf)
+        } else {
+            Some(AppRole::PullHub)
+        }
     } else {
         None
     };
@@ -153,16 +189,21 @@ fn main() -> Result<(), failure::Error> {
         app_conf.log_full_path.as_path(),
         app_conf.get_log_conf().get_verbose_modules(),
         verbose,
+        app_conf.get_log_conf().rotation,
     )?;
 
     if db_cmd::create_db(&mut app_conf, &m)? {
         return Ok(());
     }
 
+    if db_cmd::db(&app_conf, &m)? {
+        return Ok(());
+    }
+
     let no_db = m.is_present("no-db");
 
     if !no_db {
-        let sqlite_db_access = SqliteDbAccess::new(app_conf.get_sqlite_db_file());
+        let sqlite_db_access = SqliteDbAccess::open(app_conf.get_sqlite_db_file())?;
         app_conf.set_db_access(sqlite_db_access);
     }
 
@@ -190,6 +231,7 @@ fn main_entry<'a>(
             let server_yml = sub_matches.value_of("server-yml");
             if server_yml.is_none() {
                 app_conf.progress_bar.take();
+                app_conf.lock_working_file()?;
             }
             let archive_after_sync = sub_matches.is_present("archive");
             command::client_push_loops(&app_conf, server_yml,archive_after_sync, app_conf.mini_app_conf.as_service, false)?;
@@ -199,6 +241,7 @@ fn main_entry<'a>(
             let server_yml = sub_matches.value_of("server-yml");
             if server_yml.is_none() {
                 app_conf.progress_bar.take();
+                app_conf.lock_working_file()?;
             }
             let archive_after_sync = sub_matches.is_present("archive");
             command::client_pull_loops(&app_conf, server_yml, archive_after_sync, app_conf.mini_app_conf.as_service, false)?;
@@ -270,11 +313,67 @@ fn main_entry<'a>(
             }
         }
         ("archive-local", Some(sub_matches)) => {
+            if sub_matches.value_of("server-yml").is_none() && !sub_matches.is_present("dry-run") {
+                app_conf.lock_working_file()?;
+            }
             command::archive_local(
                 app_conf,
                 sub_matches.value_of("server-yml"),
                 sub_matches.is_present("prune"),
                 sub_matches.is_present("prune-only"),
+                sub_matches.is_present("dry-run"),
+                sub_matches.is_present("json"),
+            )?;
+        }
+        ("list-archives", Some(sub_matches)) => {
+            command::archives::list_archives(app_conf, sub_matches.value_of("server-yml"))?;
+        }
+        ("verify-archive", Some(sub_matches)) => {
+            command::archives::verify_archive(
+                app_conf,
+                sub_matches.value_of("server-yml").expect("server-yml should be present"),
+                sub_matches.value_of("archive"),
+            )?;
+        }
+        ("print-report", Some(sub_matches)) => {
+            command::report::print_report(
+                app_conf,
+                sub_matches.value_of("server-yml").expect("server-yml should be present"),
+                sub_matches.value_of("report"),
+                sub_matches.is_present("json"),
+            )?;
+        }
+        ("check-freshness", Some(sub_matches)) => {
+            let any_stale = command::report::check_freshness(
+                app_conf,
+                sub_matches.value_of("server-yml"),
+                sub_matches.is_present("json"),
+                sub_matches.is_present("notify"),
+            )?;
+            if any_stale {
+                // for the monitoring systems.
+                std::process::exit(2);
+            }
+        }
+        ("find", Some(sub_matches)) => {
+            command::catalog::find(
+                app_conf,
+                sub_matches.value_of("path").expect("path should be present"),
+                sub_matches.value_of("server"),
+                sub_matches.is_present("json"),
+            )?;
+        }
+        ("send-report-digest", Some(sub_matches)) => {
+            let hours = sub_matches.value_of("hours").unwrap_or("24").parse::<i64>()?;
+            command::report::send_report_digest(app_conf, hours)?;
+        }
+        ("decrypt-archive", Some(sub_matches)) => {
+            command::archives::decrypt_archive(
+                app_conf,
+                sub_matches.value_of("archive").expect("archive should be present"),
+                sub_matches.value_of("out"),
+                sub_matches.value_of("server-yml"),
+                sub_matches.value_of("passphrase"),
             )?;
         }
         ("verify-server-yml", Some(sub_matches)) => {
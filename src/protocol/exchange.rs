@@ -19,6 +19,7 @@ pub enum TransferType {
     FileItemChanged,
     FileItemUnchanged,
     StartSend,
+    HookResult,
     StringError,
 }
 
@@ -37,6 +38,7 @@ impl TransferType {
             9 => Ok(TransferType::FileItemChanged),
             10 => Ok(TransferType::FileItemUnchanged),
             11 => Ok(TransferType::StartSend),
+            12 => Ok(TransferType::HookResult),
             14 => Ok(TransferType::StringError),
             i => {
                 error!("from_u8 unexpected transfer type: {:?}", i);
@@ -59,6 +61,7 @@ impl TransferType {
             TransferType::FileItemChanged => 9,
             TransferType::FileItemUnchanged => 10,
             TransferType::StartSend => 11,
+            TransferType::HookResult => 12,
             TransferType::StringError => 14,
         }
     }